@@ -0,0 +1,45 @@
+use std::rc::Rc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rlox::{
+    chunk::{Closure, Function},
+    compiler::Compiler,
+    vm::VM,
+};
+
+const PROGRAMS: [(&str, &str); 4] = [
+    ("fib(30)", include_str!("lox/fib.lox")),
+    ("string concatenation", include_str!("lox/strings.lox")),
+    ("closure creation", include_str!("lox/closures.lox")),
+    ("instances and methods", include_str!("lox/instances.lox")),
+];
+
+// A fresh VM with tracing off, so only the program itself is measured
+fn run(function: &Rc<Function>) -> VM {
+    let mut vm = VM::new();
+    vm.trace_execution = false;
+    if vm.interpret(Rc::new(Closure::new(function.clone()))).is_err() {
+        panic!("benchmark program failed");
+    }
+    vm
+}
+
+// Ids don't depend on features, so the unchecked build can be measured against the default one:
+//   cargo bench --bench programs -- --save-baseline checked
+//   cargo bench --bench programs --features unsafe-fast -- --baseline checked
+fn programs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("programs");
+    group.sample_size(10);
+    for (name, source) in PROGRAMS.iter() {
+        let function = match Compiler::new((*source).to_owned()).compile() {
+            Ok(function) => Rc::new(function),
+            Err(diagnostics) => panic!("{} does not compile: {:?}", name, diagnostics),
+        };
+        assert!(run(&function).get_global("result").is_some());
+        group.bench_function(*name, |b| b.iter(|| run(&function)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, programs);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+use std::rc::Rc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rlox::{
+    chunk::{LoxString, Value},
+    nanbox::NanBox,
+};
+
+const OPERATIONS: usize = 1000;
+
+// The shape of a binary instruction: push both operands, pop them, push the result
+fn arithmetic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stack arithmetic");
+    group.bench_function("enum", |b| {
+        let mut stack: Vec<Value> = Vec::with_capacity(16);
+        b.iter(|| {
+            stack.push(Value::Double(0.5));
+            for _ in 0..OPERATIONS {
+                stack.push(Value::Double(black_box(1.5)));
+                let right = stack.pop().and_then(|v| v.as_number()).unwrap();
+                let left = stack.pop().and_then(|v| v.as_number()).unwrap();
+                stack.push(Value::Double(left + right));
+            }
+            stack.pop()
+        })
+    });
+    group.bench_function("nanbox", |b| {
+        let mut stack: Vec<NanBox> = Vec::with_capacity(16);
+        b.iter(|| {
+            stack.push(NanBox::number(0.5));
+            for _ in 0..OPERATIONS {
+                stack.push(NanBox::number(black_box(1.5)));
+                let right = stack.pop().and_then(|v| v.as_number()).unwrap();
+                let left = stack.pop().and_then(|v| v.as_number()).unwrap();
+                stack.push(NanBox::number(left + right));
+            }
+            stack.pop()
+        })
+    });
+    group.finish();
+}
+
+// Locals being read onto the stack and popped again, half of them heap values
+fn copies(c: &mut Criterion) {
+    let string = Value::String(Rc::new(LoxString::from("name")));
+    let locals = [Value::Double(1.0), string, Value::Nil, Value::Bool(true)];
+    let boxed: Vec<NanBox> = locals.iter().cloned().map(NanBox::from).collect();
+
+    let mut group = c.benchmark_group("stack copies");
+    group.bench_function("enum", |b| {
+        let mut stack: Vec<Value> = Vec::with_capacity(16);
+        b.iter(|| {
+            for index in 0..OPERATIONS {
+                stack.push(locals[index % locals.len()].clone());
+                black_box(stack.pop());
+            }
+        })
+    });
+    group.bench_function("nanbox", |b| {
+        let mut stack: Vec<NanBox> = Vec::with_capacity(16);
+        b.iter(|| {
+            for index in 0..OPERATIONS {
+                stack.push(boxed[index % boxed.len()].clone());
+                black_box(stack.pop());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, arithmetic, copies);
+criterion_main!(benches);
@@ -0,0 +1,732 @@
+use std::fmt::Write;
+
+use crate::{
+    compiler::{Precedence, MAX_NESTING},
+    error::{self, Diagnostic},
+    scanner::Scanner,
+    token::{Token, TokenType},
+    util,
+};
+
+// The compiler goes straight to bytecode, this tree only exists to be looked at
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+    Variable(String),
+    This,
+    Assign(String, Box<Expr>),
+    Unary(String, Box<Expr>),
+    // Arithmetic, comparison and the short circuiting `and` and `or`
+    Binary(Box<Expr>, String, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    Group(Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Get(Box<Expr>, String),
+    Set(Box<Expr>, String, Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    IndexSet(Box<Expr>, Box<Expr>, Box<Expr>),
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    Lambda(Vec<String>, Vec<Stmt>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Expression(Expr),
+    Print(Expr),
+    Var(String, Option<Expr>),
+    Block(Vec<Stmt>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    While(Expr, Box<Stmt>),
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+    Return(Option<Expr>),
+    Throw(Expr),
+    // The try block, the catch variable and block, and the finally block
+    Try(Vec<Stmt>, Option<(String, Vec<Stmt>)>, Option<Vec<Stmt>>),
+    Function(String, Vec<String>, Vec<Stmt>),
+    // Only found among the methods of a class
+    Getter(String, Vec<Stmt>),
+    Setter(String, String, Vec<Stmt>),
+    Class(String, Vec<Stmt>),
+    Extern(String, Vec<String>),
+}
+
+// The same grammar and precedence as the compiler, building nodes instead of code
+pub struct Parser {
+    pub scanner: Scanner,
+    pub current: Token,
+    pub previous: Token,
+    pub diagnostics: Vec<Diagnostic>,
+    pub panic_mode: bool,
+    // Levels of statements and expressions being parsed, limited as in the compiler
+    pub nesting: usize,
+    pub gave_up: bool,
+}
+
+pub fn parse(source: &str) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+    let mut parser = Parser {
+        scanner: Scanner::new(source.to_owned()),
+        current: Token::default(),
+        previous: Token::default(),
+        diagnostics: vec![],
+        panic_mode: false,
+        nesting: 0,
+        gave_up: false,
+    };
+    parser.advance();
+    let mut statements = vec![];
+    while !parser.match_token(TokenType::Eof) {
+        if let Some(statement) = parser.parse_declaration() {
+            statements.push(statement);
+        }
+    }
+    if parser.diagnostics.is_empty() {
+        Ok(statements)
+    } else {
+        Err(parser.diagnostics)
+    }
+}
+
+impl Parser {
+    fn advance(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+        loop {
+            self.current = self.scanner.scan();
+            if self.current.token_type != TokenType::Error {
+                break;
+            }
+            self.error(self.current.clone(), "Invalid Token");
+        }
+    }
+
+    fn error(&mut self, token: Token, message: &str) {
+        if self.panic_mode || self.gave_up {
+            return;
+        }
+        self.panic_mode = true;
+        let diagnostic = match token.token_type {
+            TokenType::Error => Diagnostic::error(&token, &token.lexeme),
+            _ => Diagnostic::error(&token, message),
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.check(token_type) {
+            self.advance();
+        } else {
+            self.error(self.current.clone(), message);
+        }
+    }
+
+    fn name(&mut self, message: &str) -> String {
+        self.consume(TokenType::Identifier, message);
+        self.previous.lexeme.to_string()
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        loop {
+            if self.previous.token_type == TokenType::SemiColon {
+                break;
+            }
+            match self.current.token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Extern
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Throw
+                | TokenType::Try
+                | TokenType::Eof => break,
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    fn nest(&mut self) -> bool {
+        if self.nesting >= MAX_NESTING {
+            self.error(self.current.clone(), error::TOO_MUCH_NESTING);
+            self.gave_up = true;
+            while !self.check(TokenType::Eof) {
+                self.advance();
+            }
+            return false;
+        }
+        self.nesting += 1;
+        true
+    }
+
+    fn parse_declaration(&mut self) -> Option<Stmt> {
+        if !self.nest() {
+            return None;
+        }
+        let statement = if self.match_token(TokenType::Var) {
+            self.parse_var_declaration()
+        } else if self.match_token(TokenType::Fun) {
+            let name = self.name(error::EXPECT_FUNCTION_NAME);
+            let (params, body) = self.parse_function();
+            Stmt::Function(name, params, body)
+        } else if self.match_token(TokenType::Extern) {
+            self.parse_extern_declaration()
+        } else if self.match_token(TokenType::Class) {
+            self.parse_class_declaration()
+        } else {
+            self.parse_statement()
+        };
+        self.nesting -= 1;
+        if self.panic_mode {
+            self.synchronize();
+            return None;
+        }
+        Some(statement)
+    }
+
+    fn parse_var_declaration(&mut self) -> Stmt {
+        let name = self.name(error::EXPECT_VARIABLE_NAME);
+        let value = if self.match_token(TokenType::Equal) {
+            Some(self.parse_expression())
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::SemiColon,
+            error::EXPECT_SEMICOLON_AFTER_VARIABLE_DECLARATION,
+        );
+        Stmt::Var(name, value)
+    }
+
+    fn parse_parameters(&mut self) -> Vec<String> {
+        self.consume(TokenType::LeftParen, error::EXPECT_LEFT_PAREN_AFTER_FUNCTION);
+        let mut params = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                params.push(self.name(error::EXPECT_PARAMETER_NAME));
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightParen,
+            error::EXPECT_RIGHT_PAREN_AFTER_PARAMETERS,
+        );
+        params
+    }
+
+    fn parse_function(&mut self) -> (Vec<String>, Vec<Stmt>) {
+        let params = self.parse_parameters();
+        self.consume(
+            TokenType::LeftBrace,
+            error::EXPECT_LEFT_BRACE_BEFORE_FUNCTION_BODY,
+        );
+        (params, self.parse_block())
+    }
+
+    fn parse_extern_declaration(&mut self) -> Stmt {
+        self.consume(TokenType::Fun, error::EXPECT_FUN_AFTER_EXTERN);
+        let name = self.name(error::EXPECT_FUNCTION_NAME);
+        let params = self.parse_parameters();
+        self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_EXTERN);
+        Stmt::Extern(name, params)
+    }
+
+    fn parse_class_declaration(&mut self) -> Stmt {
+        let name = self.name(error::EXPECT_CLASS_NAME);
+        self.consume(
+            TokenType::LeftBrace,
+            error::EXPECT_LEFT_BRACE_BEFORE_CLASS_BODY,
+        );
+        let mut methods = vec![];
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            let method = self.name(error::EXPECT_METHOD_NAME);
+            if method == "set" && self.check(TokenType::Identifier) {
+                let name = self.name(error::EXPECT_METHOD_NAME);
+                let token = self.previous.clone();
+                let (mut params, body) = self.parse_function();
+                if params.len() != 1 {
+                    self.error(token, error::SETTER_ARITY);
+                }
+                methods.push(Stmt::Setter(name, params.pop().unwrap_or_default(), body));
+            } else if self.match_token(TokenType::LeftBrace) {
+                methods.push(Stmt::Getter(method, self.parse_block()));
+            } else {
+                let (params, body) = self.parse_function();
+                methods.push(Stmt::Function(method, params, body));
+            }
+            if self.panic_mode {
+                break;
+            }
+        }
+        self.consume(
+            TokenType::RightBrace,
+            error::EXPECT_RIGHT_BRACE_AFTER_CLASS_BODY,
+        );
+        Stmt::Class(name, methods)
+    }
+
+    fn parse_block(&mut self) -> Vec<Stmt> {
+        let mut statements = vec![];
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if let Some(statement) = self.parse_declaration() {
+                statements.push(statement);
+            }
+        }
+        self.consume(TokenType::RightBrace, error::EXPECT_RIGHT_BRACE_AFTER_BLOCK);
+        statements
+    }
+
+    fn parse_statement(&mut self) -> Stmt {
+        if !self.nest() {
+            return Stmt::Block(vec![]);
+        }
+        let statement = if self.match_token(TokenType::Print) {
+            let value = self.parse_expression();
+            self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_VALUE);
+            Stmt::Print(value)
+        } else if self.match_token(TokenType::LeftBrace) {
+            Stmt::Block(self.parse_block())
+        } else if self.match_token(TokenType::If) {
+            self.consume(TokenType::LeftParen, error::EXPECT_LEFT_PAREN_AFTER_IF);
+            let condition = self.parse_expression();
+            self.consume(
+                TokenType::RightParen,
+                error::EXPECT_RIGHT_PAREN_AFTER_CONDITION,
+            );
+            let then_branch = Box::new(self.parse_statement());
+            let else_branch = if self.match_token(TokenType::Else) {
+                Some(Box::new(self.parse_statement()))
+            } else {
+                None
+            };
+            Stmt::If(condition, then_branch, else_branch)
+        } else if self.match_token(TokenType::While) {
+            self.consume(TokenType::LeftParen, error::EXPECT_LEFT_PAREN_AFTER_WHILE);
+            let condition = self.parse_expression();
+            self.consume(
+                TokenType::RightParen,
+                error::EXPECT_RIGHT_PAREN_AFTER_CONDITION,
+            );
+            Stmt::While(condition, Box::new(self.parse_statement()))
+        } else if self.match_token(TokenType::For) {
+            self.parse_for_statement()
+        } else if self.match_token(TokenType::Return) {
+            let value = if self.check(TokenType::SemiColon) {
+                None
+            } else {
+                Some(self.parse_expression())
+            };
+            self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_RETURN);
+            Stmt::Return(value)
+        } else if self.match_token(TokenType::Throw) {
+            let value = self.parse_expression();
+            self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_THROW);
+            Stmt::Throw(value)
+        } else if self.match_token(TokenType::Try) {
+            self.parse_try_statement()
+        } else {
+            let expression = self.parse_expression();
+            self.consume(
+                TokenType::SemiColon,
+                error::EXPECT_SEMICOLON_AFTER_EXPRESSION,
+            );
+            Stmt::Expression(expression)
+        };
+        self.nesting -= 1;
+        statement
+    }
+
+    fn parse_try_statement(&mut self) -> Stmt {
+        self.consume(TokenType::LeftBrace, error::EXPECT_LEFT_BRACE_AFTER_TRY);
+        let body = self.parse_block();
+        let catch = if self.match_token(TokenType::Catch) {
+            self.consume(TokenType::LeftParen, error::EXPECT_LEFT_PAREN_AFTER_CATCH);
+            let name = self.name(error::EXPECT_CATCH_VARIABLE);
+            self.consume(TokenType::RightParen, error::EXPECT_RIGHT_PAREN_AFTER_CATCH);
+            self.consume(TokenType::LeftBrace, error::EXPECT_LEFT_BRACE_AFTER_CATCH);
+            Some((name, self.parse_block()))
+        } else {
+            None
+        };
+        let finally = if self.match_token(TokenType::Finally) {
+            self.consume(TokenType::LeftBrace, error::EXPECT_LEFT_BRACE_AFTER_FINALLY);
+            Some(self.parse_block())
+        } else {
+            if catch.is_none() {
+                self.error(self.current.clone(), error::EXPECT_CATCH_OR_FINALLY);
+            }
+            None
+        };
+        Stmt::Try(body, catch, finally)
+    }
+
+    fn parse_for_statement(&mut self) -> Stmt {
+        self.consume(TokenType::LeftParen, error::EXPECT_LEFT_PAREN_AFTER_FOR);
+        let initializer = if self.match_token(TokenType::SemiColon) {
+            None
+        } else if self.match_token(TokenType::Var) {
+            Some(Box::new(self.parse_var_declaration()))
+        } else {
+            let expression = self.parse_expression();
+            self.consume(
+                TokenType::SemiColon,
+                error::EXPECT_SEMICOLON_AFTER_EXPRESSION,
+            );
+            Some(Box::new(Stmt::Expression(expression)))
+        };
+        let condition = if self.check(TokenType::SemiColon) {
+            None
+        } else {
+            Some(self.parse_expression())
+        };
+        self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_LOOP);
+        let increment = if self.check(TokenType::RightParen) {
+            None
+        } else {
+            Some(self.parse_expression())
+        };
+        self.consume(
+            TokenType::RightParen,
+            error::EXPECT_RIGHT_PAREN_AFTER_CONDITION,
+        );
+        Stmt::For(initializer, condition, increment, Box::new(self.parse_statement()))
+    }
+
+    fn parse_expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        if !self.nest() {
+            return Expr::Nil;
+        }
+        self.advance();
+        let mut expression = self.parse_prefix(precedence);
+        while precedence <= Precedence::from(self.current.token_type) {
+            self.advance();
+            expression = self.parse_infix(expression, precedence);
+        }
+        if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
+            self.error(self.previous.clone(), error::INVALID_ASSIGNMENT_TARGET);
+        }
+        self.nesting -= 1;
+        expression
+    }
+
+    fn parse_list(&mut self, end: TokenType, message: &str) -> Vec<Expr> {
+        let mut items = vec![];
+        if !self.check(end) {
+            loop {
+                items.push(self.parse_expression());
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(end, message);
+        items
+    }
+
+    fn parse_prefix(&mut self, precedence: Precedence) -> Expr {
+        let token = self.previous.clone();
+        match token.token_type {
+            TokenType::LeftParen => {
+                let inner = self.parse_expression();
+                self.consume(
+                    TokenType::RightParen,
+                    error::EXPECT_RIGHT_PAREN_AFTER_EXPRESSION,
+                );
+                Expr::Group(Box::new(inner))
+            }
+            TokenType::Minus | TokenType::Bang => {
+                let operand = self.parse_precedence(Precedence::Unary);
+                Expr::Unary(token.lexeme.to_string(), Box::new(operand))
+            }
+            TokenType::Number => Expr::Number(util::parse_double(&token.lexeme)),
+            TokenType::String => {
+                let mut chars = token.lexeme.to_string();
+                while self.match_token(TokenType::String) {
+                    chars.push_str(&self.previous.lexeme);
+                }
+                Expr::String(chars)
+            }
+            TokenType::True => Expr::Bool(true),
+            TokenType::False => Expr::Bool(false),
+            TokenType::Nil => Expr::Nil,
+            TokenType::This => Expr::This,
+            TokenType::Identifier => {
+                let name = token.lexeme.to_string();
+                if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
+                    Expr::Assign(name, Box::new(self.parse_expression()))
+                } else {
+                    Expr::Variable(name)
+                }
+            }
+            TokenType::LeftBracket => Expr::List(
+                self.parse_list(TokenType::RightBracket, error::EXPECT_RIGHT_BRACKET_AFTER_ELEMENTS),
+            ),
+            TokenType::LeftBrace => {
+                let mut entries = vec![];
+                if !self.check(TokenType::RightBrace) {
+                    loop {
+                        let key = self.parse_expression();
+                        self.consume(TokenType::Colon, error::EXPECT_COLON_AFTER_KEY);
+                        entries.push((key, self.parse_expression()));
+                        if !self.match_token(TokenType::Comma) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(
+                    TokenType::RightBrace,
+                    error::EXPECT_RIGHT_BRACE_AFTER_ENTRIES,
+                );
+                Expr::Map(entries)
+            }
+            TokenType::Fun => {
+                let (params, body) = self.parse_function();
+                Expr::Lambda(params, body)
+            }
+            _ => {
+                self.error(token, error::EXPECT_EXPRESSION);
+                Expr::Nil
+            }
+        }
+    }
+
+    fn parse_infix(&mut self, left: Expr, precedence: Precedence) -> Expr {
+        let token = self.previous.clone();
+        let left = Box::new(left);
+        match token.token_type {
+            TokenType::Minus
+            | TokenType::Plus
+            | TokenType::Star
+            | TokenType::Slash
+            | TokenType::EqualEqual
+            | TokenType::BangEqual
+            | TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual
+            | TokenType::And
+            | TokenType::Or => {
+                let right = self.parse_precedence(Precedence::from(token.token_type) + 1);
+                Expr::Binary(left, token.lexeme.to_string(), Box::new(right))
+            }
+            TokenType::Question => {
+                let then_branch = self.parse_expression();
+                self.consume(TokenType::Colon, error::EXPECT_COLON_IN_TERNARY);
+                let else_branch = self.parse_precedence(Precedence::Ternary);
+                Expr::Ternary(left, Box::new(then_branch), Box::new(else_branch))
+            }
+            TokenType::LeftParen => Expr::Call(
+                left,
+                self.parse_list(TokenType::RightParen, error::EXPECT_RIGHT_PAREN_AFTER_ARG),
+            ),
+            TokenType::Dot => {
+                let name = self.name(error::EXPECT_PROPERTY_NAME);
+                if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
+                    Expr::Set(left, name, Box::new(self.parse_expression()))
+                } else {
+                    Expr::Get(left, name)
+                }
+            }
+            TokenType::LeftBracket => {
+                let index = Box::new(self.parse_expression());
+                self.consume(
+                    TokenType::RightBracket,
+                    error::EXPECT_RIGHT_BRACKET_AFTER_INDEX,
+                );
+                if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
+                    Expr::IndexSet(left, index, Box::new(self.parse_expression()))
+                } else {
+                    Expr::Index(left, index)
+                }
+            }
+            _ => {
+                self.error(token, error::EXPECT_INFIX_OPERATOR);
+                *left
+            }
+        }
+    }
+}
+
+// One node per line, children indented under their parent
+pub fn dump(statements: &[Stmt]) -> String {
+    let mut out = String::new();
+    for statement in statements {
+        dump_stmt(&mut out, statement, 0);
+    }
+    out
+}
+
+fn line(out: &mut String, depth: usize, label: &str) {
+    writeln!(out, "{:indent$}{}", "", label, indent = depth * 2).ok();
+}
+
+fn dump_block(out: &mut String, label: &str, statements: &[Stmt], depth: usize) {
+    line(out, depth, label);
+    for statement in statements {
+        dump_stmt(out, statement, depth + 1);
+    }
+}
+
+fn dump_stmt(out: &mut String, statement: &Stmt, depth: usize) {
+    match statement {
+        Stmt::Expression(expression) => {
+            line(out, depth, "Expression");
+            dump_expr(out, expression, depth + 1);
+        }
+        Stmt::Print(expression) => {
+            line(out, depth, "Print");
+            dump_expr(out, expression, depth + 1);
+        }
+        Stmt::Var(name, value) => {
+            line(out, depth, &format!("Var {}", name));
+            if let Some(value) = value {
+                dump_expr(out, value, depth + 1);
+            }
+        }
+        Stmt::Block(statements) => dump_block(out, "Block", statements, depth),
+        Stmt::If(condition, then_branch, else_branch) => {
+            line(out, depth, "If");
+            dump_expr(out, condition, depth + 1);
+            dump_stmt(out, then_branch, depth + 1);
+            if let Some(else_branch) = else_branch {
+                line(out, depth, "Else");
+                dump_stmt(out, else_branch, depth + 1);
+            }
+        }
+        Stmt::While(condition, body) => {
+            line(out, depth, "While");
+            dump_expr(out, condition, depth + 1);
+            dump_stmt(out, body, depth + 1);
+        }
+        Stmt::For(initializer, condition, increment, body) => {
+            line(out, depth, "For");
+            if let Some(initializer) = initializer {
+                dump_stmt(out, initializer, depth + 1);
+            }
+            if let Some(condition) = condition {
+                dump_expr(out, condition, depth + 1);
+            }
+            if let Some(increment) = increment {
+                dump_expr(out, increment, depth + 1);
+            }
+            dump_stmt(out, body, depth + 1);
+        }
+        Stmt::Return(value) => {
+            line(out, depth, "Return");
+            if let Some(value) = value {
+                dump_expr(out, value, depth + 1);
+            }
+        }
+        Stmt::Throw(value) => {
+            line(out, depth, "Throw");
+            dump_expr(out, value, depth + 1);
+        }
+        Stmt::Try(body, catch, finally) => {
+            dump_block(out, "Try", body, depth);
+            if let Some((name, body)) = catch {
+                dump_block(out, &format!("Catch {}", name), body, depth);
+            }
+            if let Some(body) = finally {
+                dump_block(out, "Finally", body, depth);
+            }
+        }
+        Stmt::Function(name, params, body) => {
+            dump_block(out, &format!("Fun {}({})", name, params.join(", ")), body, depth)
+        }
+        Stmt::Getter(name, body) => dump_block(out, &format!("Getter {}", name), body, depth),
+        Stmt::Setter(name, param, body) => {
+            dump_block(out, &format!("Setter {}({})", name, param), body, depth)
+        }
+        Stmt::Class(name, methods) => dump_block(out, &format!("Class {}", name), methods, depth),
+        Stmt::Extern(name, params) => line(out, depth, &format!("Extern {}({})", name, params.join(", "))),
+    }
+}
+
+fn dump_expr(out: &mut String, expression: &Expr, depth: usize) {
+    let children: Vec<&Expr> = match expression {
+        Expr::Number(value) => return line(out, depth, &format!("Number {}", value)),
+        Expr::String(value) => return line(out, depth, &format!("String {:?}", value)),
+        Expr::Bool(value) => return line(out, depth, &format!("Bool {}", value)),
+        Expr::Nil => return line(out, depth, "Nil"),
+        Expr::Variable(name) => return line(out, depth, &format!("Variable {}", name)),
+        Expr::This => return line(out, depth, "This"),
+        Expr::Lambda(params, body) => {
+            return dump_block(out, &format!("Fun ({})", params.join(", ")), body, depth)
+        }
+        Expr::Assign(name, value) => {
+            line(out, depth, &format!("Assign {}", name));
+            vec![value]
+        }
+        Expr::Unary(operator, operand) => {
+            line(out, depth, &format!("Unary {}", operator));
+            vec![operand]
+        }
+        Expr::Binary(left, operator, right) => {
+            line(out, depth, &format!("Binary {}", operator));
+            vec![left, right]
+        }
+        Expr::Ternary(condition, then_branch, else_branch) => {
+            line(out, depth, "Ternary");
+            vec![condition, then_branch, else_branch]
+        }
+        Expr::Group(inner) => {
+            line(out, depth, "Group");
+            vec![inner]
+        }
+        Expr::Call(callee, args) => {
+            line(out, depth, "Call");
+            std::iter::once(&**callee).chain(args.iter()).collect()
+        }
+        Expr::Get(object, name) => {
+            line(out, depth, &format!("Get {}", name));
+            vec![object]
+        }
+        Expr::Set(object, name, value) => {
+            line(out, depth, &format!("Set {}", name));
+            vec![object, value]
+        }
+        Expr::Index(object, index) => {
+            line(out, depth, "Index");
+            vec![object, index]
+        }
+        Expr::IndexSet(object, index, value) => {
+            line(out, depth, "IndexSet");
+            vec![object, index, value]
+        }
+        Expr::List(items) => {
+            line(out, depth, "List");
+            items.iter().collect()
+        }
+        Expr::Map(entries) => {
+            line(out, depth, "Map");
+            entries.iter().flat_map(|(key, value)| vec![key, value]).collect()
+        }
+    };
+    for child in children {
+        dump_expr(out, child, depth + 1);
+    }
+}
@@ -0,0 +1,320 @@
+use std::{cell::RefCell, convert::TryFrom};
+
+use crate::{chunk::Chunk, op_code::OpCode};
+
+// Instructions packed into bytes, which is what the VM runs: a one byte opcode followed
+// by its operands, each a LEB128 varint, so small operands take one byte where the enum
+// always takes a usize. Constant indices are fixed width instead, one byte for
+// OpConstant and two for OpConstantLong, which the compiler keeps within MAX_CONSTANTS.
+// Jumps take four bytes and count bytes, as ip does for a packed chunk. Four bytes span
+// any chunk that fits in memory, so unlike constants they need no long form.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PackedCode {
+    // Quickening rewrites opcodes in place
+    pub bytes: RefCell<Vec<u8>>,
+    // Offset of each instruction and of the end, sorted, so an ip maps back to its
+    // instruction without decoding everything before it
+    starts: Vec<usize>,
+}
+
+impl PackedCode {
+    // None when an operand is too wide for its encoding
+    pub fn pack(codes: &[OpCode]) -> Option<Self> {
+        // Jumps have a fixed width, so where every instruction starts is known before
+        // any distance between them is
+        let mut starts = Vec::with_capacity(codes.len() + 1);
+        let mut scratch = vec![];
+        for code in codes {
+            starts.push(scratch.len());
+            if !encode(code, &mut scratch) {
+                return None;
+            }
+        }
+        starts.push(scratch.len());
+
+        let mut bytes = Vec::with_capacity(scratch.len());
+        for (index, code) in codes.iter().enumerate() {
+            let code = match Chunk::jump_target(index, code) {
+                Some(target) => retarget(code, starts[index], *starts.get(target)?)?,
+                None if is_jump(code) => return None,
+                None => *code,
+            };
+            if !encode(&code, &mut bytes) {
+                return None;
+            }
+        }
+        Some(PackedCode {
+            bytes: RefCell::new(bytes),
+            starts,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.borrow().is_empty()
+    }
+
+    // The instruction starting at offset and its length, only ever packed by pack
+    pub fn fetch(&self, offset: usize) -> (OpCode, usize) {
+        match decode(&self.bytes.borrow(), offset) {
+            Some((code, next)) => (code, next - offset),
+            None => unreachable!("corrupt packed code at {}", offset),
+        }
+    }
+
+    // Instructions before the one at offset
+    pub fn index_of(&self, offset: usize) -> usize {
+        match self.starts.binary_search(&offset) {
+            Ok(index) | Err(index) => index,
+        }
+    }
+
+    // The instruction at index as it runs now, its jump counting instructions again
+    pub fn code_at(&self, index: usize) -> OpCode {
+        let offset = self.starts[index];
+        let (code, _) = self.fetch(offset);
+        let target = Chunk::jump_target(offset, &code)
+            .and_then(|target| self.starts.binary_search(&target).ok());
+        match target.and_then(|target| retarget(&code, index, target)) {
+            Some(unpacked) => unpacked,
+            None => code,
+        }
+    }
+
+    // Swap the instruction at offset for one of the same length, as quickening does
+    pub fn patch(&self, offset: usize, code: OpCode) -> bool {
+        let mut encoded = vec![];
+        let mut bytes = self.bytes.borrow_mut();
+        match decode(&bytes, offset) {
+            Some((_, next)) if encode(&code, &mut encoded) && encoded.len() == next - offset => {
+                bytes[offset..next].copy_from_slice(&encoded);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Back to the enum form the compiler, optimizer and disassembler work on, with jumps
+    // counting instructions again
+    pub fn unpack(&self) -> Vec<OpCode> {
+        let bytes = self.bytes.borrow();
+        let mut codes = vec![];
+        let mut starts = vec![];
+        let mut offset = 0;
+        while let Some((code, next)) = decode(&bytes, offset) {
+            codes.push(code);
+            starts.push(offset);
+            offset = next;
+        }
+        starts.push(offset);
+        for (index, code) in codes.iter_mut().enumerate() {
+            let target = Chunk::jump_target(starts[index], code)
+                .and_then(|target| starts.binary_search(&target).ok());
+            if let Some(unpacked) = target.and_then(|target| retarget(code, index, target)) {
+                *code = unpacked;
+            }
+        }
+        codes
+    }
+}
+
+fn is_jump(code: &OpCode) -> bool {
+    matches!(
+        code,
+        OpCode::OpJump(_) | OpCode::OpJumpIfFalse(_) | OpCode::OpJumpIfTrue(_) | OpCode::OpLoop(_) | OpCode::OpTry(_)
+    )
+}
+
+// The same jump from position from to position to
+fn retarget(code: &OpCode, from: usize, to: usize) -> Option<OpCode> {
+    Some(match *code {
+        OpCode::OpJump(_) => OpCode::OpJump(to.checked_sub(from)?),
+        OpCode::OpJumpIfFalse(_) => OpCode::OpJumpIfFalse(to.checked_sub(from)?),
+        OpCode::OpJumpIfTrue(_) => OpCode::OpJumpIfTrue(to.checked_sub(from)?),
+        OpCode::OpTry(_) => OpCode::OpTry(to.checked_sub(from)?),
+        OpCode::OpLoop(_) => OpCode::OpLoop(from.checked_sub(to)?),
+        _ => return None,
+    })
+}
+
+fn write_operand(mut value: usize, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_operand(bytes: &[u8], offset: &mut usize) -> Option<usize> {
+    let mut value: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*offset)?;
+        *offset += 1;
+        if shift >= usize::BITS {
+            return None;
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+// The opcode byte and operands of an instruction
+fn split(code: &OpCode) -> (u8, Option<usize>, Option<usize>) {
+    match *code {
+        OpCode::OpReturn => (0, None, None),
+        OpCode::OpConstant(i) => (1, Some(i), None),
+        OpCode::OpNegate => (2, None, None),
+        OpCode::OpAdd => (3, None, None),
+        OpCode::OpSubtract => (4, None, None),
+        OpCode::OpMultiply => (5, None, None),
+        OpCode::OpDivide => (6, None, None),
+        OpCode::OpNil => (7, None, None),
+        OpCode::OpTrue => (8, None, None),
+        OpCode::OpFalse => (9, None, None),
+        OpCode::OpNot => (10, None, None),
+        OpCode::OpEqual => (11, None, None),
+        OpCode::OpGreater => (12, None, None),
+        OpCode::OpLess => (13, None, None),
+        OpCode::OpPrint => (14, None, None),
+        OpCode::OpPop => (15, None, None),
+        OpCode::OpDefineGlobal(i) => (16, Some(i), None),
+        OpCode::OpGetGlobal(i) => (17, Some(i), None),
+        OpCode::OpSetGlobal(i) => (18, Some(i), None),
+        OpCode::OpGetLocal(i) => (19, Some(i), None),
+        OpCode::OpSetLocal(i) => (20, Some(i), None),
+        OpCode::OpJumpIfFalse(i) => (21, Some(i), None),
+        OpCode::OpJump(i) => (22, Some(i), None),
+        OpCode::OpLoop(i) => (23, Some(i), None),
+        OpCode::OpCall(i) => (24, Some(i), None),
+        OpCode::OpGetUpValue(i) => (25, Some(i), None),
+        OpCode::OpSetUpValue(i) => (26, Some(i), None),
+        OpCode::OpClosure => (27, None, None),
+        OpCode::OpCloseUpvalue => (28, None, None),
+        OpCode::OpClass(i) => (29, Some(i), None),
+        OpCode::OpMethod(i) => (30, Some(i), None),
+        OpCode::OpGetProperty(i) => (31, Some(i), None),
+        OpCode::OpSetProperty(i) => (32, Some(i), None),
+        OpCode::OpInvoke(name, arg_count) => (33, Some(name), Some(arg_count)),
+        OpCode::OpBuildList(i) => (34, Some(i), None),
+        OpCode::OpBuildMap(i) => (35, Some(i), None),
+        OpCode::OpIndexGet => (36, None, None),
+        OpCode::OpIndexSet => (37, None, None),
+        OpCode::OpThrow => (38, None, None),
+        OpCode::OpTry(i) => (39, Some(i), None),
+        OpCode::OpPopHandler => (40, None, None),
+        OpCode::OpJumpIfTrue(i) => (41, Some(i), None),
+        OpCode::OpPopN(i) => (42, Some(i), None),
+        OpCode::OpAddNumber => (43, None, None),
+        OpCode::OpConstantLong(i) => (44, Some(i), None),
+        OpCode::OpEcho => (45, None, None),
+    }
+}
+
+// False, with nothing written, for an operand its fixed width cannot hold
+pub fn encode(code: &OpCode, out: &mut Vec<u8>) -> bool {
+    let (opcode, first, second) = split(code);
+    match *code {
+        OpCode::OpConstant(index) => match u8::try_from(index) {
+            Ok(index) => out.extend_from_slice(&[opcode, index]),
+            Err(_) => return false,
+        },
+        OpCode::OpConstantLong(index) => match u16::try_from(index) {
+            Ok(index) => {
+                out.push(opcode);
+                out.extend_from_slice(&index.to_le_bytes());
+            }
+            Err(_) => return false,
+        },
+        _ if is_jump(code) => match first.map(u32::try_from) {
+            Some(Ok(offset)) => {
+                out.push(opcode);
+                out.extend_from_slice(&offset.to_le_bytes());
+            }
+            _ => return false,
+        },
+        _ => {
+            out.push(opcode);
+            for operand in first.into_iter().chain(second) {
+                write_operand(operand, out);
+            }
+        }
+    }
+    true
+}
+
+// The instruction at offset and where the next one starts, None for bytes encode never
+// writes
+pub fn decode(bytes: &[u8], offset: usize) -> Option<(OpCode, usize)> {
+    let mut next = offset + 1;
+    let jump = || -> Option<usize> {
+        let operand = bytes.get(next..next + 4)?;
+        Some(u32::from_le_bytes([operand[0], operand[1], operand[2], operand[3]]) as usize)
+    };
+    match *bytes.get(offset)? {
+        1 => return Some((OpCode::OpConstant(*bytes.get(next)? as usize), next + 1)),
+        44 => {
+            let index = u16::from_le_bytes([*bytes.get(next)?, *bytes.get(next + 1)?]);
+            return Some((OpCode::OpConstantLong(index as usize), next + 2));
+        }
+        21 => return Some((OpCode::OpJumpIfFalse(jump()?), next + 4)),
+        22 => return Some((OpCode::OpJump(jump()?), next + 4)),
+        23 => return Some((OpCode::OpLoop(jump()?), next + 4)),
+        39 => return Some((OpCode::OpTry(jump()?), next + 4)),
+        41 => return Some((OpCode::OpJumpIfTrue(jump()?), next + 4)),
+        _ => {}
+    }
+    let mut operand = || read_operand(bytes, &mut next);
+    let code = match bytes[offset] {
+        0 => OpCode::OpReturn,
+        2 => OpCode::OpNegate,
+        3 => OpCode::OpAdd,
+        4 => OpCode::OpSubtract,
+        5 => OpCode::OpMultiply,
+        6 => OpCode::OpDivide,
+        7 => OpCode::OpNil,
+        8 => OpCode::OpTrue,
+        9 => OpCode::OpFalse,
+        10 => OpCode::OpNot,
+        11 => OpCode::OpEqual,
+        12 => OpCode::OpGreater,
+        13 => OpCode::OpLess,
+        14 => OpCode::OpPrint,
+        15 => OpCode::OpPop,
+        16 => OpCode::OpDefineGlobal(operand()?),
+        17 => OpCode::OpGetGlobal(operand()?),
+        18 => OpCode::OpSetGlobal(operand()?),
+        19 => OpCode::OpGetLocal(operand()?),
+        20 => OpCode::OpSetLocal(operand()?),
+        24 => OpCode::OpCall(operand()?),
+        25 => OpCode::OpGetUpValue(operand()?),
+        26 => OpCode::OpSetUpValue(operand()?),
+        27 => OpCode::OpClosure,
+        28 => OpCode::OpCloseUpvalue,
+        29 => OpCode::OpClass(operand()?),
+        30 => OpCode::OpMethod(operand()?),
+        31 => OpCode::OpGetProperty(operand()?),
+        32 => OpCode::OpSetProperty(operand()?),
+        33 => {
+            let name = operand()?;
+            OpCode::OpInvoke(name, operand()?)
+        }
+        34 => OpCode::OpBuildList(operand()?),
+        35 => OpCode::OpBuildMap(operand()?),
+        36 => OpCode::OpIndexGet,
+        37 => OpCode::OpIndexSet,
+        38 => OpCode::OpThrow,
+        40 => OpCode::OpPopHandler,
+        42 => OpCode::OpPopN(operand()?),
+        43 => OpCode::OpAddNumber,
+        45 => OpCode::OpEcho,
+        _ => return None,
+    };
+    Some((code, next))
+}
@@ -1,18 +1,48 @@
 use std::{cell::RefCell, fmt::Debug};
 use std::{fmt::Display, vec};
 use std::{
+    collections::HashMap,
+    convert::TryFrom,
     fmt::{Formatter, Result},
+    io::{self, Write},
+    hash::{BuildHasherDefault, Hash, Hasher},
+    ops::Deref,
     rc::Rc,
 };
 
-use crate::{compiler::UpValueMeta, op_code::OpCode};
+use crate::{
+    compiler::{LazyBody, UpValueMeta},
+    op_code::OpCode,
+    vm::VM,
+};
 
 #[derive(Debug, Clone)]
 pub struct Function {
     pub arity: usize,
     pub chunk: Chunk,
     pub name: String,
-    pub upvalues:Vec<UpValueMeta>
+    pub upvalues:Vec<UpValueMeta>,
+    pub lazy: Option<Rc<LazyBody>>,
+    // Set for methods the VM runs on property reads or assignments instead of calls
+    pub accessor: Option<Accessor>,
+    pub verified: Verified,
+}
+
+// Outcome of checking a function's stack effects, kept from its first call on. A clone
+// starts over, whoever clones a function may go on to change its chunk.
+#[derive(Debug, Default)]
+pub struct Verified(std::cell::OnceCell<std::result::Result<(), String>>);
+
+impl Clone for Verified {
+    fn clone(&self) -> Self {
+        Verified::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Accessor {
+    Getter,
+    Setter,
 }
 
 #[derive(Debug,Clone, Copy)]
@@ -24,7 +54,7 @@ pub struct UpValue{
 impl UpValue {
     pub fn new(location:usize)->UpValue{
         UpValue{
-            location:location,
+            location,
             is_hoist:false
         }
     }
@@ -42,21 +72,57 @@ pub struct Closure {
 impl Closure {
     pub fn new(function:Rc<Function>) -> Closure {
         Closure {
-            function:function,
+            function,
             upvalues:vec![]
         }
     }
 }
 
 impl Function {
+    // Verbose form for the disassembler, including the function's own chunk
+    pub fn disassemble(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out)?;
+        if self.lazy.is_some() {
+            return writeln!(out, "== {} (not compiled yet) ==", self);
+        }
+        self.chunk.disassemble(
+            out,
+            &format!("{} upvalues: {}", self, self.upvalues.len()),
+        )
+    }
+
     pub fn new(arity: usize, chunk: Chunk, name: String,upvalues:Vec<UpValueMeta>) -> Function {
         Function {
-            arity: arity,
-            chunk: chunk,
-            name: name,
-            upvalues:upvalues
+            arity,
+            chunk,
+            name,
+            upvalues,
+            lazy: None,
+            accessor: None,
+            verified: Verified::default(),
         }
     }
+
+    pub fn lazy(arity: usize, name: String, body: LazyBody) -> Function {
+        Function {
+            arity,
+            chunk: Chunk::new(),
+            name,
+            upvalues: vec![],
+            lazy: Some(Rc::new(body)),
+            accessor: None,
+            verified: Verified::default(),
+        }
+    }
+
+    // Whether the body keeps the stack in bounds, as the unchecked accesses of unsafe-fast
+    // assume. Worked out once, the VM asks before every call.
+    pub fn verify(&self) -> std::result::Result<(), String> {
+        self.verified
+            .0
+            .get_or_init(|| self.chunk.verify_stack(self.arity + 1))
+            .clone()
+    }
 }
 
 pub struct CallFrame<'a> {
@@ -68,15 +134,420 @@ pub struct CallFrame<'a> {
     pub base: i32,
 }
 
+// FNV-1a, as clox uses for its strings
+pub fn hash_str(chars: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in chars.bytes() {
+        hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// String contents with their hash computed once, so tables never rehash them
+#[derive(Debug, Clone)]
+pub struct LoxString {
+    pub chars: String,
+    pub hash: u64,
+}
+
+impl LoxString {
+    pub fn new(chars: String) -> Self {
+        let hash = hash_str(&chars);
+        LoxString { chars, hash }
+    }
+
+    pub fn concat(&self, other: &LoxString) -> LoxString {
+        LoxString::new(self.chars.clone() + &other.chars)
+    }
+}
+
+impl From<&str> for LoxString {
+    fn from(chars: &str) -> Self {
+        LoxString::new(chars.to_owned())
+    }
+}
+
+impl Deref for LoxString {
+    type Target = String;
+    fn deref(&self) -> &String {
+        &self.chars
+    }
+}
+
+impl PartialEq for LoxString {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.chars == other.chars
+    }
+}
+
+impl Eq for LoxString {}
+
+impl Hash for LoxString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl Display for LoxString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.chars)
+    }
+}
+
+// Hasher for keys that already carry their hash, it passes the cached value through
+#[derive(Default)]
+pub struct CachedHasher(u64);
+
+impl Hasher for CachedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 ^= hash;
+    }
+}
+
+pub type CachedHashMap<K, V> = HashMap<K, V, BuildHasherDefault<CachedHasher>>;
+
+// Failure reported by a native, the VM turns it into a runtime error
+#[derive(Debug, Clone, PartialEq)]
+pub struct NativeError(pub String);
+
+pub type NativeResult = std::result::Result<Value, NativeError>;
+
+// A host function, it may use the VM to read or set globals while it runs
+pub type NativeFn = dyn Fn(&mut VM, &[Value]) -> NativeResult;
+
+// A native that captures nothing, as listed in tables of natives
+pub type NativeFnPointer = fn(&mut VM, &[Value]) -> NativeResult;
+
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    // Receives the arguments in call order, the VM has already checked their count
+    pub function: Box<NativeFn>,
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "Native {{ name: {:?}, arity: {} }}", self.name, self.arity)
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: Rc<LoxString>,
+    pub methods: RefCell<CachedHashMap<Rc<LoxString>, Rc<Closure>>>,
+    pub getters: RefCell<CachedHashMap<Rc<LoxString>, Rc<Closure>>>,
+    pub setters: RefCell<CachedHashMap<Rc<LoxString>, Rc<Closure>>>,
+    // Shape of a new instance, with no fields
+    pub shape: Rc<Shape>,
+}
+
+impl Class {
+    pub fn new(name: Rc<LoxString>) -> Class {
+        Class {
+            name,
+            methods: RefCell::new(CachedHashMap::default()),
+            getters: RefCell::new(CachedHashMap::default()),
+            setters: RefCell::new(CachedHashMap::default()),
+            shape: Rc::new(Shape::default()),
+        }
+    }
+}
+
+// Field layout shared by the instances of a class that gained the same fields in the
+// same order. Each class has its own tree of shapes, so a shape also tells the class.
+#[derive(Debug, Default)]
+pub struct Shape {
+    // Field names in slot order
+    pub names: Vec<Rc<LoxString>>,
+    slots: CachedHashMap<Rc<LoxString>, usize>,
+    // Shapes with one more field, made the first time an instance adds it
+    transitions: RefCell<CachedHashMap<Rc<LoxString>, Rc<Shape>>>,
+}
+
+impl Shape {
+    pub fn slot(&self, name: &LoxString) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    // The shape after adding name as the next field
+    pub fn with(&self, name: &Rc<LoxString>) -> Rc<Shape> {
+        if let Some(next) = self.transitions.borrow().get(name) {
+            return next.clone();
+        }
+        let mut names = self.names.clone();
+        names.push(name.clone());
+        let mut slots = self.slots.clone();
+        slots.insert(name.clone(), self.names.len());
+        let next = Rc::new(Shape {
+            names,
+            slots,
+            transitions: RefCell::new(CachedHashMap::default()),
+        });
+        self.transitions.borrow_mut().insert(name.clone(), next.clone());
+        next
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub shape: RefCell<Rc<Shape>>,
+    // Field values in the slots the shape gives them
+    pub fields: RefCell<Vec<Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Instance {
+        let shape = class.shape.clone();
+        Instance {
+            class,
+            shape: RefCell::new(shape),
+            fields: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn get_field(&self, name: &LoxString) -> Option<Value> {
+        let slot = self.shape.borrow().slot(name)?;
+        Some(self.fields.borrow()[slot].clone())
+    }
+
+    pub fn has_field(&self, name: &LoxString) -> bool {
+        self.shape.borrow().slot(name).is_some()
+    }
+
+    // Overwrite the field, or add it and move to the next shape
+    pub fn set_field(&self, name: &Rc<LoxString>, value: Value) {
+        let slot = self.shape.borrow().slot(name);
+        match slot {
+            Some(slot) => self.fields.borrow_mut()[slot] = value,
+            None => {
+                let next = self.shape.borrow().with(name);
+                *self.shape.borrow_mut() = next;
+                self.fields.borrow_mut().push(value);
+            }
+        }
+    }
+}
+
+// Method read off an instance, calling it puts the instance in slot 0 as `this`
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+// Entries stay in the order they were first inserted, which is the order keys, values,
+// entries and print list them in
+pub type LoxMap = indexmap::IndexMap<Value, Value>;
+
 #[derive(Debug, Clone)]
 pub enum Value {
     Bool(bool),
     Double(f64),
+    // Literals without a fraction, exact until an operation has to give a double
+    Int(i64),
     Nil,
     Function(Rc<Function>),
-    String(Rc<String>),
-    NativeFunction(Box<fn()->Value>),
-    Closure(Rc<Closure>)
+    String(Rc<LoxString>),
+    NativeFunction(Rc<Native>),
+    Closure(Rc<Closure>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<LoxMap>>),
+}
+
+impl Value {
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Double(v) => Some(*v),
+            Value::Int(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+
+    // Result of an arithmetic or comparison instruction on two numbers, None when either
+    // operand is not one. Ints stay exact unless the result overflows, division always
+    // gives a double.
+    pub fn arithmetic(code: OpCode, left: &Value, right: &Value) -> Option<Value> {
+        if let (Value::Int(left), Value::Int(right)) = (left, right) {
+            let exact = match code {
+                OpCode::OpAdd | OpCode::OpAddNumber => left.checked_add(*right),
+                OpCode::OpSubtract => left.checked_sub(*right),
+                OpCode::OpMultiply => left.checked_mul(*right),
+                OpCode::OpGreater => return Some(Value::Bool(left > right)),
+                OpCode::OpLess => return Some(Value::Bool(left < right)),
+                _ => None,
+            };
+            if let Some(value) = exact {
+                return Some(Value::Int(value));
+            }
+        }
+        let (left, right) = (left.as_number()?, right.as_number()?);
+        Some(match code {
+            OpCode::OpAdd | OpCode::OpAddNumber => Value::Double(left + right),
+            OpCode::OpSubtract => Value::Double(left - right),
+            OpCode::OpMultiply => Value::Double(left * right),
+            OpCode::OpDivide => Value::Double(left / right),
+            OpCode::OpGreater => Value::Bool(left > right),
+            OpCode::OpLess => Value::Bool(left < right),
+            _ => return None,
+        })
+    }
+
+    pub fn negate(&self) -> Option<Value> {
+        match self {
+            Value::Int(v) => Some(v.checked_neg().map_or(Value::Double(-(*v as f64)), Value::Int)),
+            Value::Double(v) => Some(Value::Double(-v)),
+            _ => None,
+        }
+    }
+
+    // Only nil and false are false in a condition
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(v) => Some(v.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    // Indented rendering for inspecting values, nested values go one level deeper
+    pub fn pretty(&self, depth: usize) -> String {
+        let indent = "  ".repeat(depth);
+        match self {
+            Value::String(v) => format!("{}{:?}", indent, v.chars),
+            // Holding the borrow marks the list as open, a cycle back to it prints [...]
+            Value::List(list) => match list.try_borrow_mut() {
+                Ok(items) if items.is_empty() => format!("{}[]", indent),
+                Ok(items) => {
+                    let items: Vec<String> = items.iter().map(|v| v.pretty(depth + 1)).collect();
+                    format!("{}[\n{}\n{}]", indent, items.join(",\n"), indent)
+                }
+                Err(_) => format!("{}[...]", indent),
+            },
+            Value::Map(map) => match map.try_borrow_mut() {
+                Ok(entries) if entries.is_empty() => format!("{}{{}}", indent),
+                Ok(entries) => {
+                    let inner = "  ".repeat(depth + 1);
+                    let entries: Vec<String> = entries
+                        .iter()
+                        .map(|(k, v)| {
+                            let value = v.pretty(depth + 1);
+                            format!("{}{}: {}", inner, k.pretty(0), value.trim_start())
+                        })
+                        .collect();
+                    format!("{}{{\n{}\n{}}}", indent, entries.join(",\n"), indent)
+                }
+                Err(_) => format!("{}{{...}}", indent),
+            },
+            // Fields in name order, a cycle back to the instance prints Name {...}
+            Value::Instance(instance) => match instance.fields.try_borrow_mut() {
+                Ok(fields) if fields.is_empty() => format!("{}{} {{}}", indent, instance.class.name),
+                Ok(fields) => {
+                    let inner = "  ".repeat(depth + 1);
+                    let shape = instance.shape.borrow();
+                    let mut fields: Vec<_> = shape.names.iter().zip(fields.iter()).collect();
+                    fields.sort_by(|(left, _), (right, _)| left.chars.cmp(&right.chars));
+                    let fields: Vec<String> = fields
+                        .into_iter()
+                        .map(|(name, v)| {
+                            let value = v.pretty(depth + 1);
+                            format!("{}{}: {}", inner, name, value.trim_start())
+                        })
+                        .collect();
+                    format!("{}{} {{\n{}\n{}}}", indent, instance.class.name, fields.join(",\n"), indent)
+                }
+                Err(_) => format!("{}{} {{...}}", indent, instance.class.name),
+            },
+            _ => format!("{}{}", indent, self),
+        }
+    }
+
+    // Copy that shares no mutable state with the original; functions stay shared as code
+    pub fn deep_clone(&self) -> Value {
+        self.deep_clone_with(&mut HashMap::new())
+    }
+
+    // Containers already copied, by address, so shared and cyclic ones keep their shape
+    fn deep_clone_with(&self, copies: &mut HashMap<usize, Value>) -> Value {
+        match self {
+            Value::String(v) => Value::String(Rc::new((**v).clone())),
+            Value::List(list) => {
+                if let Some(copy) = copies.get(&(Rc::as_ptr(list) as usize)) {
+                    return copy.clone();
+                }
+                let copy = Rc::new(RefCell::new(vec![]));
+                copies.insert(Rc::as_ptr(list) as usize, Value::List(copy.clone()));
+                let items = list
+                    .borrow()
+                    .iter()
+                    .map(|v| v.deep_clone_with(copies))
+                    .collect();
+                *copy.borrow_mut() = items;
+                Value::List(copy)
+            }
+            Value::Map(map) => {
+                if let Some(copy) = copies.get(&(Rc::as_ptr(map) as usize)) {
+                    return copy.clone();
+                }
+                let copy = Rc::new(RefCell::new(LoxMap::default()));
+                copies.insert(Rc::as_ptr(map) as usize, Value::Map(copy.clone()));
+                // Keys are strings and numbers, nothing mutable to copy or hash
+                #[allow(clippy::mutable_key_type)]
+                let entries = map
+                    .borrow()
+                    .iter()
+                    .map(|(k, v)| (k.deep_clone(), v.deep_clone_with(copies)))
+                    .collect();
+                *copy.borrow_mut() = entries;
+                Value::Map(copy)
+            }
+            // The class and shape are shared, the field values copied
+            Value::Instance(instance) => {
+                if let Some(copy) = copies.get(&(Rc::as_ptr(instance) as usize)) {
+                    return copy.clone();
+                }
+                let copy = Rc::new(Instance::new(instance.class.clone()));
+                copies.insert(Rc::as_ptr(instance) as usize, Value::Instance(copy.clone()));
+                let fields = instance
+                    .fields
+                    .borrow()
+                    .iter()
+                    .map(|v| v.deep_clone_with(copies))
+                    .collect();
+                *copy.shape.borrow_mut() = instance.shape.borrow().clone();
+                *copy.fields.borrow_mut() = fields;
+                Value::Instance(copy)
+            }
+            _ => self.clone(),
+        }
+    }
 }
 
 impl PartialEq for Value {
@@ -84,219 +555,945 @@ impl PartialEq for Value {
         match (self, other) {
             (Value::Bool(left_v), Value::Bool(right_v)) => left_v == right_v,
             (Value::Double(left_v), Value::Double(right_v)) => left_v == right_v,
+            (Value::Int(left_v), Value::Int(right_v)) => left_v == right_v,
+            // Exact, so that equality stays transitive for ints beyond 2^53
+            (Value::Int(int), Value::Double(double)) | (Value::Double(double), Value::Int(int)) => {
+                double.fract() == 0.0
+                    && *double >= i64::MIN as f64
+                    && *double < i64::MAX as f64
+                    && *double as i64 == *int
+            }
             (Value::Nil, Value::Nil) => true,
             (Value::String(left_v), Value::String(right_v)) => left_v == right_v,
+            (Value::Class(left_v), Value::Class(right_v)) => Rc::ptr_eq(left_v, right_v),
+            (Value::Instance(left_v), Value::Instance(right_v)) => Rc::ptr_eq(left_v, right_v),
+            (Value::List(left_v), Value::List(right_v)) => Rc::ptr_eq(left_v, right_v),
+            (Value::Map(left_v), Value::Map(right_v)) => Rc::ptr_eq(left_v, right_v),
+            (Value::Function(left_v), Value::Function(right_v)) => Rc::ptr_eq(left_v, right_v),
+            (Value::Closure(left_v), Value::Closure(right_v)) => Rc::ptr_eq(left_v, right_v),
+            (Value::NativeFunction(left_v), Value::NativeFunction(right_v)) => {
+                Rc::ptr_eq(left_v, right_v)
+            }
+            // Each property read binds anew, a.f == a.f as long as it finds the same method
+            (Value::BoundMethod(left_v), Value::BoundMethod(right_v)) => {
+                left_v.receiver == right_v.receiver && Rc::ptr_eq(&left_v.method, &right_v.method)
+            }
             _ => false,
         }
     }
 }
 
-impl From<Value> for bool {
-    fn from(value: Value) -> Self {
-        match value {
-            Value::Bool(v) => v,
-            Value::Nil => false,
-            _ => true,
+// Only strings and non-NaN numbers are used as map keys, and those are reflexive
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Bool(v) => v.hash(state),
+            // 0.0 and -0.0 are equal, so they must hash the same
+            Value::Double(v) if *v == 0.0 => state.write_u64(0),
+            Value::Double(v) => state.write_u64(v.to_bits()),
+            // Hashed like the double it equals
+            Value::Int(v) => Value::Double(*v as f64).hash(state),
+            Value::Nil => state.write_u64(0),
+            Value::String(v) => v.hash(state),
+            Value::Function(v) => std::ptr::hash(&**v, state),
+            Value::Closure(v) => std::ptr::hash(&**v, state),
+            Value::NativeFunction(v) => std::ptr::hash(&**v, state),
+            Value::Class(v) => std::ptr::hash(&**v, state),
+            Value::Instance(v) => std::ptr::hash(&**v, state),
+            Value::BoundMethod(v) => std::ptr::hash(&*v.method, state),
+            Value::List(v) => std::ptr::hash(&**v, state),
+            Value::Map(v) => std::ptr::hash(&**v, state),
         }
     }
 }
 
-impl From<Value> for f64 {
-    fn from(value: Value) -> Self {
-        match value {
-            Value::Double(f) => f,
-            Value::Bool(v) => (v as i32) as f64,
-            Value::Nil => 0.0,
-            _ => 0.0,
-        }
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Double(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(Rc::new(LoxString::new(value)))
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(Rc::new(LoxString::from(value)))
+    }
+}
+
+// Typed arguments for natives, `let n = f64::try_from(&args[0])?;`
+impl TryFrom<&Value> for f64 {
+    type Error = NativeError;
+
+    fn try_from(value: &Value) -> std::result::Result<Self, Self::Error> {
+        value
+            .as_number()
+            .ok_or_else(|| NativeError(format!("expected a number but got {}", value)))
+    }
+}
+
+impl TryFrom<&Value> for bool {
+    type Error = NativeError;
+
+    fn try_from(value: &Value) -> std::result::Result<Self, Self::Error> {
+        value
+            .as_bool()
+            .ok_or_else(|| NativeError(format!("expected a boolean but got {}", value)))
+    }
+}
+
+impl TryFrom<&Value> for String {
+    type Error = NativeError;
+
+    fn try_from(value: &Value) -> std::result::Result<Self, Self::Error> {
+        value
+            .as_str()
+            .map(str::to_owned)
+            .ok_or_else(|| NativeError(format!("expected a string but got {}", value)))
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = NativeError;
+
+    fn try_from(value: Value) -> std::result::Result<Self, Self::Error> {
+        f64::try_from(&value)
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = NativeError;
+
+    fn try_from(value: Value) -> std::result::Result<Self, Self::Error> {
+        bool::try_from(&value)
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = NativeError;
+
+    fn try_from(value: Value) -> std::result::Result<Self, Self::Error> {
+        String::try_from(&value)
     }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
-            Value::Bool(v) => write!(f, "Bool {}", v),
-            Value::Double(v) => write!(f, "Double {}", v),
-            Value::Nil => write!(f, "Nil"),
+            Value::Bool(v) => write!(f, "{}", v),
+            // Integral numbers print without a fraction, 3 rather than 3.0
+            Value::Double(v) => write!(f, "{}", v),
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Nil => write!(f, "nil"),
             Value::String(b) => write!(f, "{}", b),
-            Value::NativeFunction(function)=>write!(f,"{:?}",function),
-            Value::Closure(function)=>write!(f,"{:?}",function),
-            Value::Function(function)=>write!(f,"{:?}",function)
+            Value::NativeFunction(_)=>write!(f,"<native fn>"),
+            Value::Closure(closure)=>write!(f,"{}",closure),
+            Value::Function(function)=>write!(f,"{}",function),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Value::BoundMethod(bound) => write!(f, "{}", bound.method),
+            // See pretty for why the borrow is held
+            Value::List(list) => match list.try_borrow_mut() {
+                Ok(items) => {
+                    let items: Vec<String> = items.iter().map(|v| v.to_string()).collect();
+                    write!(f, "[{}]", items.join(", "))
+                }
+                Err(_) => write!(f, "[...]"),
+            },
+            Value::Map(map) => match map.try_borrow_mut() {
+                Ok(entries) => {
+                    let entries: Vec<String> =
+                        entries.iter().map(|(k, v)| format!("{}: {}", k, v)).collect();
+                    write!(f, "{{{}}}", entries.join(", "))
+                }
+                Err(_) => write!(f, "{{...}}"),
+            },
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if self.name.is_empty() {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<fn {}/{}>", self.name, self.arity)
         }
     }
 }
 
+impl Display for Closure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.function)
+    }
+}
+
 #[derive(Debug, Clone,Default)]
 pub struct Chunk {
     pub codes: Vec<OpCode>,
     pub values: Vec<Value>,
-    pub lines: Vec<i32>,
+    // Run-length encoded: each entry is a line and how many instructions in a row came from it
+    pub lines: Vec<(i32, usize)>,
+    // Runtime copy of codes with hot sites rewritten, empty until the first rewrite
+    pub quickened: RefCell<Vec<OpCode>>,
+    // Consecutive monomorphic executions of each instruction
+    pub counts: RefCell<Vec<u32>>,
+    // What each property read or invoke found last, by ip, empty until the first one runs
+    pub caches: RefCell<Vec<Option<InlineCache>>>,
+    // Host functions the script declared with extern and their parameter counts, checked
+    // before it runs
+    pub externs: Vec<(String, usize)>,
+    // Byte encoding of codes the VM runs instead, made on the first fetch once the
+    // compiler and optimizer are done with codes. None if codes cannot be packed, the
+    // VM then runs codes as they are.
+    #[cfg(feature = "packed-code")]
+    pub packed: std::cell::OnceCell<Option<crate::bytecode::PackedCode>>,
+}
+
+// Constants one chunk can use, as many as the two byte index of OpConstantLong reaches
+pub const MAX_CONSTANTS: usize = 1 << 16;
+
+// A site's last lookup, good again for any instance with the same shape
+#[derive(Debug, Clone)]
+pub struct InlineCache {
+    pub shape: Rc<Shape>,
+    pub found: CachedProperty,
 }
 
+#[derive(Debug, Clone)]
+pub enum CachedProperty {
+    // Slot in the instance's fields
+    Field(usize),
+    Getter(Rc<Closure>),
+    Method(Rc<Closure>),
+}
+
+// Executions with the same operand types before a site is specialized
+pub const HOT_THRESHOLD: u32 = 64;
+
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             codes: vec![],
             values: vec![],
             lines: vec![],
+            quickened: RefCell::new(vec![]),
+            counts: RefCell::new(vec![]),
+            caches: RefCell::new(vec![]),
+            externs: vec![],
+            #[cfg(feature = "packed-code")]
+            packed: std::cell::OnceCell::new(),
         }
     }
-    pub fn disassemble(&self, name: &str) {
-        println!("== {} ==\n", name);
+
+    pub fn push_line(&mut self, line: i32) {
+        match self.lines.last_mut() {
+            Some((last, count)) if *last == line => *count += 1,
+            _ => self.lines.push((line, 1)),
+        }
+    }
+
+    // Source line of the instruction at index
+    pub fn line_at(&self, index: usize) -> Option<i32> {
+        let mut end = 0;
+        for (line, count) in self.lines.iter() {
+            end += count;
+            if index < end {
+                return Some(*line);
+            }
+        }
+        None
+    }
+
+    // Forget the lines of every instruction from len on, to go with codes.truncate(len)
+    pub fn truncate_lines(&mut self, len: usize) {
+        let mut end: usize = self.lines.iter().map(|(_, count)| count).sum();
+        while end > len {
+            let (_, count) = self.lines.last_mut().unwrap();
+            let drop = (end - len).min(*count);
+            *count -= drop;
+            end -= drop;
+            if *count == 0 {
+                self.lines.pop();
+            }
+        }
+    }
+
+    // The packed form the VM runs, None until the first fetch or when codes cannot be
+    // packed
+    #[cfg(feature = "packed-code")]
+    fn packed_code(&self) -> Option<&crate::bytecode::PackedCode> {
+        self.packed
+            .get_or_init(|| crate::bytecode::PackedCode::pack(&self.codes))
+            .as_ref()
+    }
+
+    // The instruction the VM runs at ip and how far ip moves past it. ip counts bytes in
+    // a packed chunk and instructions otherwise.
+    pub fn fetch(&self, ip: usize) -> (OpCode, usize) {
+        #[cfg(feature = "packed-code")]
+        if let Some(packed) = self.packed_code() {
+            return packed.fetch(ip);
+        }
+        let quickened = self.quickened.borrow();
+        let codes = if quickened.is_empty() {
+            &self.codes
+        } else {
+            &*quickened
+        };
+        #[cfg(feature = "unsafe-fast")]
+        // SAFETY: the dispatch loop only fetches while ip is below code_end()
+        unsafe {
+            (*codes.get_unchecked(ip), 1)
+        }
+        #[cfg(not(feature = "unsafe-fast"))]
+        (codes[ip], 1)
+    }
+
+    // Where ip stops
+    pub fn code_end(&self) -> usize {
+        #[cfg(feature = "packed-code")]
+        if let Some(packed) = self.packed_code() {
+            return packed.len();
+        }
+        self.codes.len()
+    }
+
+    // Index in codes of the instruction at ip
+    pub fn index_at(&self, ip: usize) -> usize {
+        #[cfg(feature = "packed-code")]
+        if let Some(packed) = self.packed_code() {
+            return packed.index_of(ip);
+        }
+        ip
+    }
+
+    pub fn line_at_ip(&self, ip: usize) -> Option<i32> {
+        self.line_at(self.index_at(ip))
+    }
+
+    // The instruction at index as it runs now, specialized if it has been quickened
+    pub fn code_at(&self, index: usize) -> OpCode {
+        #[cfg(feature = "packed-code")]
+        if let Some(packed) = self.packed_code() {
+            return packed.code_at(index);
+        }
+        let quickened = self.quickened.borrow();
+        if quickened.is_empty() {
+            self.codes[index]
+        } else {
+            quickened[index]
+        }
+    }
+
+    pub fn constant(&self, index: usize) -> &Value {
+        #[cfg(feature = "unsafe-fast")]
+        // SAFETY: the verifier rejects constant operands outside the pool
+        unsafe {
+            self.values.get_unchecked(index)
+        }
+        #[cfg(not(feature = "unsafe-fast"))]
+        &self.values[index]
+    }
+
+    // Count a monomorphic execution, true once the site has become hot
+    pub fn record_hit(&self, ip: usize) -> bool {
+        let mut counts = self.counts.borrow_mut();
+        if counts.is_empty() {
+            counts.resize(self.code_end(), 0);
+        }
+        counts[ip] += 1;
+        counts[ip] == HOT_THRESHOLD
+    }
+
+    pub fn record_miss(&self, ip: usize) {
+        if let Some(count) = self.counts.borrow_mut().get_mut(ip) {
+            *count = 0;
+        }
+    }
+
+    pub fn quicken(&self, ip: usize, code: OpCode) {
+        #[cfg(feature = "packed-code")]
+        if let Some(packed) = self.packed_code() {
+            packed.patch(ip, code);
+            return;
+        }
+        let mut quickened = self.quickened.borrow_mut();
+        if quickened.is_empty() {
+            *quickened = self.codes.clone();
+        }
+        quickened[ip] = code;
+    }
+
+    // What the site at ip found for an instance of shape, if that is the shape it last saw
+    pub fn cached_property(&self, ip: usize, shape: &Rc<Shape>) -> Option<CachedProperty> {
+        match self.caches.borrow().get(ip) {
+            Some(Some(cache)) if Rc::ptr_eq(&cache.shape, shape) => Some(cache.found.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn cache_property(&self, ip: usize, shape: Rc<Shape>, found: CachedProperty) {
+        let mut caches = self.caches.borrow_mut();
+        if caches.is_empty() {
+            caches.resize(self.code_end(), None);
+        }
+        caches[ip] = Some(InlineCache { shape, found });
+    }
+
+    // Put the generic instruction back after a specialized one saw other types
+    pub fn deoptimize(&self, ip: usize) {
+        self.record_miss(ip);
+        self.quicken(ip, self.codes[self.index_at(ip)]);
+    }
+    // Whether both chunks run the same code, ignoring the bodies of their functions
+    pub fn same_shape(&self, other: &Chunk) -> bool {
+        self.codes == other.codes
+            && self.values.len() == other.values.len()
+            && self
+                .values
+                .iter()
+                .zip(other.values.iter())
+                .all(|pair| match pair {
+                    (Value::Function(left), Value::Function(right)) => left.name == right.name,
+                    (left, right) => left == right,
+                })
+    }
+
+    pub fn disassemble(&self, out: &mut dyn Write, name: &str) -> io::Result<()> {
+        writeln!(out, "== {} ==\n", name)?;
         for (index, code) in self.codes.iter().enumerate() {
-            self.disassemble_op_code(code, index);
+            self.disassemble_op_code(out, code, index)?;
         }
+        for value in self.values.iter() {
+            if let Value::Function(function) = value {
+                function.disassemble(out)?;
+            }
+        }
+        Ok(())
     }
-    pub fn disassemble_op_code(&self, code: &OpCode, index: usize) {
-        print!("{:04}  ", index);
+    pub fn disassemble_op_code(
+        &self,
+        out: &mut dyn Write,
+        code: &OpCode,
+        index: usize,
+    ) -> io::Result<()> {
+        write!(out, "{:04}  ", index)?;
 
-        if index > 0 && self.lines[index] == self.lines[index - 1] {
-            print!("    | ")
+        let line = self.line_at(index).unwrap_or(0);
+        if index > 0 && self.line_at(index - 1) == Some(line) {
+            write!(out, "    | ")?;
         } else {
-            print!("{:04}", self.lines[index])
+            write!(out, "{:04}", line)?;
         }
         match code {
-            OpCode::OpConstant(i) => println!("{} {} '{}'", code, i, self.values[*i]),
-            _ => println!("{}", code),
+            OpCode::OpConstant(i) | OpCode::OpConstantLong(i) => {
+                writeln!(out, "{} {} '{}'", code, i, self.values[*i])
+            }
+            _ => writeln!(out, "{}", code),
+        }
+    }
+    // Value computed by the instructions from `start` on, if they only work on constants
+    pub fn evaluate_constant(&self, start: usize) -> Option<Value> {
+        let mut stack: Vec<Value> = vec![];
+        for code in self.codes[start..].iter() {
+            let value = match code {
+                OpCode::OpConstant(index) | OpCode::OpConstantLong(index) => match &self.values[*index] {
+                    Value::Function(_) => return None,
+                    value => value.clone(),
+                },
+                OpCode::OpNil => Value::Nil,
+                OpCode::OpTrue => Value::Bool(true),
+                OpCode::OpFalse => Value::Bool(false),
+                OpCode::OpNegate => stack.pop()?.negate()?,
+                OpCode::OpNot => Value::Bool(!stack.pop()?.is_truthy()),
+                _ => {
+                    let right = stack.pop()?;
+                    let left = stack.pop()?;
+                    match (code, left, right) {
+                        (OpCode::OpEqual, left, right) => Value::Bool(left == right),
+                        (OpCode::OpAdd, Value::String(left), Value::String(right)) => {
+                            Value::String(Rc::new(left.concat(&right)))
+                        }
+                        (code, left, right) => Value::arithmetic(*code, &left, &right)?,
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        if stack.len() == 1 {
+            stack.pop()
+        } else {
+            None
+        }
+    }
+
+    // Simulate the stack depth along every path, checking it never underflows and
+    // that all paths reaching an instruction agree on its depth. The depth before each
+    // instruction is returned, plus the depth after falling off the end.
+    pub fn stack_depths(&self, initial: usize) -> std::result::Result<Vec<Option<usize>>, String> {
+        let mut depths: Vec<Option<usize>> = vec![None; self.codes.len() + 1];
+        let mut pending = vec![(0, initial)];
+        while let Some((index, depth)) = pending.pop() {
+            if index > self.codes.len() {
+                continue;
+            }
+            match depths[index] {
+                Some(known) if known == depth => continue,
+                Some(known) => {
+                    return Err(format!(
+                        "Stack depth mismatch at {:04}: {} vs {}",
+                        index, known, depth
+                    ))
+                }
+                None => depths[index] = Some(depth),
+            }
+            if index == self.codes.len() {
+                continue;
+            }
+
+            let code = self.codes[index];
+            let (pops, pushes) = code.stack_effect();
+            if depth < pops {
+                return Err(format!("Stack underflow at {:04} {}", index, code));
+            }
+            match code {
+                OpCode::OpConstant(i)
+                | OpCode::OpConstantLong(i)
+                | OpCode::OpDefineGlobal(i)
+                | OpCode::OpGetGlobal(i)
+                | OpCode::OpSetGlobal(i)
+                | OpCode::OpClass(i)
+                | OpCode::OpMethod(i)
+                | OpCode::OpGetProperty(i)
+                | OpCode::OpSetProperty(i)
+                | OpCode::OpInvoke(i, _)
+                    if i >= self.values.len() =>
+                {
+                    return Err(format!("Constant out of range at {:04} {}", index, code))
+                }
+                OpCode::OpGetLocal(i) | OpCode::OpSetLocal(i) if i >= depth => {
+                    return Err(format!("Local out of range at {:04} {}", index, code))
+                }
+                _ => {}
+            }
+            let next = depth - pops + pushes;
+            match code {
+                OpCode::OpReturn | OpCode::OpThrow => {}
+                OpCode::OpJump(offset) => pending.push((index + offset, next)),
+                // The handler starts with the thrown value above the slots live here
+                OpCode::OpTry(offset) => {
+                    pending.push((index + offset, next + 1));
+                    pending.push((index + 1, next));
+                }
+                OpCode::OpJumpIfFalse(offset) | OpCode::OpJumpIfTrue(offset) => {
+                    pending.push((index + offset, next));
+                    pending.push((index + 1, next));
+                }
+                OpCode::OpLoop(offset) => match index.checked_sub(offset) {
+                    Some(target) => pending.push((target, next)),
+                    None => return Err(format!("Loop before start at {:04}", index)),
+                },
+                _ => pending.push((index + 1, next)),
+            }
+        }
+        Ok(depths)
+    }
+
+    pub fn verify_stack(&self, initial: usize) -> std::result::Result<(), String> {
+        self.stack_depths(initial)?;
+
+        for value in self.values.iter() {
+            if let Value::Function(function) = value {
+                function
+                    .verify()
+                    .map_err(|message| format!("{} in {}", message, function))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Disassembly with each source line printed above the instructions it produced
+    pub fn disassemble_with_source(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        source: &str,
+        color: bool,
+    ) -> io::Result<()> {
+        let source_lines: Vec<&str> = source.lines().collect();
+        writeln!(out, "== {} ==\n", name)?;
+        for (index, code) in self.codes.iter().enumerate() {
+            let line = self.line_at(index).unwrap_or(0);
+            if index == 0 || self.line_at(index - 1) != Some(line) {
+                let text = source_lines.get(line as usize).map_or("", |l| l.trim());
+                if color {
+                    writeln!(out, "\x1b[2m{:>4} | {}\x1b[0m", line, text)?;
+                } else {
+                    writeln!(out, "{:>4} | {}", line, text)?;
+                }
+            }
+            self.disassemble_annotated_op_code(out, code, index, color)?;
+        }
+        for value in self.values.iter() {
+            if let Value::Function(function) = value {
+                writeln!(out)?;
+                function
+                    .chunk
+                    .disassemble_with_source(out, &function.to_string(), source, color)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn disassemble_annotated_op_code(
+        &self,
+        out: &mut dyn Write,
+        code: &OpCode,
+        index: usize,
+        color: bool,
+    ) -> io::Result<()> {
+        let name = code.to_string();
+        let name = name.split(' ').next().unwrap_or("");
+        let operand = match code {
+            OpCode::OpConstant(i)
+            | OpCode::OpConstantLong(i)
+            | OpCode::OpDefineGlobal(i)
+            | OpCode::OpGetGlobal(i)
+            | OpCode::OpSetGlobal(i)
+            | OpCode::OpClass(i)
+            | OpCode::OpMethod(i)
+            | OpCode::OpGetProperty(i)
+            | OpCode::OpSetProperty(i) => format!("{} '{}'", i, self.values[*i]),
+            OpCode::OpInvoke(i, arg_count) => {
+                format!("{} '{}' ({} args)", i, self.values[*i], arg_count)
+            }
+            OpCode::OpJumpIfFalse(offset)
+            | OpCode::OpJumpIfTrue(offset)
+            | OpCode::OpJump(offset)
+            | OpCode::OpTry(offset) => format!("{} -> {:04}", offset, index + offset),
+            OpCode::OpLoop(offset) => format!("{} -> {:04}", offset, index.wrapping_sub(*offset)),
+            _ => code.operand().map_or(String::new(), |i| i.to_string()),
+        };
+        if color {
+            writeln!(out, "       {:04}  \x1b[1;36m{:<16}\x1b[0m \x1b[33m{}\x1b[0m", index, name, operand)
+        } else {
+            writeln!(out, "{}", format!("       {:04}  {:<16} {}", index, name, operand).trim_end())
         }
     }
+
     pub fn add_op_return(&mut self, line: i32) {
         self.codes.push(OpCode::OpReturn);
-        self.lines.push(line);
+        self.push_line(line);
     }
-    pub fn add_op_constant(&mut self, value: Value, line: i32) {
+    // False when the pool is full and nothing was added
+    pub fn add_op_constant(&mut self, value: Value, line: i32) -> bool {
+        if self.values.len() >= MAX_CONSTANTS {
+            return false;
+        }
         self.values.push(value);
         let index = self.values.len() - 1;
-        self.codes.push(OpCode::OpConstant(index));
-        self.lines.push(line);
+        self.codes.push(OpCode::constant(index));
+        self.push_line(line);
+        true
     }
     pub fn add_op_negate(&mut self, line: i32) {
         self.codes.push(OpCode::OpNegate);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_add(&mut self, line: i32) {
         self.codes.push(OpCode::OpAdd);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_subtract(&mut self, line: i32) {
         self.codes.push(OpCode::OpSubtract);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_multily(&mut self, line: i32) {
         self.codes.push(OpCode::OpMultiply);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_divide(&mut self, line: i32) {
         self.codes.push(OpCode::OpDivide);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_false(&mut self, line: i32) {
         self.codes.push(OpCode::OpFalse);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_true(&mut self, line: i32) {
         self.codes.push(OpCode::OpTrue);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_nil(&mut self, line: i32) {
         self.codes.push(OpCode::OpNil);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_not(&mut self, line: i32) {
         self.codes.push(OpCode::OpNot);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_equal(&mut self, line: i32) {
         self.codes.push(OpCode::OpEqual);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_greater(&mut self, line: i32) {
         self.codes.push(OpCode::OpGreater);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_less(&mut self, line: i32) {
         self.codes.push(OpCode::OpLess);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_print(&mut self, line: i32) {
         self.codes.push(OpCode::OpPrint);
-        self.lines.push(line);
+        self.push_line(line);
+    }
+
+    pub fn add_op_echo(&mut self, line: i32) {
+        self.codes.push(OpCode::OpEcho);
+        self.push_line(line);
     }
 
     pub fn add_op_define_global(&mut self, index: usize, line: i32) {
         self.codes.push(OpCode::OpDefineGlobal(index));
-        self.lines.push(line);
+        self.push_line(line);
     }
 
-    pub fn add_value(&mut self, value: Value) -> usize {
+    // The value's slot, None once the pool holds MAX_CONSTANTS
+    pub fn add_value(&mut self, value: Value) -> Option<usize> {
+        // Interned strings are shared, so reuse their existing slot
+        if let Value::String(string) = &value {
+            let index = self.values.iter().position(|v| match v {
+                Value::String(s) => Rc::ptr_eq(s, string),
+                _ => false,
+            });
+            if index.is_some() {
+                return index;
+            }
+        }
+        if self.values.len() >= MAX_CONSTANTS {
+            return None;
+        }
         self.values.push(value);
-        self.values.len() - 1
+        Some(self.values.len() - 1)
+    }
+
+    pub fn is_jump_target(&self, target: usize) -> bool {
+        self.codes
+            .iter()
+            .enumerate()
+            .any(|(index, code)| Chunk::jump_target(index, code) == Some(target))
+    }
+
+    // Where the jump, loop or handler at index leads
+    pub fn jump_target(index: usize, code: &OpCode) -> Option<usize> {
+        match code {
+            OpCode::OpJump(offset)
+            | OpCode::OpJumpIfFalse(offset)
+            | OpCode::OpJumpIfTrue(offset)
+            | OpCode::OpTry(offset) => Some(index + offset),
+            OpCode::OpLoop(offset) => index.checked_sub(*offset),
+            _ => None,
+        }
+    }
+
+    // Remove the instructions marked in deleted, one flag per instruction, keeping their lines and every jump
+    // pointed at the same instruction. A jump to a deleted one lands on what follows it.
+    pub fn delete_codes(&mut self, deleted: &[bool]) {
+        let mut positions = Vec::with_capacity(self.codes.len() + 1);
+        let mut kept = 0;
+        for &is_deleted in deleted.iter().take(self.codes.len()) {
+            positions.push(kept);
+            if !is_deleted {
+                kept += 1;
+            }
+        }
+        positions.push(kept);
+        let mut codes = Vec::with_capacity(kept);
+        let mut lines = vec![];
+        let old_lines = std::mem::take(&mut self.lines);
+        let mut line_runs = old_lines.iter().flat_map(|&(line, count)| std::iter::repeat_n(line, count));
+        for (index, code) in self.codes.iter().enumerate() {
+            let line = line_runs.next().unwrap_or(0);
+            if deleted[index] {
+                continue;
+            }
+            let at = positions[index];
+            let code = match (code, Chunk::jump_target(index, code)) {
+                (OpCode::OpLoop(_), Some(target)) => OpCode::OpLoop(at - positions[target]),
+                (OpCode::OpJump(_), Some(target)) => OpCode::OpJump(positions[target] - at),
+                (OpCode::OpJumpIfFalse(_), Some(target)) => OpCode::OpJumpIfFalse(positions[target] - at),
+                (OpCode::OpJumpIfTrue(_), Some(target)) => OpCode::OpJumpIfTrue(positions[target] - at),
+                (OpCode::OpTry(_), Some(target)) => OpCode::OpTry(positions[target] - at),
+                (code, _) => *code,
+            };
+            codes.push(code);
+            match lines.last_mut() {
+                Some((last, count)) if *last == line => *count += 1,
+                _ => lines.push((line, 1)),
+            }
+        }
+        self.codes = codes;
+        self.lines = lines;
+    }
+
+    // Drop the constant again if it is the newest one and no instruction uses it
+    pub fn release_value(&mut self, index: usize) {
+        let is_used = self.codes.iter().any(|code| match code {
+            OpCode::OpConstant(i)
+            | OpCode::OpConstantLong(i)
+            | OpCode::OpDefineGlobal(i)
+            | OpCode::OpGetGlobal(i)
+            | OpCode::OpSetGlobal(i)
+            | OpCode::OpClass(i)
+            | OpCode::OpMethod(i)
+            | OpCode::OpGetProperty(i)
+            | OpCode::OpSetProperty(i)
+            | OpCode::OpInvoke(i, _) => *i == index,
+            _ => false,
+        });
+        if index + 1 == self.values.len() && !is_used {
+            self.values.pop();
+        }
     }
 
     pub fn add_op_get_global(&mut self, index: usize, line: i32) {
         self.codes.push(OpCode::OpGetGlobal(index));
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_set_global(&mut self, index: usize, line: i32) {
         self.codes.push(OpCode::OpSetGlobal(index));
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_pop(&mut self, line: i32) {
         self.codes.push(OpCode::OpPop);
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_get_local(&mut self, index: usize, line: i32) {
         self.codes.push(OpCode::OpGetLocal(index));
-        self.lines.push(line);
+        self.push_line(line);
     }
 
     pub fn add_op_set_local(&mut self, index: usize, line: i32) {
         self.codes.push(OpCode::OpSetLocal(index));
-        self.lines.push(line);
+        self.push_line(line);
+    }
+
+    pub fn add_op_get_upvalue(&mut self, index: usize, line: i32) {
+        self.codes.push(OpCode::OpGetUpValue(index));
+        self.push_line(line);
+    }
+
+    pub fn add_op_set_upvalue(&mut self, index: usize, line: i32) {
+        self.codes.push(OpCode::OpSetUpValue(index));
+        self.push_line(line);
     }
 
     pub fn add_op_juml_if_false(&mut self, index: usize, line: i32) -> usize {
         self.codes.push(OpCode::OpJumpIfFalse(index));
-        self.lines.push(line);
-        return self.codes.len() - 1;
+        self.push_line(line);
+        self.codes.len() - 1
     }
 
     pub fn add_op_jump(&mut self, index: usize, line: i32) -> usize {
         self.codes.push(OpCode::OpJump(index));
-        self.lines.push(line);
-        return self.codes.len() - 1;
+        self.push_line(line);
+        self.codes.len() - 1
     }
 
     pub fn add_op_loop(&mut self, index: usize, line: i32) -> usize {
         self.codes.push(OpCode::OpLoop(index));
-        self.lines.push(line);
-        return self.codes.len() - 1;
+        self.push_line(line);
+        self.codes.len() - 1
     }
     pub fn add_op_call(&mut self, arg_count: usize, line: i32) {
         self.codes.push(OpCode::OpCall(arg_count));
-        self.lines.push(line);
+        self.push_line(line);
     }
     pub fn add_op_closure(&mut self,line:i32){
         self.codes.push(OpCode::OpClosure);
-        self.lines.push(line);
+        self.push_line(line);
     }
     pub fn add_op_close_value(&mut self,line:i32){
         self.codes.push(OpCode::OpCloseUpvalue);
-        self.lines.push(line);
+        self.push_line(line);
+    }
+    pub fn add_op_class(&mut self, index: usize, line: i32) {
+        self.codes.push(OpCode::OpClass(index));
+        self.push_line(line);
+    }
+    pub fn add_op_method(&mut self, index: usize, line: i32) {
+        self.codes.push(OpCode::OpMethod(index));
+        self.push_line(line);
+    }
+    pub fn add_op_build_list(&mut self, count: usize, line: i32) {
+        self.codes.push(OpCode::OpBuildList(count));
+        self.push_line(line);
+    }
+    pub fn add_op_build_map(&mut self, count: usize, line: i32) {
+        self.codes.push(OpCode::OpBuildMap(count));
+        self.push_line(line);
+    }
+    pub fn add_op_index_get(&mut self, line: i32) {
+        self.codes.push(OpCode::OpIndexGet);
+        self.push_line(line);
+    }
+    pub fn add_op_index_set(&mut self, line: i32) {
+        self.codes.push(OpCode::OpIndexSet);
+        self.push_line(line);
+    }
+    pub fn add_op_get_property(&mut self, index: usize, line: i32) {
+        self.codes.push(OpCode::OpGetProperty(index));
+        self.push_line(line);
+    }
+    pub fn add_op_set_property(&mut self, index: usize, line: i32) {
+        self.codes.push(OpCode::OpSetProperty(index));
+        self.push_line(line);
+    }
+    pub fn add_op_invoke(&mut self, index: usize, arg_count: usize, line: i32) {
+        self.codes.push(OpCode::OpInvoke(index, arg_count));
+        self.push_line(line);
+    }
+    pub fn add_op_throw(&mut self, line: i32) {
+        self.codes.push(OpCode::OpThrow);
+        self.push_line(line);
+    }
+    pub fn add_op_try(&mut self, index: usize, line: i32) -> usize {
+        self.codes.push(OpCode::OpTry(index));
+        self.push_line(line);
+        self.codes.len() - 1
+    }
+    pub fn add_op_pop_handler(&mut self, line: i32) -> usize {
+        self.codes.push(OpCode::OpPopHandler);
+        self.push_line(line);
+        self.codes.len() - 1
     }
 }
@@ -1,13 +1,19 @@
-use core::panic;
 use num::FromPrimitive;
 use num_derive::FromPrimitive;
-use std::{ops::Add, rc::Rc, vec};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ops::Add,
+    rc::Rc,
+    result, vec,
+};
 
 use crate::{
-    chunk::{Chunk, Closure, Function, Value},
-    error,
+    chunk::{hash_str, Accessor, CachedHashMap, Chunk, Function, LoxString, Value, MAX_CONSTANTS},
+    error::{self, Diagnostic, Severity},
     scanner::Scanner,
     token::{Token, TokenType},
+    trace_event, trace_span, util,
 };
 
 use crate::op_code::OpCode;
@@ -16,6 +22,7 @@ use crate::op_code::OpCode;
 pub enum Precedence {
     None,
     Assignment,
+    Ternary,
     Or,
     And,
     Equality,
@@ -45,23 +52,29 @@ impl From<TokenType> for Precedence {
             TokenType::BangEqual | TokenType::EqualEqual => Precedence::Equality,
             TokenType::Greater | TokenType::GreaterEqual => Precedence::Comparison,
             TokenType::Less | TokenType::LessEqual => Precedence::Comparison,
-            TokenType::LeftParen => Precedence::Call,
+            TokenType::LeftParen | TokenType::Dot | TokenType::LeftBracket => Precedence::Call,
+            TokenType::And => Precedence::And,
+            TokenType::Or => Precedence::Or,
+            TokenType::Question => Precedence::Ternary,
             _ => Precedence::None,
         }
     }
 }
 
-#[derive(Debug)]
-pub enum ParseError {
-    TokenError,
-    ConsumeError(String),
-}
+// Small integer standing in for an identifier name, handed out by the Session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Symbol(pub u32);
 
 #[derive(Debug, Clone)]
 pub struct Local {
-    pub name: String,
+    pub symbol: Symbol,
     pub depth: u32,
+    // False between a var's name and the end of its initializer
+    pub is_initialized: bool,
     pub is_captured: bool,
+    // Warning to give if the scope ends before the local is read, None for parameters
+    // and hidden slots and once it has been read
+    pub unused: Option<(Token, &'static str)>,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -70,6 +83,43 @@ pub struct UpValueMeta {
     pub is_local: bool,
 }
 
+#[derive(Debug)]
+pub struct LazyBody {
+    pub name: String,
+    pub params: Vec<String>,
+    pub source: String,
+    pub line: i32,
+    // Where the body starts in the whole source, so diagnostics point into the file
+    pub offset: usize,
+    pub line_start: usize,
+    pub compiled: RefCell<Option<Rc<Function>>>,
+    pub session: Rc<RefCell<Session>>,
+    pub strict: bool,
+}
+
+impl LazyBody {
+    pub fn function(&self) -> result::Result<Rc<Function>, Vec<Diagnostic>> {
+        if let Some(function) = self.compiled.borrow().as_ref() {
+            return Ok(function.clone());
+        }
+        let function = Rc::new(Compiler::compile_lazy(self)?);
+        *self.compiled.borrow_mut() = Some(function.clone());
+        Ok(function)
+    }
+
+    // Spans are positions in the whole file, render them against the body alone
+    pub fn render(&self, diagnostics: &[Diagnostic]) -> String {
+        diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let mut local = diagnostic.clone();
+                local.start -= self.offset;
+                local.render(&self.source, false)
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Builder {
     pub chunk: Chunk,
@@ -77,33 +127,190 @@ pub struct Builder {
     pub locals: Vec<Local>,
     pub parent: Option<Box<Builder>>,
     pub upvalues: Vec<UpValueMeta>,
+    pub arity: usize,
+    // Setters give back the assigned value, so assignments through them still chain
+    pub is_setter: bool,
+    // Constant slot holding each global name already referenced by this chunk, by symbol
+    pub globals: Vec<Option<usize>>,
+    // Set once the constant pool overflowed, so that is reported only once
+    pub constants_full: bool,
+    // Try statements the code being compiled is inside, innermost last
+    pub tries: Vec<PendingTry>,
+    // Instructions removed by constant folding and dead code removal, and calls inlined
+    pub folded: usize,
+    pub dropped: usize,
+    pub inlined: usize,
+}
+
+// A try statement being compiled, which the returns inside it leave through
+#[derive(Debug, Clone, Default)]
+pub struct PendingTry {
+    // First of three hidden locals: the exception or return value the finally clause
+    // is entered with, whether to rethrow it and whether to return it afterwards
+    pub slot: usize,
+    // Only the guard handler is left once the catch clause runs
+    pub in_catch: bool,
+    // Jumps of the returns, patched to the finally clause
+    pub returns: Vec<usize>,
+    // OpPopHandler instructions for the guard, no-ops unless there are both clauses
+    pub unguard: Vec<usize>,
 }
 
 impl Builder {
-    fn new(name: String, parent: Box<Builder>) -> Builder {
+    // Stack slots the frame starts with: the callee and its parameters
+    fn frame_size(&self) -> usize {
+        self.arity + 1
+    }
+
+    fn new(symbol: Symbol, parent: Box<Builder>) -> Builder {
         let mut builder = Builder {
             parent: Some(parent),
             ..Default::default()
         };
         builder.locals.push(Local {
-            name: name,
+            symbol,
             depth: 0,
+            is_initialized: true,
             is_captured: false,
+            unused: None,
         });
         builder
     }
-    fn default(name: String) -> Builder {
+    fn default(symbol: Symbol) -> Builder {
         let mut builder = Builder {
             parent: None,
             ..Default::default()
         };
         builder.locals.push(Local {
-            name: name,
+            symbol,
             depth: 0,
+            is_initialized: true,
             is_captured: false,
+            unused: None,
         });
         builder
     }
+
+    fn take_rewrites(&mut self) -> [(&'static str, usize); 3] {
+        [
+            ("constant-folding", std::mem::take(&mut self.folded)),
+            ("inlining", std::mem::take(&mut self.inlined)),
+            ("dead-code", std::mem::take(&mut self.dropped)),
+        ]
+    }
+
+    // Slot of the innermost local with this name, so inner blocks shadow outer ones
+    fn resolve_local(&self, symbol: Symbol) -> Option<usize> {
+        self.locals.iter().rposition(|local| local.symbol == symbol)
+    }
+
+    // A slot the compiler keeps for itself, out of reach of any name in the source
+    fn add_hidden_local(&mut self, symbol: Symbol) -> usize {
+        self.locals.push(Local {
+            symbol,
+            depth: self.scope_depth,
+            is_initialized: true,
+            is_captured: false,
+            unused: None,
+        });
+        self.locals.len() - 1
+    }
+
+    fn mark_initialized(&mut self) {
+        if let Some(local) = self.locals.last_mut() {
+            local.is_initialized = true;
+        }
+    }
+
+    // Walks the enclosing builders in place, marking the captured local on the way
+    fn resolve_upvalue(&mut self, symbol: Symbol) -> Option<usize> {
+        let parent = self.parent.as_mut()?;
+        if let Some(index) = parent.resolve_local(symbol) {
+            parent.locals[index].is_captured = true;
+            parent.locals[index].unused = None;
+            return Some(self.add_upvalue(index as i32, true));
+        }
+        let index = parent.resolve_upvalue(symbol)?;
+        Some(self.add_upvalue(index as i32, false))
+    }
+
+    fn add_upvalue(&mut self, index: i32, is_local: bool) -> usize {
+        if let Some(i) = self
+            .upvalues
+            .iter()
+            .position(|v| v.is_local == is_local && v.index == index)
+        {
+            return i;
+        }
+        self.upvalues.push(UpValueMeta { is_local, index });
+        self.upvalues.len() - 1
+    }
+}
+
+// State kept alive across compilations, e.g. between REPL lines
+#[derive(Debug, Default)]
+pub struct Session {
+    // Interned strings bucketed by hash, so a lookup hashes the text only once
+    pub strings: CachedHashMap<u64, Vec<Rc<LoxString>>>,
+    pub globals: HashSet<Symbol>,
+    pub symbols: CachedHashMap<Rc<LoxString>, Symbol>,
+    // Interned name of each symbol, indexed by its number
+    pub names: Vec<Rc<LoxString>>,
+}
+
+impl Session {
+    pub fn intern(&mut self, name: &str) -> Rc<LoxString> {
+        let hash = hash_str(name);
+        let bucket = self.strings.entry(hash).or_default();
+        if let Some(string) = bucket.iter().find(|string| string.chars == name) {
+            return string.clone();
+        }
+        let string = Rc::new(LoxString {
+            chars: name.to_owned(),
+            hash,
+        });
+        bucket.push(string.clone());
+        string
+    }
+
+    pub fn symbol(&mut self, name: &str) -> Symbol {
+        let string = self.intern(name);
+        if let Some(symbol) = self.symbols.get(&string) {
+            return *symbol;
+        }
+        let symbol = Symbol(self.names.len() as u32);
+        self.names.push(string.clone());
+        self.symbols.insert(string, symbol);
+        symbol
+    }
+
+    pub fn name(&self, symbol: Symbol) -> Rc<LoxString> {
+        self.names[symbol.0 as usize].clone()
+    }
+}
+
+// Largest function body, in instructions, that calls get inlined
+pub const INLINE_LIMIT: usize = 32;
+
+// Deepest the parser recurses through nested statements and expressions, well short of
+// what overflows a 2 MB thread stack in a debug build
+pub const MAX_NESTING: usize = 200;
+
+fn is_inlinable(function: &Function) -> bool {
+    function.lazy.is_none()
+        && function.upvalues.is_empty()
+        && function.chunk.codes.len() <= INLINE_LIMIT
+        && function.chunk.codes.iter().all(|code| match code {
+            OpCode::OpClosure
+            | OpCode::OpGetUpValue(_)
+            | OpCode::OpSetUpValue(_)
+            | OpCode::OpCloseUpvalue
+            | OpCode::OpTry(_) => false,
+            OpCode::OpGetGlobal(index) => {
+                function.chunk.values[*index].as_str() != Some(function.name.as_str())
+            }
+            _ => true,
+        })
 }
 
 pub struct Compiler {
@@ -111,60 +318,301 @@ pub struct Compiler {
     pub current: Token,
     pub scanner: Scanner,
     pub panic_mode: bool,
-    pub errors: Vec<ParseError>,
+    // Every reported error with its location, errors suppressed in panic mode are left out
+    pub diagnostics: Vec<Diagnostic>,
     pub builder: Box<Builder>,
+    pub lazy: bool,
+    pub session: Rc<RefCell<Session>>,
+    pub inline: bool,
+    pub inline_functions: HashMap<String, Rc<Function>>,
+    // Names assigned somewhere in the source or declared twice at the top level, whose
+    // functions are never inlined
+    pub reassigned: HashSet<String>,
+    // Report references to globals that are never declared at compile time
+    pub strict: bool,
+    // Fold operators on constants into a single constant while compiling
+    pub fold: bool,
+    pub repl: bool,
+    // Class bodies being compiled, `this` is only valid inside one
+    pub class_depth: usize,
+    // Globals the script reads, and the ones it defines with the warning to give if
+    // the other never mentions them
+    pub global_reads: HashSet<Symbol>,
+    pub global_definitions: Vec<(Symbol, Token, &'static str)>,
+    // What the optimizations done while compiling changed in each function, in the
+    // form of the PassManager report
+    pub rewrites: Vec<(String, &'static str, usize)>,
+    // Statements and expressions the parser is inside of
+    pub nesting: usize,
+    // Set once the source nests too deep, the rest of it is skipped without more errors
+    pub gave_up: bool,
 }
 
 impl Compiler {
     pub fn new(source: String) -> Self {
+        Compiler::with_session(source, Rc::new(RefCell::new(Session::default())))
+    }
+
+    pub fn with_session(source: String, session: Rc<RefCell<Session>>) -> Self {
+        let script = session.borrow_mut().symbol("");
         Compiler {
             previous: Token::default(),
             current: Token::default(),
             panic_mode: false,
             scanner: Scanner::new(source),
-            errors: vec![],
-            builder: Box::new(Builder::default("".to_owned())),
+            diagnostics: vec![],
+            builder: Box::new(Builder::default(script)),
+            lazy: false,
+            session,
+            inline: false,
+            inline_functions: HashMap::new(),
+            reassigned: HashSet::new(),
+            strict: false,
+            fold: true,
+            repl: false,
+            class_depth: 0,
+            global_reads: HashSet::new(),
+            global_definitions: vec![],
+            rewrites: vec![],
+            nesting: 0,
+            gave_up: false,
         }
     }
 
-    pub fn compile(&mut self) -> Closure {
+    pub fn intern(&self, name: &str) -> Rc<LoxString> {
+        self.session.borrow_mut().intern(name)
+    }
+
+    pub fn symbol(&self, name: &str) -> Symbol {
+        self.session.borrow_mut().symbol(name)
+    }
+
+    // Constant slot for a global's name, added to the chunk on first use
+    pub fn global_constant(&mut self, symbol: Symbol) -> usize {
+        let slot = symbol.0 as usize;
+        if let Some(Some(index)) = self.builder.globals.get(slot) {
+            return *index;
+        }
+        let name = self.session.borrow().name(symbol);
+        let index = match self.builder.chunk.add_value(Value::String(name)) {
+            Some(index) => index,
+            None => {
+                self.constants_full();
+                return 0;
+            }
+        };
+        if self.builder.globals.len() <= slot {
+            self.builder.globals.resize(slot + 1, None);
+        }
+        self.builder.globals[slot] = Some(index);
+        index
+    }
+
+    pub fn compile_lazy(body: &LazyBody) -> result::Result<Function, Vec<Diagnostic>> {
+        trace_span!("compile_lazy", function = body.name.as_str());
+        let mut compiler = Compiler::with_session(body.source.clone(), body.session.clone());
+        compiler.scanner.line = body.line;
+        compiler.scanner.offset = body.offset;
+        compiler.scanner.line_start = body.line_start;
+        compiler.strict = body.strict;
+        let symbol = compiler.symbol(&body.name);
+        let parent = std::mem::take(&mut compiler.builder);
+        *compiler.builder = Builder::new(symbol, parent);
+
+        compiler.builder.arity = body.params.len();
+        compiler.enter_scope();
+        for param in body.params.iter() {
+            compiler.define_local_variable(Token::new(TokenType::Identifier, param, body.line));
+        }
+        compiler.advance();
+        let function = compiler.finish_function(body.name.clone(), body.params.len());
+
+        compiler.result(function)
+    }
+
+    // Everything reported so far if any of it is an error
+    fn result(&mut self, function: Function) -> result::Result<Function, Vec<Diagnostic>> {
+        let failed = self
+            .diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error);
+        if failed {
+            // Names left unread are often a knock-on effect of the errors
+            let diagnostics = std::mem::take(&mut self.diagnostics);
+            Err(diagnostics
+                .into_iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .collect())
+        } else {
+            Ok(function)
+        }
+    }
+
+    // Names defined outside the script, e.g. natives, for strict mode
+    pub fn declare_global(&mut self, name: &str) {
+        let symbol = self.symbol(name);
+        self.session.borrow_mut().globals.insert(symbol);
+    }
+
+    // Pre-pass recording every top-level declaration, so uses may come before them
+    pub fn collect_globals(&mut self) {
+        let mut scanner = Scanner::new(self.scanner.source.clone());
+        let mut depth = 0;
+        let mut previous = TokenType::Eof;
+        loop {
+            let token = scanner.scan();
+            match token.token_type {
+                TokenType::Eof => break,
+                TokenType::LeftBrace | TokenType::LeftParen => depth += 1,
+                TokenType::RightBrace | TokenType::RightParen => depth -= 1,
+                TokenType::Identifier
+                    if depth == 0
+                        && matches!(
+                            previous,
+                            TokenType::Var | TokenType::Fun | TokenType::Class
+                        ) =>
+                {
+                    self.declare_global(&token.lexeme)
+                }
+                _ => {}
+            }
+            previous = token.token_type;
+        }
+    }
+
+    pub fn collect_reassigned(&mut self) {
+        let mut scanner = Scanner::new(self.scanner.source.clone());
+        let mut declared = HashSet::new();
+        let mut depth = 0;
+        let mut previous = TokenType::Eof;
+        let mut name = None;
+        loop {
+            let token = scanner.scan();
+            match token.token_type {
+                TokenType::Eof => break,
+                TokenType::LeftBrace | TokenType::LeftParen => depth += 1,
+                TokenType::RightBrace | TokenType::RightParen => depth -= 1,
+                TokenType::Identifier
+                    if depth == 0
+                        && matches!(
+                            previous,
+                            TokenType::Var | TokenType::Fun | TokenType::Class
+                        )
+                        && !declared.insert(token.lexeme.clone()) =>
+                {
+                    self.reassigned.insert(token.lexeme.to_string());
+                }
+                // Any name = value, locals included, as a property set follows a '.'
+                TokenType::Equal => {
+                    if let Some(name) = name.take() {
+                        self.reassigned.insert(name);
+                    }
+                }
+                _ => {}
+            }
+            name = match token.token_type {
+                TokenType::Identifier if previous != TokenType::Dot => Some(token.lexeme.to_string()),
+                _ => None,
+            };
+            previous = token.token_type;
+        }
+    }
+
+    // Errors are collected rather than printed, callers decide how to show them
+    pub fn compile(&mut self) -> result::Result<Function, Vec<Diagnostic>> {
+        trace_span!("compile", strict = self.strict, lazy = self.lazy);
+        if self.strict {
+            trace_span!("collect_globals");
+            self.collect_globals();
+        }
+        if self.inline {
+            self.collect_reassigned();
+        }
         self.advance();
         while !self.match_token(TokenType::Eof) {
             self.parse_declaration();
         }
         self.consume(TokenType::Eof, error::EXPECT_EOF);
-        Closure::new(Rc::new(Function::new(
+        // A global may be there for other scripts or the host, so only strict mode asks
+        // for every one to be read. Later REPL lines and lazily compiled bodies still might.
+        if self.strict && !self.repl && !self.lazy {
+            self.warn_unused_globals();
+        }
+        trace_event!(debug, errors = self.diagnostics.len(), "compiled");
+        let function = Function::new(
             0,
-            self.builder.chunk.clone(),
+            std::mem::take(&mut self.builder.chunk),
             "".to_owned(),
             vec![],
-        )))
+        );
+        let rewrites = self.builder.take_rewrites();
+        self.record_rewrites(&function, rewrites);
+        self.result(function)
+    }
+
+    fn record_rewrites(&mut self, function: &Function, rewrites: [(&'static str, usize); 3]) {
+        for (name, changed) in rewrites {
+            if changed > 0 {
+                self.rewrites.push((function.to_string(), name, changed));
+            }
+        }
+    }
+
+    fn warn_unused_globals(&mut self) {
+        for (symbol, token, message) in std::mem::take(&mut self.global_definitions) {
+            if !self.global_reads.contains(&symbol) {
+                self.warn_unused(&token, message);
+            }
+        }
+    }
+
+    // Names starting with an underscore are unused on purpose
+    fn warn_unused(&mut self, token: &Token, message: &str) {
+        if !token.lexeme.starts_with('_') {
+            let message = format!("{} '{}'", message, token.lexeme);
+            self.diagnostics
+                .push(Diagnostic::new(Severity::Warning, token, &message));
+        }
+    }
+
+    // Remember a var or fun declaration, to warn about it if it is never read
+    fn track_unused(&mut self, token: Token, message: &'static str) {
+        let symbol = self.symbol(&token.lexeme);
+        if self.builder.scope_depth == 0 {
+            self.global_definitions.push((symbol, token, message));
+        } else if let Some(local) = self.builder.locals.last_mut() {
+            if local.symbol == symbol {
+                local.unused = Some((token, message));
+            }
+        }
     }
 
     pub fn advance(&mut self) {
-        self.previous = self.current.clone();
+        self.previous = std::mem::take(&mut self.current);
         loop {
             self.current = self.scanner.scan();
             if self.current.token_type != TokenType::Error {
                 break;
             }
             self.show_error(self.current.clone(), "Invalid Token");
-            self.errors.push(ParseError::TokenError);
         }
     }
 
     pub fn show_error(&mut self, token: Token, message: &str) {
-        if self.panic_mode {
+        if self.panic_mode || self.gave_up {
             return;
         }
         self.panic_mode = true;
-        print!("[lint {}] Error: ", token.line);
+        // A scanner error token carries its own message
+        let diagnostic = match token.token_type {
+            TokenType::Error => Diagnostic::error(&token, &token.lexeme),
+            _ => Diagnostic::error(&token, message),
+        };
+        self.diagnostics.push(diagnostic);
+    }
 
-        match token.token_type {
-            TokenType::Eof => print!("At end "),
-            _ => print!("{} ", token.lexeme),
-        }
-        println!("{}", message);
+    pub fn error(&mut self, token: Token, message: &str) {
+        self.show_error(token, message);
     }
 
     pub fn consume(&mut self, token_type: TokenType, message: &str) {
@@ -173,16 +621,32 @@ impl Compiler {
             return;
         }
         self.show_error(self.current.clone(), message);
-        self.errors
-            .push(ParseError::ConsumeError(message.to_owned()))
+    }
+
+    // Every literal and function takes a slot of its own, up to MAX_CONSTANTS
+    fn emit_constant(&mut self, value: Value, line: i32) {
+        if !self.builder.chunk.add_op_constant(value, line) {
+            self.constants_full();
+            // Keeps the stack shape for whatever is compiled after it
+            self.builder.chunk.add_op_nil(line);
+        }
+    }
+
+    fn constants_full(&mut self) {
+        if !self.builder.constants_full {
+            self.builder.constants_full = true;
+            self.error(self.previous.clone(), error::TOO_MANY_CONSTANTS);
+        }
     }
 
     pub fn parse_number(&mut self) {
-        let v: f64 = self.previous.lexeme.parse().unwrap_or(0.0);
-        let value = Value::Double(v);
-        self.builder
-            .chunk
-            .add_op_constant(value, self.previous.line);
+        let lexeme = &self.previous.lexeme;
+        // Whole numbers too large for an Int fall back to a double
+        let value = match util::parse_int(lexeme) {
+            Some(v) => Value::Int(v),
+            None => Value::Double(util::parse_double(lexeme)),
+        };
+        self.emit_constant(value, self.previous.line);
     }
 
     pub fn parse_group(&mut self) {
@@ -195,6 +659,7 @@ impl Compiler {
 
     pub fn parse_unary(&mut self) {
         let token: Token = self.previous.clone();
+        let start = self.builder.chunk.codes.len();
         self.parse_precedence(Precedence::Unary);
 
         match token.token_type {
@@ -206,13 +671,16 @@ impl Compiler {
             }
             _ => {}
         }
+        self.fold_operator(start);
     }
 
     pub fn parse_binary(&mut self) {
         let token: Token = self.previous.clone();
+        let left_end = self.builder.chunk.codes.len();
 
+        // One level up keeps binary operators left associative, 1 - 2 - 3 is (1 - 2) - 3
         let precedence: Precedence = token.token_type.into();
-        self.parse_precedence(precedence);
+        self.parse_precedence(precedence + 1);
         match token.token_type {
             TokenType::Plus => self.builder.chunk.add_op_add(token.line),
             TokenType::Minus => self.builder.chunk.add_op_subtract(token.line),
@@ -241,6 +709,10 @@ impl Compiler {
             }
             _ => {}
         }
+        // Operands are folded as they are parsed, so a constant left side is one instruction
+        if left_end > 0 {
+            self.fold_operator(left_end - 1);
+        }
     }
 
     pub fn parse_literal(&mut self) {
@@ -257,29 +729,95 @@ impl Compiler {
         self.parse_precedence(Precedence::Assignment);
     }
 
+    // Replace an operator and its constant operands, emitted from start on, by the
+    // result: 1 + 2 * 3 becomes 7 and "a" + "b" becomes "ab"
+    pub fn fold_operator(&mut self, start: usize) {
+        let chunk = &mut self.builder.chunk;
+        if !self.fold {
+            return;
+        }
+        // Looking for jumps scans the whole chunk, so only once the operands are constant
+        let value = match chunk.evaluate_constant(start) {
+            Some(value) => value,
+            None => return,
+        };
+        if (start + 1..chunk.codes.len()).any(|index| chunk.is_jump_target(index)) {
+            return;
+        }
+        let mut operands: Vec<usize> = chunk.codes[start..]
+            .iter()
+            .filter_map(|code| match code {
+                OpCode::OpConstant(index) | OpCode::OpConstantLong(index) => Some(*index),
+                _ => None,
+            })
+            .collect();
+        let line = chunk.line_at(start).unwrap_or(0);
+        self.builder.folded += chunk.codes.len() - start;
+        chunk.codes.truncate(start);
+        chunk.truncate_lines(start);
+        operands.sort_unstable();
+        for index in operands.into_iter().rev() {
+            chunk.release_value(index);
+        }
+        let value = match value {
+            Value::String(string) => Value::String(self.intern(&string)),
+            value => value,
+        };
+        self.emit_constant(value, line);
+    }
+
     pub fn parse_string(&mut self) {
-        let token = self.previous.clone();
-        self.builder
-            .chunk
-            .add_op_constant(Value::String(Rc::new(token.lexeme)), token.line);
+        let mut token = self.previous.clone();
+        // Adjacent literals are one string, "Hello, " "world"
+        if self.check(TokenType::String) {
+            let mut chars = token.lexeme.to_string();
+            while self.match_token(TokenType::String) {
+                chars.push_str(&self.previous.lexeme);
+            }
+            token.lexeme = chars.into();
+        }
+        let string = self.intern(&token.lexeme);
+        self.emit_constant(Value::String(string), token.line);
+    }
+
+    // Count one more level of nesting, or skip to the end of the source when that is too
+    // many so the callers all unwind
+    fn nest(&mut self) -> bool {
+        if self.nesting >= MAX_NESTING {
+            self.error(self.current.clone(), error::TOO_MUCH_NESTING);
+            self.gave_up = true;
+            while !self.check(TokenType::Eof) {
+                self.advance();
+            }
+            return false;
+        }
+        self.nesting += 1;
+        true
     }
 
     pub fn parse_precedence(&mut self, precedence: Precedence) {
+        if !self.nest() {
+            return;
+        }
         self.advance();
 
         self.parse_prefix(precedence);
 
         while precedence <= Precedence::from(self.current.token_type) {
             self.advance();
-            self.parse_infix();
+            self.parse_infix(precedence);
         }
 
         if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
-            self.show_error(self.previous.clone(), error::INVALID_ASSIGNMENT_TARGET);
+            self.error(self.previous.clone(), error::INVALID_ASSIGNMENT_TARGET);
         }
+        self.nesting -= 1;
     }
 
     pub fn parse_statement(&mut self) {
+        if !self.nest() {
+            return;
+        }
         match self.current.token_type {
             TokenType::Print => {
                 self.advance();
@@ -304,21 +842,192 @@ impl Compiler {
                 self.parse_for_statement();
             }
             TokenType::Return => {
+                self.advance();
                 self.parse_return_statement();
             }
+            TokenType::Throw => {
+                self.advance();
+                self.parse_throw_statement();
+            }
+            TokenType::Try => {
+                self.advance();
+                self.parse_try_statement();
+            }
             _ => self.parse_expression_statement(),
         }
+        self.nesting -= 1;
     }
 
     pub fn parse_return_statement(&mut self) {
         if self.match_token(TokenType::SemiColon) {
-            self.builder.chunk.add_op_nil(self.previous.line);
-            self.builder.chunk.add_op_return(self.previous.line);
+            self.add_default_return(self.previous.line);
         } else {
+            if self.builder.is_setter {
+                self.error(self.previous.clone(), error::SETTER_RETURN_VALUE);
+            }
             self.parse_expression();
             self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_RETURN);
-            self.builder.chunk.add_op_return(self.previous.line);
+            self.emit_return(self.previous.line);
+        }
+    }
+
+    // Return the value on top of the stack. Inside a try statement that means storing it,
+    // leaving the try's scopes and handlers and jumping to where its finally clause
+    // runs, which then returns the value in turn.
+    fn emit_return(&mut self, line: i32) {
+        let (slot, in_catch) = match self.builder.tries.last() {
+            Some(pending) => (pending.slot, pending.in_catch),
+            None => {
+                self.builder.chunk.add_op_return(line);
+                return;
+            }
+        };
+        let chunk = &mut self.builder.chunk;
+        chunk.add_op_set_local(slot, line);
+        chunk.add_op_pop(line);
+        for local in self.builder.locals[slot + 3..].iter().rev() {
+            if local.is_captured {
+                chunk.add_op_close_value(line);
+            } else {
+                chunk.add_op_pop(line);
+            }
+        }
+        chunk.add_op_true(line);
+        chunk.add_op_set_local(slot + 2, line);
+        chunk.add_op_pop(line);
+        if !in_catch {
+            chunk.add_op_pop_handler(line);
+        }
+        let guard = chunk.add_op_pop_handler(line);
+        let jump = chunk.add_op_jump(0, line);
+        let pending = self.builder.tries.last_mut().unwrap();
+        pending.unguard.push(guard);
+        pending.returns.push(jump);
+    }
+
+    pub fn parse_throw_statement(&mut self) {
+        self.parse_expression();
+        self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_THROW);
+        self.builder.chunk.add_op_throw(self.previous.line);
+    }
+
+    // The catch body runs under a guard handler pushed before the try block's own, so a
+    // finally clause still runs when it throws. A finally clause is entered with three
+    // hidden locals, set aside before the try block: the pending exception or return
+    // value and flags saying whether to rethrow or return it once the clause is done.
+    pub fn parse_try_statement(&mut self) {
+        let line = self.previous.line;
+        self.enter_scope();
+        self.builder.chunk.add_op_nil(line);
+        self.builder.chunk.add_op_false(line);
+        self.builder.chunk.add_op_false(line);
+        let slot = self.builder.add_hidden_local(self.symbol(""));
+        self.builder.add_hidden_local(self.symbol(""));
+        self.builder.add_hidden_local(self.symbol(""));
+        let guard_index = self.builder.chunk.add_op_try(0, line);
+        let try_index = self.builder.chunk.add_op_try(0, line);
+        self.builder.tries.push(PendingTry {
+            slot,
+            // The guard is only wanted with both clauses, otherwise its instructions
+            // become jumps to the next one
+            unguard: vec![guard_index],
+            ..Default::default()
+        });
+        self.consume(TokenType::LeftBrace, error::EXPECT_LEFT_BRACE_AFTER_TRY);
+        self.parse_scoped_block();
+        self.builder.chunk.add_op_pop_handler(self.previous.line);
+
+        let mut rethrow_index = try_index;
+        if self.match_token(TokenType::Catch) {
+            let guard = self.builder.chunk.add_op_pop_handler(self.previous.line);
+            let skip_index = self.builder.chunk.add_op_jump(0, self.previous.line);
+            self.patch_op(try_index);
+            rethrow_index = guard_index;
+            if let Some(pending) = self.builder.tries.last_mut() {
+                pending.unguard.push(guard);
+                pending.in_catch = true;
+            }
+
+            self.enter_scope();
+            self.consume(TokenType::LeftParen, error::EXPECT_LEFT_PAREN_AFTER_CATCH);
+            self.consume(TokenType::Identifier, error::EXPECT_CATCH_VARIABLE);
+            // The handler left the exception where this local's slot is
+            self.define_local_variable(self.previous.clone());
+            self.consume(TokenType::RightParen, error::EXPECT_RIGHT_PAREN_AFTER_CATCH);
+            self.consume(TokenType::LeftBrace, error::EXPECT_LEFT_BRACE_AFTER_CATCH);
+            self.parse_scoped_block();
+            self.exit_scope();
+            let guard = self.builder.chunk.add_op_pop_handler(self.previous.line);
+            self.builder.tries.last_mut().unwrap().unguard.push(guard);
+            self.patch_op(skip_index);
+        } else if !self.check(TokenType::Finally) {
+            self.error(self.current.clone(), error::EXPECT_CATCH_OR_FINALLY);
+        }
+
+        let pending = self.builder.tries.pop().unwrap();
+        let has_finally = self.match_token(TokenType::Finally);
+        if !has_finally || rethrow_index != guard_index {
+            for &index in pending.unguard.iter() {
+                self.builder.chunk.codes[index] = OpCode::OpJump(1);
+            }
+        }
+        let line = self.previous.line;
+        if !has_finally {
+            // Nothing to run on the way out, so the returns go straight on
+            if !pending.returns.is_empty() {
+                let skip_index = self.builder.chunk.add_op_jump(0, line);
+                for &index in pending.returns.iter() {
+                    self.patch_op(index);
+                }
+                self.builder.chunk.add_op_get_local(slot, line);
+                self.emit_return(line);
+                self.patch_op(skip_index);
+            }
+            self.exit_scope();
+            return;
         }
+        let finally_index = self.builder.chunk.add_op_jump(0, line);
+        self.patch_op(rethrow_index);
+        let chunk = &mut self.builder.chunk;
+        chunk.add_op_set_local(slot, line);
+        chunk.add_op_pop(line);
+        chunk.add_op_true(line);
+        chunk.add_op_set_local(slot + 1, line);
+        chunk.add_op_pop(line);
+        self.patch_op(finally_index);
+        for &index in pending.returns.iter() {
+            self.patch_op(index);
+        }
+
+        self.consume(TokenType::LeftBrace, error::EXPECT_LEFT_BRACE_AFTER_FINALLY);
+        self.parse_scoped_block();
+        let line = self.previous.line;
+        let chunk = &mut self.builder.chunk;
+        chunk.add_op_get_local(slot + 1, line);
+        let done_index = chunk.add_op_juml_if_false(0, line);
+        chunk.add_op_pop(line);
+        chunk.add_op_get_local(slot, line);
+        chunk.add_op_throw(line);
+        self.patch_op(done_index);
+        self.builder.chunk.add_op_pop(line);
+        if !pending.returns.is_empty() {
+            let chunk = &mut self.builder.chunk;
+            chunk.add_op_get_local(slot + 2, line);
+            let done_index = chunk.add_op_juml_if_false(0, line);
+            chunk.add_op_pop(line);
+            chunk.add_op_get_local(slot, line);
+            self.emit_return(line);
+            self.patch_op(done_index);
+            self.builder.chunk.add_op_pop(line);
+        }
+        self.exit_scope();
+    }
+
+    // A `{ }` block after its opening brace, in a scope of its own
+    fn parse_scoped_block(&mut self) {
+        self.enter_scope();
+        self.parse_block_statement();
+        self.exit_scope();
     }
 
     pub fn parse_for_statement(&mut self) {
@@ -331,37 +1040,42 @@ impl Compiler {
             self.parse_expression_statement();
         }
 
-        let mut exit_index: i32 = -1;
-        let condition_index = self.builder.chunk.codes.len();
+        let mut loop_index = self.builder.chunk.codes.len();
+        let mut exit_index = None;
         if !self.match_token(TokenType::SemiColon) {
             self.parse_expression();
             self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_LOOP);
-
-            exit_index = self
-                .builder
-                .chunk
-                .add_op_juml_if_false(0, self.previous.line) as i32;
+            exit_index = Some(self.builder.chunk.add_op_juml_if_false(0, self.previous.line));
             self.builder.chunk.add_op_pop(self.previous.line);
         }
 
-        let incre_index = self.builder.chunk.codes.len();
-
+        // The increment comes first in the code but runs after the body, which jumps
+        // back to it and it on to the condition
         if !self.match_token(TokenType::RightParen) {
             let body_index = self.builder.chunk.add_op_jump(0, self.previous.line);
+            let increment_index = self.builder.chunk.codes.len();
             self.parse_expression();
             self.builder.chunk.add_op_pop(self.previous.line);
-            self.builder
-                .chunk
-                .add_op_loop(condition_index, self.previous.line);
+            self.consume(
+                TokenType::RightParen,
+                error::EXPECT_RIGHT_PAREN_AFTER_CONDITION,
+            );
+            self.builder.chunk.add_op_loop(
+                self.builder.chunk.codes.len() - loop_index,
+                self.previous.line,
+            );
+            loop_index = increment_index;
             self.patch_op(body_index);
         }
 
-        self.builder
-            .chunk
-            .add_op_loop(incre_index, self.previous.line);
+        self.parse_statement();
+        self.builder.chunk.add_op_loop(
+            self.builder.chunk.codes.len() - loop_index,
+            self.previous.line,
+        );
 
-        if exit_index != -1 {
-            self.patch_op(exit_index as usize);
+        if let Some(exit_index) = exit_index {
+            self.patch_op(exit_index);
             self.builder.chunk.add_op_pop(self.previous.line);
         }
         self.exit_scope();
@@ -425,19 +1139,40 @@ impl Compiler {
             OpCode::OpJumpIfFalse(ref mut offset) => {
                 *offset = code_len - index;
             }
-            OpCode::OpJump(ref mut offset) => {
+            OpCode::OpJump(ref mut offset) | OpCode::OpTry(ref mut offset) => {
                 *offset = code_len - index;
             }
             _ => {
-                panic!("Path not jump")
+                self.error(self.previous.clone(), error::PATCH_NOT_JUMP);
             }
         }
     }
 
+    // Statements after a return or throw are still checked, but their code is dropped
     pub fn parse_block_statement(&mut self) {
+        let mut left = false;
+        let mut dead_start = None;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if left && dead_start.is_none() {
+                dead_start = Some(self.builder.chunk.codes.len());
+                self.diagnostics.push(Diagnostic::new(
+                    Severity::Warning,
+                    &self.current,
+                    error::UNREACHABLE_CODE,
+                ));
+            }
+            left |= matches!(self.current.token_type, TokenType::Return | TokenType::Throw);
             self.parse_declaration();
         }
+        if let Some(start) = dead_start {
+            self.builder.dropped += self.builder.chunk.codes.len() - start;
+            self.builder.chunk.codes.truncate(start);
+            self.builder.chunk.truncate_lines(start);
+            for pending in self.builder.tries.iter_mut() {
+                pending.returns.retain(|&index| index < start);
+                pending.unguard.retain(|&index| index < start);
+            }
+        }
         self.consume(TokenType::RightBrace, error::EXPECT_RIGHT_BRACE_AFTER_BLOCK);
     }
     pub fn enter_scope(&mut self) {
@@ -451,16 +1186,31 @@ impl Compiler {
             } else{
                 self.builder.chunk.add_op_pop(self.previous.line);
             }
-            self.builder.locals.remove(self.builder.locals.len()-1);
+            let local = self.builder.locals.remove(self.builder.locals.len()-1);
+            if let Some((token, message)) = local.unused {
+                self.warn_unused(&token, message);
+            }
         }
     }
 
     pub fn parse_expression_statement(&mut self) {
         self.parse_expression();
+        // The REPL echoes bare top-level expressions, where the last ';' is optional
+        if self.repl && self.builder.parent.is_none() && self.builder.scope_depth == 0 {
+            if !self.check(TokenType::Eof) {
+                self.consume(
+                    TokenType::SemiColon,
+                    error::EXPECT_SEMICOLON_AFTER_EXPRESSION,
+                );
+            }
+            self.builder.chunk.add_op_echo(self.previous.line);
+            return;
+        }
         self.consume(
             TokenType::SemiColon,
             error::EXPECT_SEMICOLON_AFTER_EXPRESSION,
         );
+        self.builder.chunk.add_op_pop(self.previous.line);
     }
 
     pub fn parse_print_statement(&mut self) {
@@ -475,6 +1225,11 @@ impl Compiler {
         self.consume(TokenType::Identifier, error::EXPECT_VARIABLE_NAME);
 
         let token = self.previous.clone();
+        // A local is in scope from its name on, but can't be read until it has a value
+        let is_local = self.builder.scope_depth != 0;
+        if is_local {
+            self.declare_local_variable(token.clone());
+        }
 
         if self.match_token(TokenType::Equal) {
             self.parse_expression();
@@ -487,29 +1242,47 @@ impl Compiler {
             error::EXPECT_SEMICOLON_AFTER_VARIABLE_DECLARATION,
         );
 
-        self.define_variable(token);
+        if is_local {
+            self.builder.mark_initialized();
+        } else {
+            self.define_global_variable(token.clone());
+        }
+        self.track_unused(token, error::UNUSED_VARIABLE);
     }
 
     pub fn define_local_variable(&mut self, token: Token) {
-        match self.resolve_local(token.lexeme.as_str()) {
-            Some(_) => {
-                self.show_error(token, error::ALREADY_VARIABLE_DELCARE);
-                return;
-            }
-            None => {}
+        self.declare_local_variable(token);
+        self.builder.mark_initialized();
+    }
+
+    pub fn declare_local_variable(&mut self, token: Token) {
+        let symbol = self.symbol(&token.lexeme);
+        // Only the innermost scope is checked, inner blocks may shadow outer locals
+        let depth = self.builder.scope_depth;
+        let is_declared = self
+            .builder
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth >= depth)
+            .any(|local| local.symbol == symbol);
+        if is_declared {
+            self.error(token, error::ALREADY_VARIABLE_DELCARE);
+            return;
         };
         self.builder.locals.push(Local {
-            name: token.lexeme,
+            symbol,
             depth: self.builder.scope_depth,
+            is_initialized: false,
             is_captured: false,
+            unused: None,
         })
     }
 
     pub fn define_global_variable(&mut self, token: Token) {
-        let index = self
-            .builder
-            .chunk
-            .add_value(Value::String(Rc::new(token.lexeme)));
+        let symbol = self.symbol(&token.lexeme);
+        self.session.borrow_mut().globals.insert(symbol);
+        let index = self.global_constant(symbol);
         self.builder.chunk.add_op_define_global(index, token.line);
     }
 
@@ -522,26 +1295,41 @@ impl Compiler {
     }
 
     pub fn resolve_local(&mut self, name: &str) -> Option<usize> {
-        self.builder
-            .locals
-            .iter()
-            .rev()
-            .position(|local| if local.name == name { true } else { false })
+        let symbol = self.symbol(name);
+        self.builder.resolve_local(symbol)
     }
 
     pub fn parse_variable(&mut self, precedence: Precedence) {
         let token = self.previous.clone();
+        let symbol = self.symbol(&token.lexeme);
         let index = self
-            .resolve_local(token.lexeme.as_str())
+            .builder
+            .resolve_local(symbol)
             .map(|v| v as i32)
             .unwrap_or(-1);
+        if index != -1 && !self.builder.locals[index as usize].is_initialized {
+            self.error(token.clone(), error::READ_LOCAL_IN_INITIALIZER);
+        }
+
+        // A local of an enclosing function, reached through the closure
+        if index == -1 {
+            if let Some(upvalue) = self.builder.resolve_upvalue(symbol) {
+                if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
+                    self.parse_expression();
+                    self.builder.chunk.add_op_set_upvalue(upvalue, token.line);
+                } else {
+                    self.builder.chunk.add_op_get_upvalue(upvalue, token.line);
+                }
+                return;
+            }
+        }
 
         // ? Handle global
         if index == -1 {
-            let global_index = self
-                .builder
-                .chunk
-                .add_value(Value::String(Rc::new(token.lexeme)));
+            if self.strict && !self.session.borrow().globals.contains(&symbol) {
+                self.error(token.clone(), error::UNDEFINED_VARIABLE);
+            }
+            let global_index = self.global_constant(symbol);
             if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
                 self.parse_expression();
                 self.builder
@@ -549,6 +1337,7 @@ impl Compiler {
                     .add_op_set_global(global_index, token.line);
                 return;
             }
+            self.global_reads.insert(symbol);
             self.builder
                 .chunk
                 .add_op_get_global(global_index, token.line);
@@ -560,6 +1349,7 @@ impl Compiler {
                     .add_op_set_local(index as usize, token.line);
                 return;
             }
+            self.builder.locals[index as usize].unused = None;
             self.builder
                 .chunk
                 .add_op_get_local(index as usize, token.line);
@@ -567,49 +1357,15 @@ impl Compiler {
     }
 
     pub fn resolve_upvalue(&mut self, name: &str) -> i32 {
-        if let None = self.builder.parent {
-            return -1;
-        }
-        let origin_builder = self.builder.clone();
-        self.builder = origin_builder.parent.as_ref().unwrap().clone();
-        let local_index = self.resolve_local(name).map(|v| v as i32).unwrap_or(-1);
-        self.builder = origin_builder.clone();
-
-        if local_index != -1 {
-            self.builder.parent.as_mut().unwrap().locals[local_index as usize].is_captured=true;
-            return self.add_upvalue(local_index, true);
-        }
-
-        let origin_builder = self.builder.clone();
-        self.builder = origin_builder.parent.as_ref().unwrap().clone();
-        let upvalue_index = self.resolve_upvalue(name);
-        self.builder = origin_builder.clone();
-
-        if upvalue_index != -1 {
-            return self.add_upvalue(upvalue_index, false);
-        }
-
-        return -1;
+        let symbol = self.symbol(name);
+        self.builder
+            .resolve_upvalue(symbol)
+            .map(|v| v as i32)
+            .unwrap_or(-1)
     }
 
     pub fn add_upvalue(&mut self, index: i32, is_local: bool) -> i32 {
-        let upvalue_count = self.builder.upvalues.len();
-
-        let upvalue_index = self
-            .builder
-            .upvalues
-            .iter()
-            .position(|v| v.is_local == is_local && v.index == index);
-
-        if let Some(i) = upvalue_index {
-            return i as i32;
-        }
-
-        self.builder.upvalues.push(UpValueMeta {
-            is_local: is_local,
-            index: index,
-        });
-        return upvalue_count as i32;
+        self.builder.add_upvalue(index, is_local) as i32
     }
 
     pub fn parse_func_declaration(&mut self) {
@@ -619,11 +1375,53 @@ impl Compiler {
             self.define_variable(token.clone());
         }
 
-        let origin_builder = self.builder.clone();
-        self.builder = Box::new(Builder::new(token.lexeme.clone(), origin_builder));
+        // Top-level functions can only see globals, so their bodies can be compiled later
+        let function = if self.lazy && self.builder.parent.is_none() && self.builder.scope_depth == 0
+        {
+            self.parse_lazy_function(token.lexeme.to_string())
+        } else {
+            self.parse_function(token.lexeme.to_string())
+        };
+
+        let function = Rc::new(function);
+        // Inlining assumes top-level functions are never reassigned
+        if self.inline
+            && self.builder.scope_depth == 0
+            && is_inlinable(&function)
+            && !self.reassigned.contains(&*token.lexeme)
+        {
+            self.inline_functions
+                .insert(token.lexeme.to_string(), function.clone());
+        }
 
+        self.emit_constant(Value::Function(function), self.previous.line);
+        self.builder.chunk.add_op_closure(self.previous.line);
+        if self.builder.scope_depth == 0 {
+            self.define_global_variable(token.clone());
+        }
+        self.track_unused(token, error::UNUSED_FUNCTION);
+    }
+
+    pub fn parse_function(&mut self, name: String) -> Function {
+        let symbol = self.symbol(&name);
+        self.parse_function_with_slot(name, symbol)
+    }
+
+    // Slot 0 holds the callee, named after the function or `this` for methods
+    pub fn parse_function_with_slot(&mut self, name: String, symbol: Symbol) -> Function {
+        self.begin_function(symbol);
+        self.parse_function_body(name)
+    }
+
+    // Make a fresh builder for a nested function current
+    pub fn begin_function(&mut self, symbol: Symbol) {
+        let parent = std::mem::take(&mut self.builder);
+        *self.builder = Builder::new(symbol, parent);
         self.enter_scope();
+    }
 
+    // Parameters and block of the function begun last
+    pub fn parse_function_body(&mut self, name: String) -> Function {
         self.consume(
             TokenType::LeftParen,
             error::EXPECT_LEFT_PAREN_AFTER_FUNCTION,
@@ -644,36 +1442,300 @@ impl Compiler {
             TokenType::RightParen,
             error::EXPECT_RIGHT_PAREN_AFTER_PARAMETERS,
         );
+        self.builder.arity = arity;
 
         self.consume(
             TokenType::LeftBrace,
             error::EXPECT_LEFT_BRACE_BEFORE_FUNCTION_BODY,
         );
+        self.finish_function(name, arity)
+    }
+
+    pub fn finish_function(&mut self, name: String, arity: usize) -> Function {
         self.parse_block_statement();
 
-        self.builder.chunk.add_op_nil(self.previous.line);
-        self.builder.chunk.add_op_return(self.previous.line);
+        self.add_default_return(self.previous.line);
 
         self.exit_scope();
 
-        let function: Function = Function::new(
-            arity,
-            self.builder.chunk.clone(),
-            token.lexeme.clone(),
-            self.builder.upvalues.clone(),
+        // Hand the finished chunk over and make the parent current again, nothing is copied
+        let parent = self.builder.parent.take().unwrap();
+        let mut builder = std::mem::replace(&mut self.builder, parent);
+        let rewrites = builder.take_rewrites();
+        let function = Function::new(arity, builder.chunk, name, builder.upvalues);
+        self.record_rewrites(&function, rewrites);
+        function
+    }
+
+    // What a function returns when it runs off its end or hits a bare `return;`
+    fn add_default_return(&mut self, line: i32) {
+        if self.builder.is_setter {
+            self.builder.chunk.add_op_get_local(1, line);
+        } else {
+            self.builder.chunk.add_op_nil(line);
+        }
+        self.emit_return(line);
+    }
+
+    pub fn parse_class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, error::EXPECT_CLASS_NAME);
+        let token = self.previous.clone();
+        let symbol = self.symbol(&token.lexeme);
+        let index = self.global_constant(symbol);
+        self.builder.chunk.add_op_class(index, token.line);
+        self.define_variable(token.clone());
+
+        // Keep the class on the stack while its methods are attached
+        match self.builder.resolve_local(symbol) {
+            Some(local) => self.builder.chunk.add_op_get_local(local, token.line),
+            None => self.builder.chunk.add_op_get_global(index, token.line),
+        }
+        self.consume(
+            TokenType::LeftBrace,
+            error::EXPECT_LEFT_BRACE_BEFORE_CLASS_BODY,
+        );
+        self.class_depth += 1;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.parse_method();
+        }
+        self.class_depth -= 1;
+        self.consume(
+            TokenType::RightBrace,
+            error::EXPECT_RIGHT_BRACE_AFTER_CLASS_BODY,
         );
+        self.builder.chunk.add_op_pop(self.previous.line);
+    }
+
+    // A method, a getter `name { ... }` or a setter `set name(value) { ... }`
+    pub fn parse_method(&mut self) {
+        self.consume(TokenType::Identifier, error::EXPECT_METHOD_NAME);
+        let mut token = self.previous.clone();
+        let this = self.symbol("this");
+        // `set` followed by '(' is still an ordinary method called set
+        let accessor = if &*token.lexeme == "set" && self.check(TokenType::Identifier) {
+            self.advance();
+            token = self.previous.clone();
+            Some(Accessor::Setter)
+        } else if self.check(TokenType::LeftBrace) {
+            Some(Accessor::Getter)
+        } else {
+            None
+        };
+        self.begin_function(this);
+        let mut function = match accessor {
+            Some(Accessor::Getter) => {
+                self.advance();
+                self.finish_function(token.lexeme.to_string(), 0)
+            }
+            Some(Accessor::Setter) => {
+                self.builder.is_setter = true;
+                self.parse_function_body(token.lexeme.to_string())
+            }
+            None => self.parse_function_body(token.lexeme.to_string()),
+        };
+        if accessor == Some(Accessor::Setter) && function.arity != 1 {
+            self.error(token.clone(), error::SETTER_ARITY);
+        }
+        function.accessor = accessor;
+
+        self.emit_constant(Value::Function(Rc::new(function)), self.previous.line);
+        self.builder.chunk.add_op_closure(self.previous.line);
+        let symbol = self.symbol(&token.lexeme);
+        let index = self.global_constant(symbol);
+        self.builder.chunk.add_op_method(index, token.line);
+    }
+
+    // fun (params) { body } as an expression, its slot 0 has a name no code can refer to
+    pub fn parse_lambda(&mut self) {
+        let line = self.previous.line;
+        let anonymous = self.symbol("");
+        let function = self.parse_function_with_slot("lambda".to_owned(), anonymous);
+        self.emit_constant(Value::Function(Rc::new(function)), line);
+        self.builder.chunk.add_op_closure(line);
+    }
+
+    pub fn parse_this(&mut self) {
+        if self.class_depth == 0 {
+            self.error(self.previous.clone(), error::THIS_OUTSIDE_CLASS);
+            return;
+        }
+        // `this` is a local like any other but can never be assigned
+        self.parse_variable(Precedence::Call);
+    }
 
-        self.builder = self.builder.parent.as_ref().unwrap().clone();
+    pub fn parse_list(&mut self) {
+        let mut count = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.parse_expression();
+                count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightBracket,
+            error::EXPECT_RIGHT_BRACKET_AFTER_ELEMENTS,
+        );
         self.builder
             .chunk
-            .add_op_constant(Value::Function(Rc::new(function)), self.previous.line);
-        self.builder.chunk.add_op_closure(self.previous.line);
-        if self.builder.scope_depth == 0 {
-            self.define_global_variable(token.clone());
+            .add_op_build_list(count, self.previous.line);
+    }
+
+    // {key: value, ...}, only reached in expression position so blocks are unaffected
+    pub fn parse_map(&mut self) {
+        let mut count = 0;
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.parse_expression();
+                self.consume(TokenType::Colon, error::EXPECT_COLON_AFTER_KEY);
+                self.parse_expression();
+                count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightBrace,
+            error::EXPECT_RIGHT_BRACE_AFTER_ENTRIES,
+        );
+        self.builder.chunk.add_op_build_map(count, self.previous.line);
+    }
+
+    pub fn parse_index(&mut self, precedence: Precedence) {
+        self.parse_expression();
+        self.consume(
+            TokenType::RightBracket,
+            error::EXPECT_RIGHT_BRACKET_AFTER_INDEX,
+        );
+        if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
+            self.parse_expression();
+            self.builder.chunk.add_op_index_set(self.previous.line);
+        } else {
+            self.builder.chunk.add_op_index_get(self.previous.line);
+        }
+    }
+
+    pub fn parse_dot(&mut self, precedence: Precedence) {
+        self.consume(TokenType::Identifier, error::EXPECT_PROPERTY_NAME);
+        let token = self.previous.clone();
+        let symbol = self.symbol(&token.lexeme);
+        let index = self.global_constant(symbol);
+        if precedence <= Precedence::Assignment && self.match_token(TokenType::Equal) {
+            self.parse_expression();
+            self.builder.chunk.add_op_set_property(index, token.line);
+        } else if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.parse_arguments();
+            self.builder
+                .chunk
+                .add_op_invoke(index, arg_count, self.previous.line);
+        } else {
+            self.builder.chunk.add_op_get_property(index, token.line);
+        }
+    }
+
+    // extern fun name(params); only declares a global the host has to provide
+    pub fn parse_extern_declaration(&mut self) {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::Fun, error::EXPECT_FUN_AFTER_EXTERN);
+        self.consume(TokenType::Identifier, error::EXPECT_FUNCTION_NAME);
+        let token = self.previous.clone();
+        self.consume(
+            TokenType::LeftParen,
+            error::EXPECT_LEFT_PAREN_AFTER_FUNCTION,
+        );
+        let mut arity = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.consume(TokenType::Identifier, error::EXPECT_PARAMETER_NAME);
+                arity += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightParen,
+            error::EXPECT_RIGHT_PAREN_AFTER_PARAMETERS,
+        );
+        self.consume(TokenType::SemiColon, error::EXPECT_SEMICOLON_AFTER_EXTERN);
+
+        if self.builder.parent.is_some() || self.builder.scope_depth != 0 {
+            self.error(keyword, error::EXTERN_NOT_TOP_LEVEL);
+            return;
+        }
+        self.declare_global(&token.lexeme);
+        let name = token.lexeme.to_string();
+        if !self.builder.chunk.externs.iter().any(|(declared, _)| *declared == name) {
+            self.builder.chunk.externs.push((name, arity));
         }
     }
 
+    pub fn parse_lazy_function(&mut self, name: String) -> Function {
+        self.consume(
+            TokenType::LeftParen,
+            error::EXPECT_LEFT_PAREN_AFTER_FUNCTION,
+        );
+        let mut params = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.consume(TokenType::Identifier, error::EXPECT_PARAMETER_NAME);
+                params.push(self.previous.lexeme.to_string());
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(
+            TokenType::RightParen,
+            error::EXPECT_RIGHT_PAREN_AFTER_PARAMETERS,
+        );
+
+        // Only skim the body tokens, keeping the source text for the first call
+        let line = self.current.line;
+        let body_start = self.scanner.current;
+        self.consume(
+            TokenType::LeftBrace,
+            error::EXPECT_LEFT_BRACE_BEFORE_FUNCTION_BODY,
+        );
+        let mut depth = 1;
+        loop {
+            match self.current.token_type {
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace => depth -= 1,
+                TokenType::Eof => break,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            self.advance();
+        }
+        let body_end = self.scanner.current;
+        self.consume(TokenType::RightBrace, error::EXPECT_RIGHT_BRACE_AFTER_BLOCK);
+
+        let arity = params.len();
+        let body = LazyBody {
+            name: name.clone(),
+            params,
+            source: self.scanner.source[body_start..body_end].to_owned(),
+            line,
+            offset: self.scanner.offset + body_start,
+            line_start: self.scanner.line_start,
+            compiled: RefCell::new(None),
+            session: self.session.clone(),
+            strict: self.strict,
+        };
+        Function::lazy(arity, name, body)
+    }
+
     pub fn parse_declaration(&mut self) {
+        if !self.nest() {
+            return;
+        }
         match self.current.token_type {
             TokenType::Var => {
                 self.advance();
@@ -683,8 +1745,17 @@ impl Compiler {
                 self.advance();
                 self.parse_func_declaration()
             }
+            TokenType::Extern => {
+                self.advance();
+                self.parse_extern_declaration()
+            }
+            TokenType::Class => {
+                self.advance();
+                self.parse_class_declaration()
+            }
             _ => self.parse_statement(),
         }
+        self.nesting -= 1;
         if self.panic_mode {
             self.synchronize();
         }
@@ -694,19 +1765,23 @@ impl Compiler {
         self.panic_mode = false;
 
         loop {
+            // The statement that failed may already have consumed its semicolon
+            if self.previous.token_type == TokenType::SemiColon {
+                break;
+            }
             match self.current.token_type {
-                TokenType::SemiColon => {
-                    self.advance();
-                    break;
-                }
                 TokenType::Class
                 | TokenType::Fun
+                | TokenType::Extern
                 | TokenType::Var
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => break,
+                | TokenType::Return
+                | TokenType::Throw
+                | TokenType::Try
+                | TokenType::Eof => break,
                 _ => {}
             }
             self.advance();
@@ -717,13 +1792,17 @@ impl Compiler {
         let token = self.previous.clone();
         match token.token_type {
             TokenType::LeftParen => self.parse_group(),
-            TokenType::Minus => self.parse_unary(),
+            TokenType::Minus | TokenType::Bang => self.parse_unary(),
             TokenType::Number => self.parse_number(),
             TokenType::True | TokenType::False | TokenType::Nil => self.parse_literal(),
             TokenType::String => self.parse_string(),
             TokenType::Identifier => self.parse_variable(precedence),
+            TokenType::This => self.parse_this(),
+            TokenType::LeftBracket => self.parse_list(),
+            TokenType::LeftBrace => self.parse_map(),
+            TokenType::Fun => self.parse_lambda(),
             _ => {
-                self.show_error(token, error::EXPECT_EXPRESSION);
+                self.error(token, error::EXPECT_EXPRESSION);
             }
         }
     }
@@ -752,7 +1831,24 @@ impl Compiler {
         self.patch_op(then_index);
     }
 
-    pub fn parse_infix(&mut self) {
+    // cond ? a : b, right associative so the else branch may chain another ternary
+    pub fn parse_ternary(&mut self) {
+        let else_index = self
+            .builder
+            .chunk
+            .add_op_juml_if_false(0, self.previous.line);
+        self.builder.chunk.add_op_pop(self.previous.line);
+        self.parse_expression();
+        self.consume(TokenType::Colon, error::EXPECT_COLON_IN_TERNARY);
+
+        let end_index = self.builder.chunk.add_op_jump(0, self.previous.line);
+        self.patch_op(else_index);
+        self.builder.chunk.add_op_pop(self.previous.line);
+        self.parse_precedence(Precedence::Ternary);
+        self.patch_op(end_index);
+    }
+
+    pub fn parse_infix(&mut self, precedence: Precedence) {
         let token = self.previous.clone();
         match token.token_type {
             TokenType::Minus
@@ -760,20 +1856,42 @@ impl Compiler {
             | TokenType::Star
             | TokenType::Slash
             | TokenType::EqualEqual
+            | TokenType::BangEqual
             | TokenType::Greater
             | TokenType::GreaterEqual
             | TokenType::Less
             | TokenType::LessEqual => self.parse_binary(),
             TokenType::And => self.parse_and(),
             TokenType::Or => self.parse_or(),
+            TokenType::Question => self.parse_ternary(),
             TokenType::LeftParen => self.parse_call(),
+            TokenType::Dot => self.parse_dot(precedence),
+            TokenType::LeftBracket => self.parse_index(precedence),
             _ => {
-                panic!("Error infix parse")
+                self.error(token, error::EXPECT_INFIX_OPERATOR);
             }
         }
     }
 
     pub fn parse_call(&mut self) {
+        let callee_index = self.builder.chunk.codes.len().checked_sub(1);
+        let arg_count = self.parse_arguments();
+
+        if self.inline {
+            if let Some(function) = self.inline_target(callee_index, arg_count) {
+                if self.inline_call(&function, arg_count, self.previous.line) {
+                    self.builder.inlined += 1;
+                    return;
+                }
+            }
+        }
+        self.builder
+            .chunk
+            .add_op_call(arg_count, self.previous.line);
+    }
+
+    // Arguments up to and including the ')', returns how many there were
+    pub fn parse_arguments(&mut self) -> usize {
         let mut arg_count = 0;
         if !self.check(TokenType::RightParen) {
             loop {
@@ -785,10 +1903,134 @@ impl Compiler {
             }
         }
         self.consume(TokenType::RightParen, error::EXPECT_RIGHT_PAREN_AFTER_ARG);
+        arg_count
+    }
 
-        self.builder
-            .chunk
-            .add_op_call(arg_count, self.previous.line);
+    // The inlinable function called when the callee is a plain global read
+    fn inline_target(&self, callee_index: Option<usize>, arg_count: usize) -> Option<Rc<Function>> {
+        let callee_index = callee_index?;
+        let chunk = &self.builder.chunk;
+        // A jump landing right after the callee means it may not be the value called
+        let is_join = chunk.codes.iter().enumerate().any(|(index, code)| match code {
+            OpCode::OpJump(offset) | OpCode::OpJumpIfFalse(offset) => {
+                index + offset == callee_index + 1
+            }
+            _ => false,
+        });
+        if is_join {
+            return None;
+        }
+        match chunk.codes[callee_index] {
+            OpCode::OpGetGlobal(index) => {
+                let function = self.inline_functions.get(chunk.values[index].as_str()?)?;
+                if function.arity == arg_count {
+                    Some(function.clone())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Copy the callee's code in place of OpCall, with its slots moved above the caller's
+    // stack and every return collapsing the frame into the callee slot
+    fn inline_call(&mut self, function: &Function, arg_count: usize, line: i32) -> bool {
+        let initial = self.builder.frame_size();
+        let depth = match self.builder.chunk.stack_depths(initial) {
+            Ok(depths) => depths[self.builder.chunk.codes.len()],
+            Err(_) => None,
+        };
+        let callee_depths = function.chunk.stack_depths(function.arity + 1);
+        let (depth, callee_depths) = match (depth, callee_depths) {
+            (Some(depth), Ok(callee_depths)) => (depth, callee_depths),
+            _ => return false,
+        };
+        // Leave the call in place rather than overflow the caller's constants
+        if self.builder.chunk.values.len() + function.chunk.values.len() > MAX_CONSTANTS {
+            return false;
+        }
+        let base = depth - arg_count - 1;
+
+        let chunk = &mut self.builder.chunk;
+        let mut positions = vec![];
+        let mut jumps = vec![];
+        let mut exits = vec![];
+        for (index, code) in function.chunk.codes.iter().enumerate() {
+            positions.push(chunk.codes.len());
+            let depth = match callee_depths[index] {
+                Some(depth) => depth,
+                None => continue,
+            };
+            let code = match *code {
+                OpCode::OpConstant(i) | OpCode::OpConstantLong(i) => {
+                    OpCode::constant(copy_constant(chunk, function, i))
+                }
+                OpCode::OpDefineGlobal(i) => {
+                    OpCode::OpDefineGlobal(copy_constant(chunk, function, i))
+                }
+                OpCode::OpGetGlobal(i) => {
+                    OpCode::OpGetGlobal(copy_constant(chunk, function, i))
+                }
+                OpCode::OpSetGlobal(i) => {
+                    OpCode::OpSetGlobal(copy_constant(chunk, function, i))
+                }
+                OpCode::OpClass(i) => {
+                    OpCode::OpClass(copy_constant(chunk, function, i))
+                }
+                OpCode::OpMethod(i) => {
+                    OpCode::OpMethod(copy_constant(chunk, function, i))
+                }
+                OpCode::OpGetProperty(i) => {
+                    OpCode::OpGetProperty(copy_constant(chunk, function, i))
+                }
+                OpCode::OpSetProperty(i) => {
+                    OpCode::OpSetProperty(copy_constant(chunk, function, i))
+                }
+                OpCode::OpInvoke(i, arg_count) => OpCode::OpInvoke(
+                    copy_constant(chunk, function, i),
+                    arg_count,
+                ),
+                OpCode::OpGetLocal(i) => OpCode::OpGetLocal(base + i),
+                OpCode::OpSetLocal(i) => OpCode::OpSetLocal(base + i),
+                OpCode::OpJump(offset) | OpCode::OpJumpIfFalse(offset) => {
+                    jumps.push((chunk.codes.len(), index + offset));
+                    *code
+                }
+                OpCode::OpLoop(offset) => {
+                    jumps.push((chunk.codes.len(), index - offset));
+                    *code
+                }
+                OpCode::OpReturn => {
+                    chunk.codes.push(OpCode::OpSetLocal(base));
+                    chunk.push_line(line);
+                    for _ in 1..depth {
+                        chunk.add_op_pop(line);
+                    }
+                    exits.push(chunk.add_op_jump(0, line));
+                    continue;
+                }
+                code => code,
+            };
+            chunk.codes.push(code);
+            chunk.push_line(line);
+        }
+        positions.push(chunk.codes.len());
+
+        let end = chunk.codes.len();
+        for (at, target) in jumps {
+            let target = positions[target];
+            chunk.codes[at] = match chunk.codes[at] {
+                OpCode::OpJump(_) => OpCode::OpJump(target - at),
+                OpCode::OpJumpIfFalse(_) => OpCode::OpJumpIfFalse(target - at),
+                OpCode::OpLoop(_) => OpCode::OpLoop(at - target),
+                code => code,
+            };
+        }
+        for at in exits {
+            chunk.codes[at] = OpCode::OpJump(end - at);
+        }
+        true
     }
 
     pub fn match_token(&mut self, token_type: TokenType) -> bool {
@@ -804,3 +2046,10 @@ impl Compiler {
         self.current.token_type == token_type
     }
 }
+
+// One of the callee's constants in the caller's pool, which inline_call made room for
+fn copy_constant(chunk: &mut Chunk, function: &Function, index: usize) -> usize {
+    chunk
+        .add_value(function.chunk.values[index].clone())
+        .expect("room is checked before inlining")
+}
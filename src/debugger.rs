@@ -0,0 +1,218 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+};
+
+use crate::{chunk::Value, vm::VM};
+
+// What a paused script does next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    // Run until the next breakpoint
+    Continue,
+    // Run until execution reaches another line
+    Step,
+    // Stop the script with a runtime error
+    Abort,
+}
+
+// Called by the VM before every instruction while attached, false stops the script
+pub trait Debugger {
+    fn before_instruction(&mut self, vm: &VM) -> bool;
+}
+
+// The VM as seen from a pause, with one frame selected for inspection
+pub struct Pause<'a> {
+    pub vm: &'a VM,
+    pub breakpoints: &'a mut BTreeSet<usize>,
+    // Counts outwards from the innermost frame, like the backtrace
+    pub selected: usize,
+}
+
+impl<'a> Pause<'a> {
+    fn frame_index(&self) -> usize {
+        self.vm.frames.len() - 1 - self.selected
+    }
+
+    // Line of the selected frame, counting from 1
+    pub fn line(&self) -> usize {
+        let frame = &self.vm.frames[self.frame_index()];
+        frame
+            .closure
+            .function
+            .chunk
+            .line_at_ip(frame.ip)
+            .map_or(0, |line| line as usize + 1)
+    }
+
+    pub fn backtrace(&self) -> Vec<String> {
+        self.vm.backtrace()
+    }
+
+    pub fn stack(&self) -> Vec<Value> {
+        self.vm.stack.borrow().clone()
+    }
+
+    // Slots of the selected frame, the callee first and then its arguments and locals
+    pub fn locals(&self) -> Vec<Value> {
+        let index = self.frame_index();
+        let stack = self.vm.stack.borrow();
+        let end = self
+            .vm
+            .frames
+            .get(index + 1)
+            .map_or(stack.len(), |frame| frame.base);
+        stack[self.vm.frames[index].base..end].to_vec()
+    }
+
+    // Select the caller of the selected frame, false when it is already the script
+    pub fn up(&mut self) -> bool {
+        if self.selected + 1 < self.vm.frames.len() {
+            self.selected += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn down(&mut self) -> bool {
+        if self.selected > 0 {
+            self.selected -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Pauses on breakpoints and steps, handing each pause to on_pause
+pub struct DebugSession<F> {
+    // Lines counting from 1
+    pub breakpoints: BTreeSet<usize>,
+    // Pause on the next line whatever the breakpoints say
+    pub stepping: bool,
+    // Frame depth, line and ip of the last instruction seen
+    last: Option<(usize, usize, usize)>,
+    on_pause: F,
+}
+
+impl<F: FnMut(&mut Pause) -> Resume> DebugSession<F> {
+    pub fn new(on_pause: F) -> Self {
+        DebugSession {
+            breakpoints: BTreeSet::new(),
+            stepping: false,
+            last: None,
+            on_pause,
+        }
+    }
+}
+
+impl<F: FnMut(&mut Pause) -> Resume> Debugger for DebugSession<F> {
+    fn before_instruction(&mut self, vm: &VM) -> bool {
+        let frame = match vm.frames.last() {
+            Some(frame) => frame,
+            None => return true,
+        };
+        let depth = vm.frames.len();
+        let line = frame
+            .closure
+            .function
+            .chunk
+            .line_at_ip(frame.ip)
+            .map_or(0, |line| line as usize + 1);
+        // Stop once per visit to a line, a jump backwards starts a new visit
+        let is_new_line = match self.last {
+            Some((last_depth, last_line, last_ip)) => {
+                last_depth != depth || last_line != line || frame.ip <= last_ip
+            }
+            None => true,
+        };
+        self.last = Some((depth, line, frame.ip));
+        if !is_new_line || !(self.stepping || self.breakpoints.contains(&line)) {
+            return true;
+        }
+
+        let mut pause = Pause {
+            vm,
+            breakpoints: &mut self.breakpoints,
+            selected: 0,
+        };
+        match (self.on_pause)(&mut pause) {
+            Resume::Continue => self.stepping = false,
+            Resume::Step => self.stepping = true,
+            Resume::Abort => return false,
+        }
+        true
+    }
+}
+
+const HELP: &str = "\
+break N, b N     pause on line N
+delete N, d N    remove the breakpoint on line N
+continue, c      run to the next breakpoint
+step, s          run to the next line
+backtrace, bt    show the active calls
+stack            show the whole value stack
+locals           show the slots of the selected frame
+up, down         select the caller or the callee
+quit, q          stop the script";
+
+// Read commands from stdin until one resumes the script, for `rlox debug`
+pub fn prompt(pause: &mut Pause, source: &str) -> Resume {
+    let line = pause.line();
+    let text = source.lines().nth(line.wrapping_sub(1)).unwrap_or("");
+    println!("{}\n{:>4} | {}", pause.backtrace()[pause.selected], line, text);
+    let stdin = io::stdin();
+    loop {
+        print!("(debug) ");
+        io::stdout().flush().ok();
+        let mut input = String::new();
+        match stdin.lock().read_line(&mut input) {
+            Ok(0) | Err(_) => return Resume::Abort,
+            Ok(_) => {}
+        }
+        let words: Vec<&str> = input.split_whitespace().collect();
+        match words[..] {
+            ["continue"] | ["c"] => return Resume::Continue,
+            ["step"] | ["s"] => return Resume::Step,
+            ["quit"] | ["q"] => return Resume::Abort,
+            ["break", line] | ["b", line] => match line.parse() {
+                Ok(line) => {
+                    pause.breakpoints.insert(line);
+                }
+                Err(_) => println!("Expect a line number"),
+            },
+            ["delete", line] | ["d", line] => match line.parse::<usize>() {
+                Ok(line) if pause.breakpoints.remove(&line) => {}
+                _ => println!("No breakpoint on line {}", line),
+            },
+            ["backtrace"] | ["bt"] => {
+                for (index, call) in pause.backtrace().iter().enumerate() {
+                    let marker = if index == pause.selected { ">" } else { " " };
+                    println!("{} {}", marker, call);
+                }
+            }
+            ["stack"] => {
+                for value in pause.stack() {
+                    print!("[ {} ]", value);
+                }
+                println!();
+            }
+            ["locals"] => {
+                for (slot, value) in pause.locals().iter().enumerate() {
+                    println!("{:>4} {}", slot, value);
+                }
+            }
+            ["up"] | ["down"] => {
+                let moved = if words[0] == "up" { pause.up() } else { pause.down() };
+                if moved {
+                    println!("{}", pause.backtrace()[pause.selected]);
+                } else {
+                    println!("No frame {} there", words[0]);
+                }
+            }
+            [] => {}
+            _ => println!("{}", HELP),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::token::Token;
+
 pub const EMPTY_STACK: &str = "Error: empty stack";
 
 pub const EXPECT_RIGHT_PAREN_AFTER_EXPRESSION: &str = "Expect ')' after expression";
@@ -14,10 +16,11 @@ pub const WARN_GLOBAL_BE_STRING: &str = "Warn OpDefineGlobal should be string";
 pub const INVALID_ASSIGNMENT_TARGET: &str = "Invalid assignment target";
 pub const EXPECT_RIGHT_BRACE_AFTER_BLOCK: &str = "Expect '}' after block";
 pub const ALREADY_VARIABLE_DELCARE: &str = "Already variable with this name in this scope";
+pub const READ_LOCAL_IN_INITIALIZER: &str = "Can't read local variable in its own initializer";
 pub const EXPECT_LEFT_PAREN_AFTER_IF: &str = "Expect '(' after 'if'";
 pub const EXPECT_RIGHT_PAREN_AFTER_CONDITION: &str = "Expect ')' after condition";
 pub const EXPECT_LEFT_PAREN_AFTER_WHILE: &str = "Expect '(' after while";
-pub const EXPECT_LEFT_PAREN_AFTER_FOR: &str = "Expect ')' after for";
+pub const EXPECT_LEFT_PAREN_AFTER_FOR: &str = "Expect '(' after 'for'";
 pub const EXPECT_SEMICOLON_AFTER_LOOP: &str = "Expect ';' after for condition";
 pub const EXPECT_FUNCTION_NAME: &str = "Expect function name";
 pub const EXPECT_LEFT_PAREN_AFTER_FUNCTION: &str = "Expect '(' after function name";
@@ -25,4 +28,154 @@ pub const EXPECT_RIGHT_PAREN_AFTER_PARAMETERS: &str = "Expect ')' after paramete
 pub const EXPECT_LEFT_BRACE_BEFORE_FUNCTION_BODY: &str = "Expect '{' before function body";
 pub const EXPECT_PARAMETER_NAME: &str = "Expect parameter name";
 pub const EXPECT_RIGHT_PAREN_AFTER_ARG: &str = "Expect ')' after arguments";
-pub const EXPECT_SEMICOLON_AFTER_RETURN:&str = "Expect ';' after return value";
\ No newline at end of file
+pub const EXPECT_SEMICOLON_AFTER_RETURN:&str = "Expect ';' after return value";
+pub const EXPECT_SEMICOLON_AFTER_THROW: &str = "Expect ';' after thrown value";
+pub const EXPECT_LEFT_BRACE_AFTER_TRY: &str = "Expect '{' after 'try'";
+pub const EXPECT_CATCH_OR_FINALLY: &str = "Expect 'catch' or 'finally' after try block";
+pub const EXPECT_LEFT_PAREN_AFTER_CATCH: &str = "Expect '(' after 'catch'";
+pub const EXPECT_CATCH_VARIABLE: &str = "Expect exception variable name";
+pub const EXPECT_RIGHT_PAREN_AFTER_CATCH: &str = "Expect ')' after exception variable";
+pub const EXPECT_LEFT_BRACE_AFTER_CATCH: &str = "Expect '{' after catch clause";
+pub const EXPECT_LEFT_BRACE_AFTER_FINALLY: &str = "Expect '{' after 'finally'";
+pub const UNCAUGHT_EXCEPTION: &str = "Uncaught exception:";
+pub const TOO_MUCH_NESTING: &str = "Too much nesting";
+pub const TOO_MANY_CONSTANTS: &str = "Too many constants in one chunk";
+pub const EXPECT_INFIX_OPERATOR: &str = "Expect infix operator";
+pub const PATCH_NOT_JUMP: &str = "Patched instruction is not a jump";
+pub const EXPECT_FUN_AFTER_EXTERN: &str = "Expect 'fun' after 'extern'";
+pub const EXPECT_SEMICOLON_AFTER_EXTERN: &str = "Expect ';' after extern declaration";
+pub const EXTERN_NOT_TOP_LEVEL: &str = "Extern declarations must be at top level";
+pub const UNBOUND_EXTERN: &str = "Unbound extern:";
+pub const EXTERN_ARITY: &str = "Extern arity mismatch:";
+pub const WARN_CLASS_NAME_BE_STRING: &str = "Warn OpClass name should be string";
+pub const EXPECT_CLASS_NAME: &str = "Expect class name";
+pub const EXPECT_LEFT_BRACE_BEFORE_CLASS_BODY: &str = "Expect '{' before class body";
+pub const EXPECT_RIGHT_BRACE_AFTER_CLASS_BODY: &str = "Expect '}' after class body";
+pub const EXPECT_METHOD_NAME: &str = "Expect method name";
+pub const SETTER_ARITY: &str = "A setter takes exactly one parameter";
+pub const SETTER_RETURN_VALUE: &str = "Can't return a value from a setter";
+pub const WARN_PROPERTY_NAME_BE_STRING: &str = "Warn property name should be string";
+pub const EXPECT_PROPERTY_NAME: &str = "Expect property name after '.'";
+pub const THIS_OUTSIDE_CLASS: &str = "Can't use 'this' outside of a class";
+pub const UNDEFINED_PROPERTY: &str = "Undefined property";
+pub const ONLY_INSTANCES_HAVE_PROPERTIES: &str = "Only instances have properties";
+pub const ONLY_INSTANCES_HAVE_FIELDS: &str = "Only instances have fields";
+pub const PRIVATE_MEMBER: &str = "Can't access private member";
+pub const MISSING_CMP: &str = "No cmp method to compare";
+pub const CMP_NOT_NUMBER: &str = "cmp must return a number but returned";
+pub const EXPECT_COLON_IN_TERNARY: &str = "Expect ':' after then branch of conditional";
+pub const INVALID_ESCAPE: &str = "Invalid escape sequence";
+pub const INVALID_NUMBER: &str = "Invalid number literal";
+pub const UNTERMINATED_COMMENT: &str = "Unterminated block comment";
+pub const UNTERMINATED_STRING: &str = "Unterminated string";
+pub const EXPECT_RIGHT_BRACKET_AFTER_ELEMENTS: &str = "Expect ']' after list elements";
+pub const EXPECT_RIGHT_BRACKET_AFTER_INDEX: &str = "Expect ']' after index";
+pub const NOT_INDEXABLE: &str = "Only lists and maps can be indexed";
+pub const INDEX_MUST_BE_INTEGER: &str = "Index must be a non-negative integer";
+pub const INDEX_OUT_OF_RANGE: &str = "List index out of range";
+pub const EXPECT_COLON_AFTER_KEY: &str = "Expect ':' after map key";
+pub const EXPECT_RIGHT_BRACE_AFTER_ENTRIES: &str = "Expect '}' after map entries";
+pub const INVALID_MAP_KEY: &str = "Map keys must be strings or numbers";
+pub const INVALID_LOCAL: &str = "Error: local slot outside the frame";
+pub const STACK_OVERFLOW: &str = "Stack overflow.";
+pub const BUDGET_EXCEEDED: &str = "Instruction budget exceeded";
+pub const HEAP_LIMIT: &str = "Heap limit exceeded";
+pub const ASSERTION_FAILED: &str = "Assertion failed";
+pub const DEBUGGER_STOPPED: &str = "Stopped by the debugger";
+pub const ALREADY_RUNNING: &str = "A script is already running on this VM";
+pub const UNUSED_VARIABLE: &str = "Unused variable";
+pub const UNUSED_FUNCTION: &str = "Unused function";
+pub const UNREACHABLE_CODE: &str = "Unreachable code";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// A problem in the source, located down to the bytes of the offending token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // Both 1-based, as editors count
+    pub line: i32,
+    pub column: usize,
+    // Byte range in the whole source
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, token: &Token, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message: message.to_owned(),
+            line: token.line + 1,
+            column: token.column,
+            start: token.start,
+            length: token.length,
+        }
+    }
+
+    pub fn error(token: &Token, message: &str) -> Diagnostic {
+        Diagnostic::new(Severity::Error, token, message)
+    }
+
+    // The scanner steps bytewise, so an error token may start inside a character
+    fn start_in(&self, source: &str) -> usize {
+        let mut start = self.start.min(source.len());
+        while !source.is_char_boundary(start) {
+            start -= 1;
+        }
+        start
+    }
+
+    // The full source line the diagnostic starts on, without its newline
+    pub fn snippet<'a>(&self, source: &'a str) -> &'a str {
+        let start = self.start_in(source);
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        &source[line_start..line_end]
+    }
+
+    // rustc style: the message, the source line, and carets under the token
+    pub fn render(&self, source: &str, color: bool) -> String {
+        let (label, paint) = match self.severity {
+            Severity::Error => ("error", "\x1b[1;31m"),
+            Severity::Warning => ("warning", "\x1b[1;33m"),
+        };
+        let (paint, blue, bold, reset) = if color {
+            (paint, "\x1b[1;34m", "\x1b[1m", "\x1b[0m")
+        } else {
+            ("", "", "", "")
+        };
+
+        let snippet = self.snippet(source);
+        let start = self.start_in(source);
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        // Keep tabs so the carets line up with the text above them
+        let padding: String = source[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let mut end = (start + self.length).min(line_start + snippet.len());
+        while !source.is_char_boundary(end) {
+            end += 1;
+        }
+        let width = source[start..end].chars().count().max(1);
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        format!(
+            "{paint}{label}{reset}{bold}: {message}{reset}\n\
+             {gutter}{blue}-->{reset} line {line}:{column}\n\
+             {gutter} {blue}|{reset}\n\
+             {blue}{line} |{reset} {snippet}\n\
+             {gutter} {blue}|{reset} {padding}{paint}{carets}{reset}\n",
+            message = self.message,
+            line = self.line,
+            column = self.column,
+            carets = "^".repeat(width),
+        )
+    }
+}
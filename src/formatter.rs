@@ -0,0 +1,244 @@
+use crate::{
+    ast,
+    error::Diagnostic,
+    scanner::Scanner,
+    token::{Token, TokenType},
+    util,
+};
+
+const INDENT: &str = "  ";
+
+// Reprint a program with canonical layout. Works on the tokens and the text between
+// them, so comments survive, but only once the program is known to parse.
+pub fn format(source: &str) -> Result<String, Vec<Diagnostic>> {
+    ast::parse(source)?;
+    let mut formatter = Formatter::default();
+    let mut scanner = Scanner::new(source.to_owned());
+    let mut end = 0;
+    loop {
+        let token = scanner.scan();
+        formatter.gap(&source[end..token.start]);
+        if token.token_type == TokenType::Eof {
+            break;
+        }
+        end = token.start + token.length;
+        formatter.token(&token, &source[token.start..end]);
+    }
+    formatter.newline();
+    Ok(formatter.out)
+}
+
+#[derive(Default)]
+struct Formatter {
+    out: String,
+    depth: usize,
+    at_line_start: bool,
+    previous: Option<TokenType>,
+    // One entry per open brace, true for blocks and false for map literals
+    braces: Vec<bool>,
+    // Open parentheses, saved and reset while inside a block so its ';' end lines
+    parens: usize,
+    saved_parens: Vec<usize>,
+    // Whether the last token closed a block or a map
+    closed_block: bool,
+    closed_map: bool,
+    // Ternaries waiting for their ':'
+    ternaries: usize,
+}
+
+impl Formatter {
+    fn newline(&mut self) {
+        if !self.out.is_empty() && !self.at_line_start {
+            self.out.push('\n');
+        }
+        self.at_line_start = true;
+    }
+
+    fn blank_line(&mut self) {
+        self.newline();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        if self.at_line_start {
+            for _ in 0..self.depth {
+                self.out.push_str(INDENT);
+            }
+            self.at_line_start = false;
+        }
+        self.out.push_str(text);
+    }
+
+    // Undo the line break just written, to put something after it on the same line
+    fn rejoin_line(&mut self) {
+        if self.at_line_start && self.out.ends_with('\n') {
+            self.out.pop();
+            self.at_line_start = false;
+        }
+    }
+
+    fn space(&mut self) {
+        if !self.at_line_start && !self.out.is_empty() && !self.out.ends_with(' ') {
+            self.out.push(' ');
+        }
+    }
+
+    // Whitespace and comments between two tokens
+    fn gap(&mut self, text: &str) {
+        let mut newlines = 0;
+        let mut rest = text;
+        while !rest.is_empty() {
+            let comment_len = if rest.starts_with("//") {
+                rest.find('\n').unwrap_or(rest.len())
+            } else if rest.starts_with("/*") {
+                util::block_comment_len(rest)
+            } else {
+                let c = rest.chars().next().unwrap_or(' ');
+                if c == '\n' {
+                    newlines += 1;
+                }
+                rest = &rest[c.len_utf8()..];
+                continue;
+            };
+            let comment = &rest[..comment_len];
+            rest = &rest[comment_len..];
+            if newlines == 0 && self.previous.is_some() {
+                // Stays at the end of the line it was on
+                self.rejoin_line();
+                self.space();
+            } else {
+                self.preserve_blank_line(newlines);
+                self.newline();
+            }
+            self.write(comment.trim_end());
+            if comment.starts_with("//") || rest.trim_start_matches([' ', '\t', '\r']).starts_with('\n') {
+                self.newline();
+            }
+            newlines = 0;
+        }
+        self.preserve_blank_line(newlines);
+    }
+
+    // Keep one empty line where statements were separated by one or more
+    fn preserve_blank_line(&mut self, newlines: usize) {
+        let at_statement_end = self.at_line_start || self.closed_block;
+        if newlines >= 2 && at_statement_end && self.previous != Some(TokenType::LeftBrace) {
+            self.blank_line();
+        }
+    }
+
+    fn ends_operand(&self) -> bool {
+        match self.previous {
+            Some(TokenType::RightBrace) => self.closed_map,
+            Some(previous) => matches!(
+                previous,
+                TokenType::Identifier
+                    | TokenType::Number
+                    | TokenType::String
+                    | TokenType::True
+                    | TokenType::False
+                    | TokenType::Nil
+                    | TokenType::This
+                    | TokenType::RightParen
+                    | TokenType::RightBracket
+            ),
+            None => false,
+        }
+    }
+
+    fn token(&mut self, token: &Token, text: &str) {
+        let token_type = token.token_type;
+        if self.closed_block {
+            self.closed_block = false;
+            match token_type {
+                TokenType::Else | TokenType::Catch | TokenType::Finally => self.space(),
+                TokenType::SemiColon | TokenType::RightParen | TokenType::Comma => {}
+                _ => self.newline(),
+            }
+        }
+
+        let is_block = token_type == TokenType::LeftBrace
+            && matches!(
+                self.previous,
+                None | Some(TokenType::RightParen)
+                    | Some(TokenType::Else)
+                    | Some(TokenType::Try)
+                    | Some(TokenType::Finally)
+                    | Some(TokenType::Identifier)
+                    | Some(TokenType::SemiColon)
+                    | Some(TokenType::LeftBrace)
+                    | Some(TokenType::RightBrace)
+            )
+            && !(self.previous == Some(TokenType::RightBrace) && self.closed_map)
+            && !(self.previous == Some(TokenType::LeftBrace) && self.braces.last() == Some(&false));
+
+        match token_type {
+            TokenType::RightBrace => {
+                if self.braces.last() == Some(&true) {
+                    self.depth = self.depth.saturating_sub(1);
+                    if self.previous == Some(TokenType::LeftBrace) {
+                        self.rejoin_line();
+                    } else {
+                        self.newline();
+                    }
+                }
+            }
+            TokenType::Comma | TokenType::SemiColon | TokenType::RightParen | TokenType::RightBracket | TokenType::Dot => {}
+            // Calls and indexing stick to what they apply to
+            TokenType::LeftParen | TokenType::LeftBracket => {
+                if !self.ends_operand() {
+                    self.space_after_previous();
+                }
+            }
+            TokenType::Colon if self.ternaries == 0 => {}
+            _ => self.space_after_previous(),
+        }
+
+        self.write(text);
+
+        let was_operand = self.ends_operand();
+        self.closed_map = false;
+        match token_type {
+            TokenType::LeftBrace if is_block => {
+                self.braces.push(true);
+                self.saved_parens.push(self.parens);
+                self.parens = 0;
+                self.depth += 1;
+                self.newline();
+            }
+            TokenType::LeftBrace => self.braces.push(false),
+            TokenType::RightBrace => match self.braces.pop() {
+                Some(true) => {
+                    self.parens = self.saved_parens.pop().unwrap_or(0);
+                    self.closed_block = true;
+                }
+                _ => self.closed_map = true,
+            },
+            TokenType::LeftParen => self.parens += 1,
+            TokenType::RightParen => self.parens = self.parens.saturating_sub(1),
+            TokenType::SemiColon if self.parens == 0 => self.newline(),
+            TokenType::Question => self.ternaries += 1,
+            TokenType::Colon if self.ternaries > 0 => self.ternaries -= 1,
+            _ => {}
+        }
+        // Unary operators sit against their operand
+        self.previous = match token_type {
+            TokenType::Minus if !was_operand => Some(TokenType::Bang),
+            token_type => Some(token_type),
+        };
+    }
+
+    // The space a token gets unless the previous one sticks to what follows it
+    fn space_after_previous(&mut self) {
+        match self.previous {
+            Some(TokenType::LeftParen)
+            | Some(TokenType::LeftBracket)
+            | Some(TokenType::Dot)
+            | Some(TokenType::Bang) => {}
+            Some(TokenType::LeftBrace) if self.braces.last() == Some(&false) => {}
+            _ => self.space(),
+        }
+    }
+}
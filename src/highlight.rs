@@ -0,0 +1,191 @@
+use std::fmt::Write;
+
+use crate::{
+    scanner::Scanner,
+    token::TokenType,
+    util,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Keyword,
+    Number,
+    String,
+    Identifier,
+    Comment,
+    Operator,
+    Punctuation,
+    // Text the scanner rejected
+    Error,
+}
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Keyword => "keyword",
+            Category::Number => "number",
+            Category::String => "string",
+            Category::Identifier => "identifier",
+            Category::Comment => "comment",
+            Category::Operator => "operator",
+            Category::Punctuation => "punctuation",
+            Category::Error => "error",
+        }
+    }
+
+    fn of(token_type: TokenType) -> Category {
+        match token_type {
+            TokenType::Identifier => Category::Identifier,
+            TokenType::Number => Category::Number,
+            TokenType::String => Category::String,
+            TokenType::LeftParen
+            | TokenType::RightParen
+            | TokenType::LeftBrace
+            | TokenType::RightBrace
+            | TokenType::LeftBracket
+            | TokenType::RightBracket
+            | TokenType::Comma
+            | TokenType::Dot
+            | TokenType::SemiColon => Category::Punctuation,
+            TokenType::Minus
+            | TokenType::Plus
+            | TokenType::Question
+            | TokenType::Colon
+            | TokenType::Slash
+            | TokenType::Star
+            | TokenType::Bang
+            | TokenType::BangEqual
+            | TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual
+            | TokenType::Equal
+            | TokenType::EqualEqual => Category::Operator,
+            TokenType::Error | TokenType::Eof => Category::Error,
+            _ => Category::Keyword,
+        }
+    }
+}
+
+// A classified piece of the source. Start and length are in bytes, line and column
+// count characters from 1 as editors do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub category: Category,
+    pub start: usize,
+    pub length: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Every token and comment of the source in order, whether or not it compiles
+pub fn highlight(source: &str) -> Vec<Span> {
+    let mut spans = vec![];
+    let mut scanner = Scanner::new(source.to_owned());
+    let mut end = 0;
+    loop {
+        let token = scanner.scan();
+        comments(source, end, token.start, &mut spans);
+        if token.token_type == TokenType::Eof {
+            break;
+        }
+        // An unterminated comment is reported as an empty token at the end
+        if token.length > 0 {
+            spans.push(Span {
+                category: Category::of(token.token_type),
+                start: token.start,
+                length: token.length,
+                line: 0,
+                column: 0,
+            });
+        }
+        end = token.start + token.length;
+    }
+
+    // Tokens only know the line they end on, so count both from the text
+    let (mut offset, mut line, mut column) = (0, 1, 1);
+    for span in spans.iter_mut() {
+        for c in source[offset..span.start].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        offset = span.start;
+        span.line = line;
+        span.column = column;
+    }
+    spans
+}
+
+// The comments between two tokens, which is otherwise only whitespace
+fn comments(source: &str, start: usize, end: usize, spans: &mut Vec<Span>) {
+    let mut index = start;
+    while index < end {
+        let rest = &source[index..end];
+        let length = if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if rest.starts_with("/*") {
+            util::block_comment_len(rest)
+        } else {
+            index += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+        spans.push(Span {
+            category: Category::Comment,
+            start: index,
+            length,
+            line: 0,
+            column: 0,
+        });
+        index += length;
+    }
+}
+
+// One object per span, for editors and the playground
+pub fn to_json(spans: &[Span]) -> String {
+    let mut out = String::from("[");
+    for (index, span) in spans.iter().enumerate() {
+        let separator = if index == 0 { "" } else { "," };
+        write!(
+            out,
+            "{}\n  {{\"start\": {}, \"length\": {}, \"line\": {}, \"column\": {}, \"category\": \"{}\"}}",
+            separator,
+            span.start,
+            span.length,
+            span.line,
+            span.column,
+            span.category.name()
+        )
+        .ok();
+    }
+    out.push_str("\n]\n");
+    out
+}
+
+// The source with terminal colors, identifiers and punctuation left plain
+pub fn to_ansi(source: &str, spans: &[Span]) -> String {
+    let mut out = String::new();
+    let mut end = 0;
+    for span in spans {
+        out.push_str(&source[end..span.start]);
+        end = span.start + span.length;
+        let text = &source[span.start..end];
+        let paint = match span.category {
+            Category::Keyword => "\x1b[35m",
+            Category::Number => "\x1b[33m",
+            Category::String => "\x1b[32m",
+            Category::Comment => "\x1b[90m",
+            Category::Error => "\x1b[31m",
+            _ => {
+                out.push_str(text);
+                continue;
+            }
+        };
+        write!(out, "{}{}\x1b[0m", paint, text).ok();
+    }
+    out.push_str(&source[end..]);
+    out
+}
@@ -1,6 +1,20 @@
-use std::{fs::File, io::Read};
+use std::{
+    cell::RefCell,
+    fmt, fs,
+    fs::File,
+    io::{self, IsTerminal, Read, Write},
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use compiler::Compiler;
+use chunk::{Closure, Function};
+use compiler::{Compiler, Session};
+use error::{Diagnostic, Severity};
+use scanner::Scanner;
+use token::{Token, TokenType};
+use optimizer::{OptLevel, PassManager};
+use vm::{VmError, VM};
 
 pub mod chunk;
 pub mod error;
@@ -10,21 +24,2121 @@ pub mod scanner;
 pub mod compiler;
 pub mod token;
 pub mod util;
+pub mod optimizer;
+pub mod natives;
+pub mod ast;
+pub mod debugger;
+pub mod formatter;
+pub mod profiler;
+pub mod highlight;
+#[cfg(feature = "nan-boxing")]
+pub mod nanbox;
+#[cfg(feature = "packed-code")]
+pub mod bytecode;
+#[cfg(feature = "serde")]
+pub mod serialize;
 
-pub fn repl() {}
+#[derive(Debug)]
+pub enum InterpretError {
+    Compile(Vec<Diagnostic>),
+    Runtime(String),
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::Compile(errors) => {
+                let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+            InterpretError::Runtime(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<VmError> for InterpretError {
+    fn from(error: VmError) -> Self {
+        match error {
+            // The verifier rejected the compiled code, there is no span to point at
+            VmError::CompileError(message) => InterpretError::Compile(vec![Diagnostic {
+                severity: Severity::Error,
+                message,
+                line: 0,
+                column: 0,
+                start: 0,
+                length: 0,
+            }]),
+            VmError::RuntimeError(message) => InterpretError::Runtime(message),
+            VmError::BudgetExceeded => InterpretError::Runtime(error::BUDGET_EXCEEDED.to_owned()),
+            VmError::Stopped => InterpretError::Runtime(error::DEBUGGER_STOPPED.to_owned()),
+        }
+    }
+}
+
+// Compiler and VM for embedding, globals persist from one interpret call to the next
+pub struct Interpreter {
+    pub vm: VM,
+    pub session: Rc<RefCell<Session>>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter {
+            vm: VM::new(),
+            session: Rc::new(RefCell::new(Session::default())),
+        }
+    }
+
+    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
+        let mut compiler = Compiler::with_session(source.to_owned(), self.session.clone());
+        let function = compiler.compile().map_err(InterpretError::Compile)?;
+        self.vm.interpret(Rc::new(Closure::new(Rc::new(function))))?;
+        Ok(())
+    }
+}
+
+// Run a script on a fresh interpreter
+pub fn interpret(source: &str) -> Result<(), InterpretError> {
+    Interpreter::new().interpret(source)
+}
+
+// What a script printed, and the report of the error that stopped it if any
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen(getter_with_clone))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunResult {
+    pub output: String,
+    pub error: Option<String>,
+}
+
+// Run a script with its output captured, the entry point of the browser playground
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+pub fn run(source: &str) -> RunResult {
+    let output = util::SharedBuffer::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.vm = VM::with_output(output.clone());
+    let error = match interpreter.interpret(source) {
+        Ok(()) => None,
+        Err(InterpretError::Compile(diagnostics)) => Some(
+            diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.render(source, false))
+                .collect(),
+        ),
+        Err(InterpretError::Runtime(message)) => {
+            let mut lines = vec![message];
+            lines.extend(interpreter.vm.backtrace());
+            Some(lines.join("\n"))
+        }
+    };
+    RunResult {
+        output: output.contents(),
+        error,
+    }
+}
+
+// Whether the input stops inside brackets, a string or a block comment, so more is coming
+pub fn is_incomplete(source: &str) -> bool {
+    let mut depth = 0;
+    for token in Scanner::new(source.to_owned()) {
+        match token.token_type {
+            TokenType::LeftBrace | TokenType::LeftParen | TokenType::LeftBracket => depth += 1,
+            TokenType::RightBrace | TokenType::RightParen | TokenType::RightBracket => depth -= 1,
+            TokenType::Error => {
+                let lexeme = &*token.lexeme;
+                if lexeme == error::UNTERMINATED_STRING || lexeme == error::UNTERMINATED_COMMENT {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+// The tokens of a source for highlighters and other tools, without compiling it. Start
+// and length locate each one in the source, lines count from 0 as in the compiler.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    Scanner::new(source.to_owned()).into_iter().collect()
+}
+
+// Instructions a checked script may run before it counts as fine
+pub const CHECK_FUEL: u64 = 10_000;
+
+// Scan and compile untrusted source, then run it briefly on a sandboxed VM, reporting
+// what went wrong instead of panicking. The target of the fuzzer in fuzz/.
+pub fn check(source: &str) -> Result<(), Vec<Diagnostic>> {
+    check_with_fuel(source, CHECK_FUEL)
+}
+
+// Like check, a fuel of 0 only compiles
+pub fn check_with_fuel(source: &str, fuel: u64) -> Result<(), Vec<Diagnostic>> {
+    let function = Compiler::new(source.to_owned()).compile()?;
+    if fuel == 0 {
+        return Ok(());
+    }
+    let mut vm = VM::sandboxed();
+    vm.output = Box::new(io::sink());
+    vm.input = Some(Box::new(io::Cursor::new(Vec::new())));
+    vm.max_heap_bytes = Some(1 << 20);
+    let message = match vm.interpret_with_budget(Rc::new(Closure::new(Rc::new(function))), fuel) {
+        Ok(()) | Err(VmError::BudgetExceeded) | Err(VmError::Stopped) => return Ok(()),
+        Err(VmError::CompileError(message)) | Err(VmError::RuntimeError(message)) => message,
+    };
+    let line = vm
+        .frames
+        .last()
+        .and_then(|frame| frame.closure.function.chunk.line_at_ip(frame.ip))
+        .map_or(0, |line| line + 1);
+    Err(vec![Diagnostic {
+        severity: Severity::Error,
+        message,
+        line,
+        column: 0,
+        start: 0,
+        length: 0,
+    }])
+}
+
+pub fn repl() {
+    // One VM and one session for the whole loop, so each line sees what earlier ones defined
+    let mut vm = VM::new();
+    let session = Rc::new(RefCell::new(Session::default()));
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "..> " });
+        io::stdout().flush().ok();
+        match io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => {
+                println!();
+                break;
+            }
+            Ok(_) => {}
+        }
+        if is_incomplete(&input) {
+            continue;
+        }
+        let source = std::mem::take(&mut input);
+        let mut compiler = Compiler::with_session(source.clone(), session.clone());
+        compiler.repl = true;
+        // Only the functions a line calls are compiled in full
+        compiler.lazy = true;
+        match compiler.compile() {
+            Ok(function) => {
+                report(&source, &compiler.diagnostics);
+                execute(&mut vm, Closure::new(Rc::new(function)))
+            }
+            Err(diagnostics) => report(&source, &diagnostics),
+        }
+    }
+}
+
+// Command line switches shared by run and watch
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub strict: bool,
+    pub opt_level: OptLevel,
+    // Print what the optimizer did and the resulting code instead of running it
+    pub emit_optimized: bool,
+    // Report how long compiling and running took once the script finishes
+    pub time: bool,
+    // Print every chunk next to the source it came from instead of running it
+    pub disassemble: bool,
+    // Print the stack and each instruction as the script runs
+    pub trace: bool,
+    // Report calls, instructions and time per function once the script ends
+    pub profile: bool,
+    // Print the parse tree instead of running the script
+    pub dump_ast: bool,
+    // Leave out the natives that touch the file system
+    pub sandbox: bool,
+    // Make assert() do nothing, for production runs
+    pub skip_asserts: bool,
+    // Compile top-level function bodies on their first call
+    pub lazy: bool,
+}
+
+// Peak resident memory of the process in kB, where the platform reports it
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn report_time(compile: Duration, execute: Duration, vm: &VM) {
+    eprintln!("== Time ==");
+    eprintln!("compile       {:?}", compile);
+    eprintln!("execute       {:?}", execute);
+    match peak_memory() {
+        Some(kb) => eprintln!("peak memory   {} kB", kb),
+        None => eprintln!("peak memory   unavailable"),
+    }
+    if let Some(count) = vm.instruction_count {
+        eprintln!("instructions  {}", count);
+    }
+}
+
+fn new_vm(filename: &str, options: &Options) -> VM {
+    let mut vm = if options.sandbox { VM::sandboxed() } else { VM::new() };
+    vm.trace_execution = options.trace;
+    vm.source_name = Some(filename.to_owned());
+    if options.skip_asserts {
+        vm.define_native("assert", 2, natives::skip_assert);
+    }
+    vm
+}
+
+fn compiler(source: String, vm: &VM, options: &Options) -> Compiler {
+    let mut compiler = Compiler::new(source);
+    compiler.strict = options.strict;
+    compiler.lazy = options.lazy;
+    options.opt_level.configure(&mut compiler);
+    for (name, _) in vm.iter_globals() {
+        compiler.declare_global(name);
+    }
+    compiler
+}
 
-pub fn run_file(filename: &String) {
-    let mut file = File::open(filename).expect(format!("Could not open file {}\n", filename).as_str());
+// Print every diagnostic of a failed compile against the source it came from
+fn report(source: &str, diagnostics: &[Diagnostic]) {
+    let color = io::stderr().is_terminal();
+    for diagnostic in diagnostics {
+        eprint!("{}", diagnostic.render(source, color));
+    }
+}
+
+fn optimize(function: Function, compiler: &Compiler, options: &Options) -> Closure {
+    let mut report = compiler.rewrites.clone();
+    let function = PassManager::for_level(options.opt_level).run(&function, &mut report);
+    if options.emit_optimized {
+        for (name, pass, changed) in report.iter() {
+            println!("{} {}: {} instructions changed", name, pass, changed);
+        }
+        let name = format!("{} {:?}", function, options.opt_level);
+        function.chunk.disassemble(&mut io::stdout(), &name).ok();
+    }
+    Closure::new(Rc::new(function))
+}
+
+fn read_file(filename: &str) -> Option<String> {
+    let mut file = File::open(filename).ok()?;
     let mut buf = String::new();
-    file.read_to_string(&mut buf).expect("Could not read file");
-    let compiler = Compiler::new(buf);
-    compiler.builder.chunk.disassemble("Code");
+    file.read_to_string(&mut buf).ok()?;
+    Some(buf)
+}
+
+fn execute(vm: &mut VM, closure: chunk::Closure) {
+    match vm.interpret(Rc::new(closure)) {
+        Ok(()) => {}
+        Err(VmError::CompileError(message)) => eprintln!("{}", message),
+        Err(VmError::RuntimeError(message)) => {
+            eprintln!("{}", message);
+            for line in vm.backtrace() {
+                eprintln!("{}", line);
+            }
+        }
+        Err(VmError::BudgetExceeded) => eprintln!("{}", error::BUDGET_EXCEEDED),
+        Err(VmError::Stopped) => eprintln!("{}", error::DEBUGGER_STOPPED),
+    }
+}
+
+pub fn run_file(filename: &String, options: &Options) {
+    let buf = read_file(filename).unwrap_or_else(|| panic!("Could not read file {}\n", filename));
+    if options.dump_ast {
+        match ast::parse(&buf) {
+            Ok(statements) => print!("{}", ast::dump(&statements)),
+            Err(diagnostics) => report(&buf, &diagnostics),
+        }
+        return;
+    }
+    let mut vm = new_vm(filename, options);
+    if options.time {
+        vm.instruction_count = Some(0);
+    }
+    if options.profile {
+        vm.profile = Some(profiler::Profile::default());
+    }
+    let start = Instant::now();
+    let mut compiler = compiler(buf.clone(), &vm, options);
+    match compiler.compile() {
+        Ok(function) => {
+            // Warnings do not stop the script
+            report(&buf, &compiler.diagnostics);
+            let closure = optimize(function, &compiler, options);
+            let compiled = Instant::now();
+            if options.disassemble {
+                let function = &closure.function;
+                let color = io::stdout().is_terminal();
+                function
+                    .chunk
+                    .disassemble_with_source(&mut io::stdout(), &function.to_string(), &buf, color)
+                    .ok();
+            } else if !options.emit_optimized {
+                execute(&mut vm, closure);
+            }
+            if options.time {
+                report_time(compiled - start, compiled.elapsed(), &vm);
+            }
+            if let Some(profile) = vm.profile.as_mut() {
+                profile.report(&mut io::stderr()).ok();
+            }
+        }
+        Err(diagnostics) => report(&buf, &diagnostics),
+    }
+}
+
+// Run a script under the line debugger, pausing before its first line
+pub fn debug_file(filename: &String, options: &Options) {
+    let buf = read_file(filename).unwrap_or_else(|| panic!("Could not read file {}\n", filename));
+    let mut vm = new_vm(filename, options);
+    let mut compiler = compiler(buf.clone(), &vm, options);
+    match compiler.compile() {
+        Ok(function) => {
+            report(&buf, &compiler.diagnostics);
+            let source = buf.clone();
+            let mut session = debugger::DebugSession::new(move |pause: &mut debugger::Pause| {
+                debugger::prompt(pause, &source)
+            });
+            session.stepping = true;
+            vm.debugger = Some(Box::new(session));
+            execute(&mut vm, optimize(function, &compiler, options));
+        }
+        Err(diagnostics) => report(&buf, &diagnostics),
+    }
+}
+
+// Rewrite a file in canonical layout, or with check only say whether it would change.
+// False when the file does not parse, could not be written or fails the check.
+pub fn format_file(filename: &String, check: bool) -> bool {
+    let buf = read_file(filename).unwrap_or_else(|| panic!("Could not read file {}\n", filename));
+    let formatted = match formatter::format(&buf) {
+        Ok(formatted) => formatted,
+        Err(diagnostics) => {
+            report(&buf, &diagnostics);
+            return false;
+        }
+    };
+    if formatted == buf {
+        return true;
+    }
+    if check {
+        eprintln!("{} is not formatted", filename);
+        return false;
+    }
+    match fs::write(filename, formatted) {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("Could not write {}: {}", filename, error);
+            false
+        }
+    }
+}
+
+// Print the file with its tokens and comments colored, or their spans as JSON
+pub fn highlight_file(filename: &String, json: bool) {
+    let buf = read_file(filename).unwrap_or_else(|| panic!("Could not read file {}\n", filename));
+    let spans = highlight::highlight(&buf);
+    if json {
+        print!("{}", highlight::to_json(&spans));
+    } else {
+        print!("{}", highlight::to_ansi(&buf, &spans));
+    }
+}
+
+pub fn watch_file(filename: &String, options: &Options) {
+    let mut vm = new_vm(filename, options);
+    let mut previous: Option<chunk::Chunk> = None;
+    let mut modified = None;
+    loop {
+        let current = fs::metadata(filename).and_then(|m| m.modified()).ok();
+        if current.is_some() && current != modified {
+            modified = current;
+            if let Some(buf) = read_file(filename) {
+                let mut compiler = compiler(buf.clone(), &vm, options);
+                match compiler.compile() {
+                    Err(diagnostics) => report(&buf, &diagnostics),
+                    Ok(function) => {
+                        report(&buf, &compiler.diagnostics);
+                        let closure = optimize(function, &compiler, options);
+                        let chunk = closure.function.chunk.clone();
+                        match &previous {
+                            // Only function bodies changed, keep the program state
+                            Some(old) if old.same_shape(&chunk) => {
+                                match vm.swap_functions(&chunk) {
+                                    Ok(()) => println!("== Reloaded functions in {} ==", filename),
+                                    Err(message) => eprintln!("{}", message),
+                                }
+                            }
+                            _ => {
+                                println!("== Running {} ==", filename);
+                                vm = new_vm(filename, options);
+                                execute(&mut vm, closure);
+                            }
+                        }
+                        previous = Some(chunk);
+                    }
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        convert::TryFrom,
+        rc::Rc,
+    };
+
+    use crate::{
+        chunk::{Chunk, Closure, Function, LoxString, NativeError, NativeResult, Value},
+        compiler::{Compiler, Session},
+        op_code::OpCode,
+        optimizer::{OptLevel, PassManager},
+        token::TokenType,
+        util::SharedBuffer,
+        vm::{VmError, VM},
+        InterpretError, Interpreter,
+    };
+
+    fn compile(compiler: &mut Compiler) -> Closure {
+        Closure::new(Rc::new(compiler.compile().unwrap()))
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn lazy_function_compiles_on_first_call() {
+        let source = "fun answer() { return 40 + 2; } var result = answer();".to_owned();
+        let options = super::Options { lazy: true, ..Default::default() };
+        let closure = compile(&mut super::compiler(source, &VM::new(), &options));
+
+        let stub = closure.function.chunk.values.iter().find_map(|value| match value {
+            Value::Function(function) => Some(function.clone()),
+            _ => None,
+        });
+        let stub = stub.unwrap();
+        assert!(stub.chunk.codes.is_empty());
+
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("result"), Some(Value::Double(42.0)));
+        assert!(stub.lazy.as_ref().unwrap().compiled.borrow().is_some());
+    }
+
+    #[test]
+    fn compiler_never_panics_on_malformed_input() {
+        let fragments = [
+            "(", ")", "{", "}", "+", "!=", "=", ";", ",", ".", "fun", "var", "return", "and",
+            "or", "if", "else", "while", "for", "print", "a", "1", "\"s", "\"", "é", "//", "\n",
+        ];
+        let mut seed: u32 = 7;
+        for _ in 0..500 {
+            let mut source = String::new();
+            for _ in 0..12 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                source.push_str(fragments[(seed >> 16) as usize % fragments.len()]);
+                source.push(' ');
+            }
+            let _ = Compiler::new(source.clone()).compile();
+            let mut compiler = Compiler::new(source);
+            compiler.lazy = true;
+            let _ = compiler.compile();
+        }
+    }
+
+    #[test]
+    fn constant_global_initializer_is_folded() {
+        let mut compiler = Compiler::new("var SIZE = 8 * 1024; var ok = 2 != 3;".to_owned());
+        let closure = compile(&mut compiler);
+        let chunk = &closure.function.chunk;
+        assert_eq!(
+            chunk.codes,
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpDefineGlobal(1),
+                OpCode::OpConstant(2),
+                OpCode::OpDefineGlobal(3),
+            ]
+        );
+        assert_eq!(chunk.values[0], Value::Double(8192.0));
+        assert_eq!(chunk.values[2], Value::Bool(true));
+    }
+
+    #[test]
+    fn constant_expressions_fold_unless_disabled() {
+        let source = "fun f() { return 1 + 2 * 3 >= -(4 - 6) != !true; } var result = f();";
+        let body = |closure: &Closure| match &closure.function.chunk.values[0] {
+            Value::Function(function) => function.chunk.codes.clone(),
+            _ => panic!("expected f first"),
+        };
+
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        assert_eq!(body(&closure)[..2], [OpCode::OpConstant(0), OpCode::OpReturn]);
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("result"), Some(Value::Bool(true)));
+
+        let mut compiler = Compiler::new(source.to_owned());
+        OptLevel::O0.configure(&mut compiler);
+        let closure = compile(&mut compiler);
+        assert!(body(&closure).contains(&OpCode::OpMultiply));
+    }
+
+    #[test]
+    fn small_function_call_is_inlined() {
+        let source = "fun answer() { if (nil) return 1; return 40 + 2; } var result = 1 + answer();";
+        let mut compiler = Compiler::new(source.to_owned());
+        compiler.inline = true;
+        let closure = compile(&mut compiler);
+        let chunk = &closure.function.chunk;
+        assert!(!chunk.codes.iter().any(|code| matches!(code, OpCode::OpCall(_))));
+        assert!(chunk.verify_stack(1).is_ok());
+
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("result"), Some(Value::Double(43.0)));
+    }
+
+    #[test]
+    fn reassigned_functions_are_not_inlined() {
+        let run = |source: &str, level: OptLevel| {
+            let mut compiler = Compiler::new(source.to_owned());
+            level.configure(&mut compiler);
+            let closure = compile(&mut compiler);
+            let output = SharedBuffer::default();
+            let result = VM::with_output(output.clone()).interpret(Rc::new(closure));
+            let error = match result {
+                Err(VmError::RuntimeError(message)) => Some(message),
+                _ => None,
+            };
+            (output.contents(), error)
+        };
+        for source in [
+            "fun g(x) { return x * 2; } g = nil; print g(3);",
+            "fun g(x) { return x * 2; } fun h() { return g(3); } print h(); g = nil; print h();",
+            "fun d() { return 1; } fun e() { return d(); } fun d() { return 2; } print e();",
+        ] {
+            assert_eq!(run(source, OptLevel::O2), run(source, OptLevel::O0), "{}", source);
+        }
+    }
+
+    #[test]
+    fn host_globals_are_visible_from_first_statement() {
+        let mut compiler = Compiler::new("var area = width * height;".to_owned());
+        let closure = compile(&mut compiler);
+
+        let mut vm = VM::new();
+        vm.set_global("width", Value::Double(3.0));
+        vm.define_globals(
+            vec![("height".to_owned(), Value::Double(4.0))]
+                .into_iter()
+                .collect(),
+        );
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("area").and_then(|v| v.as_number()), Some(12.0));
+    }
+
+    #[test]
+    fn hot_add_is_quickened_and_deoptimized() {
+        let source = "fun add(a, b) { return a + b; } var i = 1; while (i < 100) { i = add(i, 1); }";
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(compile(&mut Compiler::new(source.to_owned())))).is_ok());
+        let function = match vm.get_global("add") {
+            Some(Value::Closure(closure)) => closure.function.clone(),
+            _ => panic!("add is not a closure"),
+        };
+        let site = function.chunk.codes.iter().position(|code| *code == OpCode::OpAdd).unwrap();
+        assert_eq!(function.chunk.code_at(site), OpCode::OpAddNumber);
+
+        let source = "var joined = add(\"a\", \"b\");";
+        assert!(vm.interpret(Rc::new(compile(&mut Compiler::new(source.to_owned())))).is_ok());
+        assert_eq!(function.chunk.code_at(site), OpCode::OpAdd);
+        assert_eq!(vm.get_global("joined").as_ref().and_then(|v| v.as_str()), Some("ab"));
+    }
+
+    #[test]
+    fn inner_block_shadows_outer_local() {
+        let source = "var inner; var outer; { var a = 1; { var a = 2; inner = a; } outer = a; }";
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(compile(&mut Compiler::new(source.to_owned())))).is_ok());
+        assert_eq!(vm.get_global("inner"), Some(Value::Double(2.0)));
+        assert_eq!(vm.get_global("outer"), Some(Value::Double(1.0)));
+
+        // A shadowing local can start from the one it hides, which comes back afterwards
+        let source = "var a = \"global\";
+            { var a = \"outer\"; { var b = a + \"!\"; var a = b; print a; { var a = 3; print a; }
+            print a; } print a; } print a;";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "outer!\n3\nouter!\nouter\nglobal\n");
+    }
+
+    #[test]
+    fn locals_resolve_to_their_own_slots() {
+        // Counting from the wrong end swapped a and b, and reading one borrowed the stack twice
+        let source = "fun f(a, b) { var c = a - b; return c * b; } print f(10, 3);";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "21\n");
+    }
+
+    #[test]
+    fn redeclaring_local_in_same_scope_is_an_error() {
+        for source in &["{ var a = 1; var a = 2; }", "fun f(a) { var a = 1; }"] {
+            let mut compiler = Compiler::new((*source).to_owned());
+            let diagnostics = compiler.compile().unwrap_err();
+            assert_eq!(diagnostics.len(), 1, "{}", source);
+            assert_eq!(diagnostics[0].message, "Already variable with this name in this scope");
+        }
+    }
+
+    #[test]
+    fn strict_mode_rejects_undeclared_globals() {
+        let source = "fun area() { return width * heigth; } var width = 2; print area();";
+        let mut compiler = Compiler::new(source.to_owned());
+        compiler.strict = true;
+        compiler.declare_global("clock");
+        assert_eq!(compiler.compile().unwrap_err().len(), 1);
+
+        let source = "fun area() { return width * clock; } var width = 2; print area();";
+        let mut compiler = Compiler::new(source.to_owned());
+        compiler.strict = true;
+        compiler.declare_global("clock");
+        assert!(compiler.compile().is_ok());
+    }
+
+    #[test]
+    fn interned_strings_carry_their_hash() {
+        let mut session = Session::default();
+        let first = session.intern("width");
+        let second = session.intern("width");
+        assert!(Rc::ptr_eq(&first, &second));
+        assert_eq!(first.hash, LoxString::from("width").hash);
+
+        let mut vm = VM::new();
+        let defaults = vm.globals.len();
+        vm.set_global("width", Value::Double(3.0));
+        vm.globals.insert(first, Value::Double(4.0));
+        assert_eq!(vm.globals.len(), defaults + 1);
+        assert_eq!(vm.get_global("width"), Some(Value::Double(4.0)));
+    }
+
+    #[test]
+    fn native_errors_become_runtime_errors() {
+        fn fail(_: &mut VM, _: &[Value]) -> NativeResult {
+            Err(NativeError("device not ready".to_owned()))
+        }
+        let mut vm = VM::new();
+        vm.define_native("draw", 0, fail);
+        let closure = compile(&mut Compiler::new("draw();".to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => assert_eq!(message, "draw: device not ready"),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn unbound_externs_are_reported_before_running() {
+        fn draw(_: &mut VM, _: &[Value]) -> NativeResult {
+            Ok(Value::Nil)
+        }
+        let source = "var ran = true; extern fun draw(x, y); extern fun beep(); draw(1, 2);";
+        let mut vm = VM::new();
+        vm.define_native("draw", 2, draw);
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => assert_eq!(message, "Unbound extern: beep"),
+            _ => panic!("expected an unbound extern error"),
+        }
+        assert_eq!(vm.get_global("ran"), None);
+
+        let closure = compile(&mut Compiler::new("var ran = true; extern fun draw(x);".to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => {
+                assert_eq!(message, "Extern arity mismatch: draw declares 1 but the native takes 2")
+            }
+            _ => panic!("expected an arity mismatch"),
+        }
+        assert_eq!(vm.get_global("ran"), None);
+    }
+
+    #[test]
+    fn jump_threading_skips_jumps_to_jumps() {
+        let source = "var n = 1; if (n > 2) { if (n > 3) { n = 4; } else { n = 5; } } else { n = 6; }";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        let mut report = vec![];
+        let function = PassManager::for_level(OptLevel::O1).run(&closure.function, &mut report);
+        assert!(report.contains(&("<script>".to_owned(), "jump-threading", 1)));
+        let codes = &function.chunk.codes;
+        for (index, code) in codes.iter().enumerate() {
+            if let OpCode::OpJump(offset) = code {
+                assert!(!matches!(codes.get(index + offset), Some(OpCode::OpJump(_))));
+            }
+        }
+
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(Closure::new(Rc::new(function)))).is_ok());
+        assert_eq!(vm.get_global("n"), Some(Value::Double(6.0)));
+    }
+
+    #[test]
+    fn peephole_rewrites_negated_and_constant_conditions_and_pop_runs() {
+        let source = "var n = 0; while (!(n > 2)) n = n + 1;\n\
+                      if (false) n = -1; else n = n * 10;\n\
+                      if (true) { var a = 1; var b = 2; n = n + a + b; }";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        let mut report = vec![];
+        let function = PassManager::for_level(OptLevel::O1).run(&closure.function, &mut report);
+        let codes = &function.chunk.codes;
+        assert!(!codes.iter().any(|code| matches!(code, OpCode::OpNot | OpCode::OpTrue | OpCode::OpFalse)));
+        assert!(codes.iter().any(|code| matches!(code, OpCode::OpJumpIfTrue(_))));
+        assert!(codes.contains(&OpCode::OpPopN(3)));
+        assert_eq!(function.chunk.line_at(codes.len() - 1), Some(2));
+        assert!(function.chunk.verify_stack(1).is_ok());
+
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(Closure::new(Rc::new(function)))).is_ok());
+        assert_eq!(vm.get_global("n"), Some(Value::Int(33)));
+    }
+
+    #[test]
+    fn compile_time_optimizations_are_reported() {
+        let source = "fun two() { return 2; } fun f() { return 1; print 3; } print 1 + 2 * 3 + two();";
+        let mut compiler = Compiler::new(source.to_owned());
+        OptLevel::O2.configure(&mut compiler);
+        compile(&mut compiler);
+        assert_eq!(
+            compiler.rewrites,
+            vec![
+                ("<fn f/0>".to_owned(), "dead-code", 2),
+                ("<script>".to_owned(), "constant-folding", 6),
+                ("<script>".to_owned(), "inlining", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn constants_past_256_use_the_long_form() {
+        let mut source: String = (0..300).map(|i| format!("{}.5;\n", i)).collect();
+        source.push_str("var last = 299.5;");
+        let closure = compile(&mut Compiler::new(source));
+        let codes = &closure.function.chunk.codes;
+        assert_eq!(codes[0], OpCode::OpConstant(0));
+        assert_eq!(codes[2 * 256], OpCode::OpConstantLong(256));
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("last"), Some(Value::Double(299.5)));
+
+        let diagnostics = Compiler::new("0;".repeat(crate::chunk::MAX_CONSTANTS + 10))
+            .compile()
+            .unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![crate::error::TOO_MANY_CONSTANTS]);
+
+        // Names fill the pool too, so the literal after them is the one that overflows
+        let mut source: String = (1..crate::chunk::MAX_CONSTANTS).map(|i| format!("{};", i)).collect();
+        source.push_str("var aa = 1; var bb = 2; print aa + bb; print \"hello\";");
+        let diagnostics = Compiler::new(source).compile().unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![crate::error::TOO_MANY_CONSTANTS]);
+    }
+
+    #[test]
+    fn string_literals_are_joined_at_compile_time() {
+        let source = "fun greet() { return \"Hello, \" \"world\" + \"!\"; } var message = greet();";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        let function = match &closure.function.chunk.values[0] {
+            Value::Function(function) => function.clone(),
+            _ => panic!("expected greet first"),
+        };
+        assert_eq!(function.chunk.codes[0], OpCode::OpConstant(0));
+        assert_eq!(function.chunk.values.len(), 1);
+        assert_eq!(function.chunk.values[0].as_str(), Some("Hello, world!"));
+
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("message").as_ref().and_then(|v| v.as_str()), Some("Hello, world!"));
+    }
+
+    #[test]
+    fn repl_lines_share_state_and_echo_expressions() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(output.clone());
+        let session = Rc::new(RefCell::new(Session::default()));
+        for line in &["var a = 20;", "a = a + 1;", "a * 2", "\"a\" + \"b\";", "[a]"] {
+            let mut compiler = Compiler::with_session((*line).to_owned(), session.clone());
+            compiler.repl = true;
+            let closure = compile(&mut compiler);
+            if !line.starts_with("var") {
+                assert_eq!(closure.function.chunk.codes.last(), Some(&OpCode::OpEcho));
+            }
+            assert!(vm.interpret(Rc::new(closure)).is_ok());
+        }
+        assert_eq!(vm.get_global("a"), Some(Value::Double(21.0)));
+        // Echoed the way values are inspected, so strings keep their quotes
+        assert_eq!(output.contents(), "21\n42\n\"ab\"\n[\n  21\n]\n");
+    }
+
+    #[test]
+    fn repl_session_shares_names_and_definitions() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(output.clone());
+        let session = Rc::new(RefCell::new(Session::default()));
+        let lines = [
+            "fun greet(name) { return \"hi \" + name; }",
+            "class Box { get() { return \"hi \"; } }",
+            "print greet(\"ann\"); print Box().get() == \"hi \";",
+        ];
+        let mut closures = vec![];
+        for line in lines {
+            let mut compiler = Compiler::with_session(line.to_owned(), session.clone());
+            compiler.repl = true;
+            let closure = Rc::new(compile(&mut compiler));
+            assert!(vm.interpret(closure.clone()).is_ok());
+            closures.push(closure);
+        }
+        assert_eq!(output.contents(), "hi ann\ntrue\n");
+        let declared = ["greet", "Box"].map(|name| session.borrow_mut().symbol(name));
+        assert!(declared.iter().all(|symbol| session.borrow().globals.contains(symbol)));
+
+        // Every line got the same interned string for a name or literal it shares with another
+        let string = |closure: &Closure, text: &str| {
+            closure.function.chunk.values.iter().find_map(|value| match value {
+                Value::String(string) if string.chars == text => Some(string.clone()),
+                _ => None,
+            })
+        };
+        let greet = string(&closures[0], "greet").unwrap();
+        assert!(Rc::ptr_eq(&greet, &string(&closures[2], "greet").unwrap()));
+        let class = string(&closures[1], "Box").unwrap();
+        assert!(Rc::ptr_eq(&class, &string(&closures[2], "Box").unwrap()));
+    }
+
+    #[test]
+    fn repl_waits_for_unbalanced_input() {
+        for open in ["fun foo() {", "print (1 +", "var a = [1,\n 2", "var s = \"a", "/* note"] {
+            assert!(crate::is_incomplete(open), "{}", open);
+        }
+        for done in ["fun foo() {\n return 1;\n}", "print (1 + 2);", "}", "var s = \"{\";"] {
+            assert!(!crate::is_incomplete(done), "{}", done);
+        }
+    }
+
+    #[test]
+    fn classes_are_instantiated_by_calling_them() {
+        let mut compiler = Compiler::new(
+            "class Foo {} var a = Foo(); var b = Foo(); var same = a == a; var other = a == b;"
+                .to_owned(),
+        );
+        let closure = compile(&mut compiler);
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("a").map(|a| a.to_string()), Some("Foo instance".to_owned()));
+        assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("other"), Some(Value::Bool(false)));
+    }
+
+    #[test]
+    fn method_calls_compile_to_one_invoke() {
+        let source = "class A { m(x) { return x; } } var a = A(); var r = a.m(7); var f = a.m;";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        let codes = &closure.function.chunk.codes;
+        assert!(codes.iter().any(|code| matches!(code, OpCode::OpInvoke(_, 1))));
+        assert_eq!(codes.iter().filter(|code| matches!(code, OpCode::OpGetProperty(_))).count(), 1);
+        // The only plain call is the one making the instance
+        assert_eq!(codes.iter().filter(|code| matches!(code, OpCode::OpCall(_))).count(), 1);
+
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("r"), Some(Value::Int(7)));
+        assert!(matches!(vm.get_global("f"), Some(Value::BoundMethod(_))));
+    }
+
+    #[test]
+    fn methods_see_their_receiver_as_this() {
+        let source = "class Counter { me() { return this; } add(a, b) { return a + b; } }
+            var c = Counter(); var same = c.me() == c; var sum = c.add(1, 2);";
+        let mut compiler = Compiler::new(source.to_owned());
+        let closure = compile(&mut compiler);
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("sum"), Some(Value::Double(3.0)));
+
+        let mut compiler = Compiler::new("fun f() { return this; }".to_owned());
+        assert_eq!(compiler.compile().unwrap_err().len(), 1);
+    }
+
+    #[test]
+    fn natives_receive_their_arguments() {
+        fn sub(_: &mut VM, args: &[Value]) -> NativeResult {
+            match (args[0].as_number(), args[1].as_number()) {
+                (Some(left), Some(right)) => Ok(Value::Double(left - right)),
+                _ => Err(NativeError("expected numbers".to_owned())),
+            }
+        }
+        let mut vm = VM::new();
+        vm.define_native("sub", 2, sub);
+        let closure = compile(&mut Compiler::new("var result = sub(10, 4);".to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("result"), Some(Value::Double(6.0)));
+
+        let closure = compile(&mut Compiler::new("sub(1);".to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => {
+                assert_eq!(message, "Expected 2 arguments but got 1")
+            }
+            _ => panic!("expected an arity error"),
+        }
+    }
+
+    #[test]
+    fn closures_are_registered_as_natives() {
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let mut vm = VM::new();
+        vm.define_native("scale", 2, move |vm: &mut VM, args: &[Value]| {
+            counter.set(counter.get() + 1);
+            let factor = f64::try_from(&args[0])?;
+            let label = String::try_from(&args[1])?;
+            vm.set_global("label", Value::from(label + "!"));
+            Ok(Value::from(factor * 2.0))
+        });
+        let closure = compile(&mut Compiler::new("var a = scale(4, \"x\");".to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("a"), Some(Value::from(8.0)));
+        assert_eq!(vm.get_global("label"), Some(Value::from("x!")));
+
+        let closure = compile(&mut Compiler::new("scale(\"4\", 1);".to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => {
+                assert_eq!(message, "scale: expected a number but got 4")
+            }
+            _ => panic!("expected a conversion error"),
+        }
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn functions_are_verified_before_they_run() {
+        // Pops the caller's slot away, which an unchecked build would read out of bounds
+        let mut chunk = Chunk::new();
+        for code in [OpCode::OpPop, OpCode::OpPop, OpCode::OpNil, OpCode::OpReturn] {
+            chunk.codes.push(code);
+            chunk.push_line(1);
+        }
+        let bad = Function::new(0, chunk, "bad".to_owned(), vec![]);
+        let mut vm = VM::new();
+        vm.set_global("bad", Value::Closure(Rc::new(Closure::new(Rc::new(bad)))));
+        let closure = compile(&mut Compiler::new("bad();".to_owned()));
+        assert!(matches!(vm.interpret(Rc::new(closure)), Err(VmError::CompileError(_))));
+
+        let inner = Rc::new(compile(&mut Compiler::new("print 1;".to_owned())));
+        vm.define_native("nested", 0, move |vm: &mut VM, _: &[Value]| {
+            vm.interpret(inner.clone())?;
+            Ok(Value::Nil)
+        });
+        let closure = compile(&mut Compiler::new("nested();".to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => assert!(message.contains("already running")),
+            _ => panic!("expected a nested interpret to fail"),
+        }
+    }
+
+    #[test]
+    fn runtime_errors_leave_a_backtrace() {
+        let source = "fun inner(x) {\n  return x + nil;\n}\nfun outer() {\n  return inner(1) + 1;\n}\nouter();";
+        let mut vm = VM::new();
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_err());
+        assert_eq!(
+            vm.backtrace(),
+            vec!["[line 2] in inner()", "[line 5] in outer()", "[line 7] in script"]
+        );
+    }
+
+    #[test]
+    fn ternary_picks_a_branch_and_chains_to_the_right() {
+        let source = "var n = 2; var a = n > 1 ? \"big\" : \"small\";
+            var b = n < 1 ? 1 : n < 3 ? 2 : 3; var c = false ? 1 : nil;";
+        let mut compiler = Compiler::new(source.to_owned());
+        let closure = compile(&mut compiler);
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("a").as_ref().and_then(Value::as_str), Some("big"));
+        assert_eq!(vm.get_global("b"), Some(Value::Double(2.0)));
+        assert_eq!(vm.get_global("c"), Some(Value::Nil));
+    }
+
+    #[test]
+    fn string_escapes_are_decoded() {
+        let source = r#"var s = "a\tb\n\"q\" \\ \u{e9}\u{1F600}";"#;
+        let mut compiler = Compiler::new(source.to_owned());
+        let closure = compile(&mut compiler);
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        let s = vm.get_global("s");
+        assert_eq!(s.as_ref().and_then(Value::as_str), Some("a\tb\n\"q\" \\ é😀"));
+
+        for bad in [r#"var s = "\q";"#, r#"var s = "\u{41";"#, r#"var s = "\u{110000}";"#] {
+            let mut compiler = Compiler::new(bad.to_owned());
+            assert!(compiler.compile().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn block_comments_nest_and_count_lines() {
+        let source = "/* outer /* inner\n */ still comment\n*/ var a = 1; /**/ var b =\n 2;";
+        let mut compiler = Compiler::new(source.to_owned());
+        let closure = compile(&mut compiler);
+        assert_eq!(closure.function.chunk.line_at(0), Some(2));
+
+        let mut compiler = Compiler::new("var a = 1; /* /* */".to_owned());
+        assert!(compiler.compile().is_err());
+    }
+
+    #[test]
+    fn expression_statements_pop_their_value() {
+        let source = "var a = 1; while (a < 3) { a + 1; a = a + 1; } fun f() { a * 2; }";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        let codes = &closure.function.chunk.codes;
+        let add = codes.iter().position(|code| *code == OpCode::OpAdd).unwrap();
+        assert_eq!(codes[add + 1], OpCode::OpPop);
+        // The loop would reach its condition one value deeper on every iteration
+        assert!(closure.function.chunk.verify_stack(1).is_ok());
+    }
+
+    #[test]
+    fn functions_print_short_and_disassemble_in_full() {
+        let source = "fun outer(a) { fun inner() { return a; } return inner; } print outer;";
+        let closure = Rc::new(compile(&mut Compiler::new(source.to_owned())));
+        let output = SharedBuffer::default();
+        assert!(VM::with_output(output.clone()).interpret(closure.clone()).is_ok());
+        assert_eq!(output.contents(), "<fn outer/1>\n");
+
+        let mut listing = vec![];
+        closure.function.chunk.disassemble(&mut listing, "script").unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        // Nested chunks are listed after their parent, with what they capture
+        let headers: Vec<&str> = listing.lines().filter(|line| line.starts_with("==")).collect();
+        assert_eq!(
+            headers,
+            vec!["== script ==", "== <fn outer/1> upvalues: 0 ==", "== <fn inner/0> upvalues: 1 =="]
+        );
+        assert!(listing.contains("OpGetUpValue"));
+    }
+
+    #[test]
+    fn disassembly_interleaves_source_lines() {
+        let source = "var a = 1;\nprint a;\nfun f() {\n  return a;\n}";
+        let mut compiler = Compiler::new(source.to_owned());
+        OptLevel::O0.configure(&mut compiler);
+        let chunk = compile(&mut compiler).function.chunk.clone();
+        let listing = |color: bool| {
+            let mut out = vec![];
+            chunk.disassemble_with_source(&mut out, "<script>", source, color).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let plain = listing(false);
+        assert!(plain.starts_with(
+            "== <script> ==\n\n   0 | var a = 1;\n       0000  OpConstant       0 '1'\n\
+             \x20      0001  OpDefineGlobal   1 'a'\n   1 | print a;\n"
+        ));
+        // A function's listing quotes the lines of its body
+        assert!(plain.contains("== <fn f/0> ==\n\n   3 | return a;\n"));
+        assert!(!plain.contains('\x1b'));
+        assert!(listing(true).contains("\x1b[2m   1 | print a;\x1b[0m"));
+    }
+
+    #[test]
+    fn instructions_are_counted_when_asked() {
+        let run = |source: &str, count: Option<u64>| {
+            let mut compiler = Compiler::new(source.to_owned());
+            OptLevel::O0.configure(&mut compiler);
+            let mut vm = VM::with_output(SharedBuffer::default());
+            vm.instruction_count = count;
+            assert!(vm.interpret(Rc::new(compile(&mut compiler))).is_ok());
+            vm.instruction_count
+        };
+        assert_eq!(run("var a = 1; print a;", None), None);
+        assert_eq!(run("var a = 1; print a;", Some(0)), Some(4));
+        // Every pass through the loop runs its condition, body and jump back again
+        let once = run("var i = 0; while (i < 1) i = i + 1;", Some(0)).unwrap();
+        let twice = run("var i = 0; while (i < 2) i = i + 1;", Some(0)).unwrap();
+        let thrice = run("var i = 0; while (i < 3) i = i + 1;", Some(0)).unwrap();
+        assert!(twice > once);
+        assert_eq!(thrice - twice, twice - once);
+    }
+
+    #[test]
+    fn swapping_functions_keeps_program_state() {
+        let mut vm = VM::new();
+        let source = "fun a() { return 1; } fun b() { return 2; } var count = 5;";
+        assert!(vm.interpret(Rc::new(compile(&mut Compiler::new(source.to_owned())))).is_ok());
+        let b = vm.get_global("b").unwrap();
+
+        let edited = compile(&mut Compiler::new("fun a() { return 10; } var count = 0;".to_owned()));
+        assert!(vm.swap_functions(&edited.function.chunk).is_ok());
+        // The new body runs, the rest of the program is as the old script left it
+        let closure = compile(&mut Compiler::new("var result = a() + b() + count;".to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("result"), Some(Value::Int(17)));
+        match (vm.get_global("b"), b) {
+            (Some(Value::Closure(now)), Value::Closure(before)) => assert!(Rc::ptr_eq(&now, &before)),
+            _ => panic!("expected b to stay a closure"),
+        }
+    }
+
+    #[test]
+    fn swapped_functions_are_verified_first() {
+        let source = "fun f() { return 1; } var a = f();";
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(compile(&mut Compiler::new(source.to_owned())))).is_ok());
+
+        let mut broken = Chunk::new();
+        broken.add_op_pop(1);
+        broken.add_op_pop(1);
+        broken.add_op_return(1);
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        let mut chunk = closure.function.chunk.clone();
+        for value in chunk.values.iter_mut() {
+            if let Value::Function(_) = value {
+                *value = Value::Function(Rc::new(Function::new(0, broken.clone(), "f".to_owned(), vec![])));
+            }
+        }
+        assert!(vm.swap_functions(&chunk).is_err());
+        let closure = compile(&mut Compiler::new("var b = f();".to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("b"), Some(Value::Int(1)));
+    }
+
+    #[test]
+    fn line_table_is_run_length_encoded() {
+        let mut chunk = Chunk::new();
+        for line in [1, 1, 1, 2, 2, 4] {
+            chunk.add_op_nil(line);
+        }
+        assert_eq!(chunk.lines, vec![(1, 3), (2, 2), (4, 1)]);
+        assert_eq!((chunk.line_at(2), chunk.line_at(3), chunk.line_at(5)), (Some(1), Some(2), Some(4)));
+        assert_eq!(chunk.line_at(6), None);
+
+        chunk.truncate_lines(4);
+        assert_eq!(chunk.lines, vec![(1, 3), (2, 1)]);
+        chunk.truncate_lines(3);
+        assert_eq!(chunk.lines, vec![(1, 3)]);
+    }
+
+    #[test]
+    fn lists_are_built_indexed_and_assigned() {
+        let source = "var a = [1, 2, [3, 4]]; a[1] = a[2][1] + 10; var b = a[1]; var empty = [];";
+        let mut compiler = Compiler::new(source.to_owned());
+        let closure = compile(&mut compiler);
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("b"), Some(Value::Double(14.0)));
+        let a = vm.get_global("a").unwrap();
+        assert_eq!(a.to_string(), "[1, 14, [3, 4]]");
+        assert_eq!(a.pretty(0), "[\n  1,\n  14,\n  [\n    3,\n    4\n  ]\n]");
+
+        let closure = compile(&mut Compiler::new("a[3];".to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => assert_eq!(message, "List index out of range"),
+            _ => panic!("expected an index error"),
+        }
+    }
+
+    #[test]
+    fn maps_are_keyed_by_strings_and_numbers() {
+        let source = "var m = {\"a\": 1, 2: \"two\"}; m[\"b\"] = m[\"a\"] + 1;
+            var b = m[\"b\"]; var two = m[2]; var missing = m[\"c\"]; var n = len(keys(m));";
+        let mut compiler = Compiler::new(source.to_owned());
+        let closure = compile(&mut compiler);
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("b"), Some(Value::Double(2.0)));
+        assert_eq!(vm.get_global("two").as_ref().and_then(Value::as_str), Some("two"));
+        assert_eq!(vm.get_global("missing"), Some(Value::Nil));
+        assert_eq!(vm.get_global("n"), Some(Value::Double(3.0)));
+
+        let closure = compile(&mut Compiler::new("m[true] = 1;".to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => {
+                assert_eq!(message, "Map keys must be strings or numbers")
+            }
+            _ => panic!("expected a key error"),
+        }
+    }
+
+    #[test]
+    fn lambdas_are_expressions() {
+        let source = "var add = fun(a, b) { return a + b; }; var sum = add(1, 2);
+            var twice = fun(f, x) { return f(f(x)); }; var four = twice(fun(n) { return n * 2; }, 1);";
+        let mut compiler = Compiler::new(source.to_owned());
+        let closure = compile(&mut compiler);
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(vm.get_global("sum"), Some(Value::Double(3.0)));
+        assert_eq!(vm.get_global("four"), Some(Value::Double(4.0)));
+        assert_eq!(vm.get_global("lambda"), None);
+    }
+
+    #[test]
+    fn interpret_returns_errors_as_values() {
+        let mut interpreter = Interpreter::new();
+        assert!(interpreter.interpret("var a = 1;").is_ok());
+        assert!(interpreter.interpret("var b = a + 1;").is_ok());
+        assert_eq!(interpreter.vm.get_global("b"), Some(Value::Double(2.0)));
+
+        match interpreter.interpret("var c = ;") {
+            Err(InterpretError::Compile(errors)) => {
+                assert_eq!(errors[0].message, "Expect expression")
+            }
+            _ => panic!("expected a compile error"),
+        }
+        match crate::interpret("-nil;") {
+            Err(InterpretError::Runtime(message)) => assert_eq!(message, "Operand must be a number"),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn nan_boxes_round_trip_values() {
+        use crate::nanbox::NanBox;
+
+        for value in [Value::Double(-2.5), Value::Double(f64::INFINITY), Value::Nil, Value::Bool(false)] {
+            assert_eq!(NanBox::from(value.clone()).to_value(), value);
+        }
+        assert!(NanBox::number(f64::NAN).as_number().unwrap().is_nan());
+
+        let string = Rc::new(LoxString::from("boxed"));
+        let boxed = NanBox::from(Value::String(string.clone()));
+        let copy = boxed.clone();
+        drop(boxed);
+        assert!(copy.is_object());
+        assert_eq!(copy.to_value().as_str(), Some("boxed"));
+        drop(copy);
+        assert_eq!(Rc::strong_count(&string), 1);
+    }
+
+    #[cfg(feature = "packed-code")]
+    #[test]
+    fn packed_code_round_trips_in_fewer_bytes() {
+        use crate::bytecode::{self, PackedCode};
+
+        let source = "class A { f(x) { return [x, {1: 2}]; } } var a = A(); var i = 0;\n\
+                      while (i < 300) { i = i + 1; } print a.f(i)[0];";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        let mut codes = closure.function.chunk.codes.clone();
+        codes.extend([OpCode::OpInvoke(300, 2), OpCode::OpPopN(3), OpCode::OpLoop(2)]);
+        let packed = PackedCode::pack(&codes).unwrap();
+        assert_eq!(packed.unpack(), codes);
+        // ip and jumps count bytes
+        assert!(packed.len() < codes.len() * 3);
+        assert_eq!(packed.fetch(0).1, 2);
+        let mut offset = 0;
+        for (index, code) in codes.iter().enumerate() {
+            assert_eq!(packed.code_at(index), *code);
+            assert_eq!(packed.index_of(offset), index);
+            offset += packed.fetch(offset).1;
+        }
+        let mut out = vec![];
+        assert!(!bytecode::encode(&OpCode::OpConstant(256), &mut out));
+        assert!(!bytecode::encode(&OpCode::OpConstantLong(1 << 16), &mut out));
+        assert!(out.is_empty());
+        assert_eq!(PackedCode::pack(&[OpCode::OpConstantLong(1 << 16)]), None);
+        assert_eq!(PackedCode::pack(&[OpCode::OpNil, OpCode::OpLoop(1 << 40)]), None);
+        assert_eq!(bytecode::decode(&[255], 0), None);
+        assert_eq!(bytecode::decode(&[44, 1], 0), None);
+        assert_eq!(bytecode::decode(&[22, 0x80], 0), None);
+
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(output.clone());
+        let closure = Rc::new(closure);
+        assert!(vm.interpret(closure.clone()).is_ok());
+        assert_eq!(output.contents(), "300\n");
+        let chunk = &closure.function.chunk;
+        assert_eq!(chunk.code_end(), PackedCode::pack(&chunk.codes).unwrap().len());
+    }
+
+    #[test]
+    fn jumps_span_more_than_64k_of_code() {
+        let body = "a = a + 1;".repeat(20000);
+        let source = format!("var a = 0; if (a == 0) {{ {} }} else {{ a = -1; }} print a;", body);
+        let closure = compile(&mut Compiler::new(source));
+        #[cfg(feature = "packed-code")]
+        assert!(closure.function.chunk.code_end() > 1 << 16);
+        let output = SharedBuffer::default();
+        assert!(VM::with_output(output.clone()).interpret(Rc::new(closure)).is_ok());
+        assert_eq!(output.contents(), "20000\n");
+    }
+
+    #[test]
+    fn print_and_trace_go_to_the_vm_output() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(output.clone());
+        // Tracing is opt in
+        assert!(!vm.trace_execution);
+        let closure = compile(&mut Compiler::new("print \"hi\"; print nil; print 1 + 2; print 7 / 2;".to_owned()));
+        let mut listing = vec![];
+        closure.function.chunk.disassemble(&mut listing, "script").unwrap();
+        assert!(String::from_utf8(listing).unwrap().contains("OpPrint"));
+
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(output.contents(), "hi\nnil\n3\n3.5\n");
+
+        vm.trace_execution = true;
+        let closure = compile(&mut Compiler::new("print true;".to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        let trace = output.contents();
+        assert!(trace.contains("OpTrue\n") && trace.contains("| OpPrint\n"), "{}", trace);
+        assert!(trace.ends_with("true\n"));
+    }
+
+    #[test]
+    fn run_captures_output_and_errors() {
+        let result = crate::run("print 1 + 2; print \"two\";");
+        assert_eq!(result.output, "3\ntwo\n");
+        assert_eq!(result.error, None);
+
+        let result = crate::run("print 1;\n-nil;");
+        assert_eq!(result.output, "1\n");
+        assert_eq!(result.error.as_deref(), Some("Operand must be a number\n[line 2] in script"));
+
+        let error = crate::run("print ;").error.unwrap();
+        assert!(error.starts_with("error: Expect expression\n"));
+    }
+
+    #[test]
+    fn clone_copies_instance_fields() {
+        let source = "class Point {} var p = Point(); p.x = 1; p.tags = [\"a\"]; p.me = p;\n\
+                      var q = clone(p); q.x = 2; q.tags[0] = \"b\";\n\
+                      print p.x; print p.tags[0]; print q.x; print q.me == q; print q.me == p;";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "1\na\n2\ntrue\nfalse\n");
+    }
+
+    #[test]
+    fn inspect_renders_nested_and_cyclic_instances() {
+        let source = "class Node {} var leaf = Node(); leaf.value = 2;\n\
+                      var root = Node(); root.value = 1; root.child = leaf; root.me = root; root.none = Node();\n\
+                      print inspect(root);";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(
+            result.output,
+            "Node {\n  child: Node {\n    value: 2\n  },\n  me: Node {...},\n  none: Node {},\n  value: 1\n}\n"
+        );
+    }
+
+    #[test]
+    fn deep_recursion_overflows_the_stack() {
+        let result = crate::run("fun deep(n) {\n  return 1 + deep(n + 1);\n}\ndeep(1);");
+        let error = result.error.unwrap();
+        let lines: Vec<&str> = error.lines().collect();
+        assert_eq!(lines[0], "Stack overflow.");
+        assert_eq!(lines[1], "[line 2] in deep()");
+        // One line per frame, the script included
+        assert_eq!(lines.len(), 1 + 256);
+    }
+
+    #[test]
+    fn budget_stops_runaway_scripts() {
+        let mut vm = VM::new();
+        vm.trace_execution = false;
+        let closure = compile(&mut Compiler::new("while (true) {}".to_owned()));
+        assert!(matches!(
+            vm.interpret_with_budget(Rc::new(closure), 1000),
+            Err(VmError::BudgetExceeded)
+        ));
+        assert_eq!(vm.budget, None);
+
+        let closure = compile(&mut Compiler::new("var a = 1 + 2;".to_owned()));
+        assert!(vm.interpret_with_budget(Rc::new(closure), 1000).is_ok());
+        assert_eq!(vm.get_global("a"), Some(Value::Double(3.0)));
+    }
+
+    #[test]
+    fn heap_limit_stops_runaway_allocation() {
+        let mut vm = VM::new();
+        vm.trace_execution = false;
+        vm.max_heap_bytes = Some(1 << 20);
+        let closure = compile(&mut Compiler::new("var s = \"ab\"; while (true) s = s + s;".to_owned()));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => assert_eq!(message, "Heap limit exceeded"),
+            _ => panic!("expected the heap limit to be hit"),
+        }
+        assert!(vm.bytes_allocated > 1 << 20);
+        // The string that would have gone past the limit was never built
+        assert!(vm.get_global("s").unwrap().as_str().unwrap().len() <= 1 << 20);
+
+        let closure = compile(&mut Compiler::new("var l = [1, 2]; var m = {\"a\": l};".to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert!(vm.bytes_allocated > 0);
+    }
+
+    #[test]
+    fn debug_session_pauses_on_breakpoints() {
+        use crate::debugger::{DebugSession, Pause, Resume};
+
+        let source = "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nvar x = add(1, 2);\nprint x;";
+        let pauses = Rc::new(RefCell::new(vec![]));
+        let seen = pauses.clone();
+        let mut session = DebugSession::new(move |pause: &mut Pause| {
+            let locals: Vec<String> = pause.locals().iter().map(|v| v.to_string()).collect();
+            assert!(pause.up());
+            seen.borrow_mut().push((pause.line(), locals, pause.locals().len()));
+            assert!(!pause.up());
+            Resume::Continue
+        });
+        session.breakpoints.insert(3);
+        let mut vm = VM::new();
+        vm.trace_execution = false;
+        vm.debugger = Some(Box::new(session));
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(
+            *pauses.borrow(),
+            vec![(5, vec!["<fn add/2>".to_owned(), "1".to_owned(), "2".to_owned(), "3".to_owned()], 1)]
+        );
+
+        vm.debugger = Some(Box::new(DebugSession::new(|_: &mut Pause| Resume::Abort)));
+        let closure = compile(&mut Compiler::new("print 1;".to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        let mut session = DebugSession::new(|_: &mut Pause| Resume::Abort);
+        session.stepping = true;
+        vm.debugger = Some(Box::new(session));
+        let closure = compile(&mut Compiler::new("print 1;".to_owned()));
+        assert!(matches!(vm.interpret(Rc::new(closure)), Err(VmError::Stopped)));
+
+        // Not even a try block around the code keeps it going
+        let mut session = DebugSession::new(|_: &mut Pause| Resume::Abort);
+        session.breakpoints.insert(2);
+        vm.debugger = Some(Box::new(session));
+        let source = "var caught = false;\ntry {\n  print 1;\n} catch (e) { caught = true; }";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        assert!(matches!(vm.interpret(Rc::new(closure)), Err(VmError::Stopped)));
+        assert_eq!(vm.get_global("caught"), Some(Value::Bool(false)));
+    }
+
+    #[test]
+    fn profile_counts_calls_and_instructions() {
+        let mut vm = VM::new();
+        vm.profile = Some(crate::profiler::Profile::default());
+        let source = "fun one() { return 1; } var a = one() + one() + one();";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        let mut profile = vm.profile.take().unwrap();
+        let one = profile.functions.iter().find(|stats| stats.function.name == "one").unwrap();
+        assert_eq!((one.calls, one.instructions), (3, 6));
+        let opcodes = profile.opcodes();
+        assert!(opcodes.contains(&("OpCall".to_owned(), 3)));
+        assert!(opcodes.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+
+        let mut report = vec![];
+        profile.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("== Profile ==\n"));
+        assert!(report.contains("<fn one/0>"));
+    }
+
+    #[test]
+    fn ast_dump_shows_precedence_and_associativity() {
+        let statements = crate::ast::parse("print 1 - 2 - 3 * -x;\na = b ? c : d;").unwrap();
+        assert_eq!(
+            crate::ast::dump(&statements),
+            "Print\n  Binary -\n    Binary -\n      Number 1\n      Number 2\n    Binary *\n      Number 3\n      Unary -\n        Variable x\n\
+             Expression\n  Assign a\n    Ternary\n      Variable b\n      Variable c\n      Variable d\n"
+        );
+
+        let diagnostics = crate::ast::parse("print (1;\nvar = 2;").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec!["Expect ')' after expression", "Expect variable name"]);
+
+        // The compiler agrees with the tree
+        let result = crate::run("print 10 - 2 - 3; print 8 / 4 / 2;");
+        assert_eq!(result.output, "5\n1\n");
+    }
+
+    #[test]
+    fn formatter_reprints_canonical_layout() {
+        let source = "// add things\nfun add(a,b){return a+b;}   // sum\n\n\nvar m={\"k\":[1,-2]};if(m)print add(1,2);else{}\n";
+        let formatted = crate::formatter::format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "// add things\nfun add(a, b) {\n  return a + b;\n} // sum\n\nvar m = {\"k\": [1, -2]};\nif (m) print add(1, 2);\nelse {}\n"
+        );
+        assert_eq!(crate::formatter::format(&formatted).unwrap(), formatted);
+        assert!(crate::formatter::format("print (1;").is_err());
+    }
+
+    #[test]
+    fn unused_names_are_warnings() {
+        let source = "fun helper() {}\nfun main() {\n  var used = 1;\n  var unused = 2;\n  var _skip = 3;\n  return used;\n}\nprint main();";
+        let warnings = |strict: bool| {
+            let mut compiler = Compiler::new(source.to_owned());
+            compiler.strict = strict;
+            assert!(compiler.compile().is_ok());
+            compiler
+                .diagnostics
+                .iter()
+                .map(|d| {
+                    assert_eq!(d.severity, crate::Severity::Warning);
+                    (d.message.clone(), d.line)
+                })
+                .collect::<Vec<_>>()
+        };
+        let unused = ("Unused variable 'unused'".to_owned(), 4);
+        assert_eq!(warnings(false), vec![unused.clone()]);
+        // Globals only count as unused in strict mode
+        assert_eq!(warnings(true), vec![unused, ("Unused function 'helper'".to_owned(), 1)]);
+
+        // Later lines may still read what the REPL defines
+        let mut compiler = Compiler::new("var x = 1;".to_owned());
+        compiler.strict = true;
+        compiler.repl = true;
+        assert!(compiler.compile().is_ok());
+        assert!(compiler.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn instances_hold_fields_next_to_methods() {
+        let source = "
+class Point {
+  sum() { return this.x + this.y; }
+  scale(k) { this.x = this.x * k; this.y = this.y * k; return this; }
+}
+var p = Point();
+p.x = 1;
+print p.y = 2;
+print p.scale(3).sum();
+var sum = p.sum;
+print sum();
+fun twice(n) { return n * 2; }
+p.sum = twice;
+print p.sum(5);";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "2\n9\n9\n10\n");
+
+        let result = crate::run("class A {}\nvar a = A();\nprint a.b;");
+        assert_eq!(result.error.as_deref(), Some("Undefined property 'b'\n[line 3] in script"));
+        let result = crate::run("var n = 1;\nn.x = 2;");
+        assert_eq!(result.error.as_deref(), Some("Only instances have fields\n[line 2] in script"));
+    }
+
+    #[test]
+    fn instances_with_the_same_fields_share_a_shape() {
+        let source = "
+class P {}
+var a = P();
+a.x = 1;
+a.y = 2;
+var b = P();
+b.x = 3;
+b.y = 4;
+b.x = 5;
+var c = P();
+c.y = 6;
+c.x = 7;";
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(compile(&mut Compiler::new(source.to_owned())))).is_ok());
+        let instance = |name| match vm.get_global(name) {
+            Some(Value::Instance(instance)) => instance,
+            _ => panic!("expected an instance"),
+        };
+        let (a, b, c) = (instance("a"), instance("b"), instance("c"));
+        assert!(Rc::ptr_eq(&a.shape.borrow(), &b.shape.borrow()));
+        assert!(!Rc::ptr_eq(&a.shape.borrow(), &c.shape.borrow()));
+        assert_eq!(*b.fields.borrow(), vec![Value::Int(5), Value::Int(4)]);
+        assert_eq!((c.get_field(&"x".into()), c.get_field(&"y".into())), (Some(Value::Int(7)), Some(Value::Int(6))));
+    }
+
+    #[test]
+    fn property_sites_cache_by_shape() {
+        let source = "
+class A { name() { return \"a\"; } }
+class B { name() { return \"b\"; } }
+fun show(o) { print o.name(); }
+var a = A();
+show(a);
+show(a);
+show(B());
+var c = A();
+c.name = \"field\";
+fun read(o) { return o.name; }
+print read(c);
+print read(a)();";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "a\na\nb\nfield\na\n");
+
+        let mut vm = VM::new();
+        assert!(vm.interpret(Rc::new(compile(&mut Compiler::new(source.to_owned())))).is_ok());
+        let show = match vm.get_global("show") {
+            Some(Value::Closure(closure)) => closure,
+            _ => panic!("expected show"),
+        };
+        let caches = show.function.chunk.caches.borrow();
+        assert_eq!(caches.iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn accessors_run_on_property_reads_and_writes() {
+        let source = "
+class Rect {
+  area { return this.w * this.h; }
+  set side(value) { this.w = value; this.h = value; }
+  set(k) { return k + 1; }
+}
+var r = Rect();
+print r.side = 3;
+print r.area;
+print r.set(1);";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "3\n9\n2\n");
+
+        let mut compiler = Compiler::new("class A {\n  set x(a, b) {}\n}".to_owned());
+        let diagnostics = compiler.compile().unwrap_err();
+        assert_eq!(diagnostics[0].message, "A setter takes exactly one parameter");
+        let mut compiler = Compiler::new("class A { set x(v) { return v; } }".to_owned());
+        let diagnostics = compiler.compile().unwrap_err();
+        assert_eq!(diagnostics[0].message, "Can't return a value from a setter");
+    }
+
+    #[test]
+    fn print_and_concatenation_use_to_string() {
+        let source = "
+class Name {
+  toString() { return this.first + \" \" + this.last; }
+}
+class Plain {}
+var n = Name();
+n.first = \"Ada\";
+n.last = \"Lovelace\";
+print n;
+print \"Hello, \" + n + \"!\";
+print Plain();";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "Ada Lovelace\nHello, Ada Lovelace!\nPlain instance\n");
+
+        // Errors inside toString unwind through the instruction that called it
+        let result = crate::run("class Bad {\n  toString() { return -nil; }\n}\nprint Bad();");
+        assert_eq!(
+            result.error.as_deref(),
+            Some("Operand must be a number\n[line 2] in toString()\n[line 4] in script")
+        );
+    }
+
+    #[test]
+    fn operators_dispatch_to_instance_methods() {
+        let source = "
+class Vec {
+  plus(other) { return vec(this.x + other.x, this.y + other.y); }
+  times(k) { return vec(this.x * k, this.y * k); }
+  negate() { return this.times(-1); }
+  eq(other) { return this.x == other.x and this.y == other.y; }
+  lt(other) { return this.x < other.x; }
+}
+fun vec(x, y) { var v = Vec(); v.x = x; v.y = y; return v; }
+var a = vec(1, 2);
+print (a + vec(3, 4)).y;
+print (-a * 2).x;
+print a == vec(1, 2);
+print a != a;
+print a < vec(3, 4);";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "6\n-2\ntrue\nfalse\ntrue\n");
+
+        let result = crate::run("class A {}\nprint A() - 1;");
+        assert_eq!(result.error.as_deref(), Some("Operand must be a number\n[line 2] in script"));
+    }
+
+    #[test]
+    fn ints_stay_exact_until_promoted() {
+        let source = "
+print 9007199254740993 + 1;
+print 7 / 2;
+print 9223372036854775807 + 1;
+print 3 * 1.5;
+print 1 == 1.0;
+print -(-9223372036854775807 - 1);
+print {1: \"one\"}[1.0];";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(
+            result.output,
+            "9007199254740994\n3.5\n9223372036854776000\n4.5\ntrue\n9223372036854776000\none\n"
+        );
+        assert_eq!(Value::Int(2), Value::Double(2.0));
+        assert_eq!(Value::Double(9007199254740992.0), Value::Int(1 << 53));
+        assert_ne!(Value::Int((1 << 53) + 1), Value::Double(9007199254740992.0));
+        assert_ne!(Value::Int(i64::MAX), Value::Double(i64::MAX as f64));
+    }
+
+    #[test]
+    fn string_natives_count_characters() {
+        let source = "
+var s = \"Grüße, Welt\";
+print len(s);
+print substr(s, 7, 99);
+print upper(substr(s, 1, 4));
+print lower(s);
+print indexOf(s, \"Welt\");
+print indexOf(s, \"x\");
+print split(\"a,b,,c\", \",\");
+print len(split(\"abc\", \"\"));";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "11\nWelt\nRÜSSE\ngrüße, welt\n7\n-1\n[a, b, , c]\n3\n");
+
+        let result = crate::run("substr(\"abc\", 1.5, 1);");
+        assert_eq!(
+            result.error.as_deref(),
+            Some("substr: expected a non-negative integer but got 1.5\n[line 1] in script")
+        );
+    }
+
+    #[test]
+    fn math_natives_keep_ints_where_they_can() {
+        let source = "
+print sqrt(16);
+print abs(-3);
+print floor(-3.5);
+print ceil(3.2);
+print min(3, 2.5);
+print max(3, 2.5);
+print pow(2, 10);
+print pow(2, -1);";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "4\n3\n-4\n4\n2.5\n3\n1024\n0.5\n");
+
+        let result = crate::run("print pow(2);");
+        assert_eq!(result.error.as_deref(), Some("Expected 2 arguments but got 1\n[line 1] in script"));
+    }
+
+    #[test]
+    fn read_natives_take_the_vm_input() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(output.clone());
+        vm.input = Some(Box::new(std::io::Cursor::new("first\r\nsecond\nthe\nrest")));
+        let source = "print readLine(); print readLine(); print readAll(); print readLine();";
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(output.contents(), "first\nsecond\nthe\nrest\nnil\n");
+    }
+
+    #[test]
+    fn file_natives_are_left_out_of_sandboxed_vms() {
+        let path = std::env::temp_dir().join(format!("rlox-file-natives-{}.txt", std::process::id()));
+        let path = format!("{:?}", path.to_string_lossy());
+        let source = format!(
+            "print fileExists({0}); writeFile({0}, \"saved\"); print fileExists({0}); print readFile({0});",
+            path
+        );
+        let result = crate::run(&source);
+        let _ = std::fs::remove_file(path.trim_matches('"'));
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "false\ntrue\nsaved\n");
+
+        let mut vm = VM::sandboxed();
+        let closure = compile(&mut Compiler::new(source));
+        match vm.interpret(Rc::new(closure)) {
+            Err(VmError::RuntimeError(message)) => assert!(message.starts_with("Undefined variable")),
+            _ => panic!("expected fileExists to be undefined"),
+        }
+    }
+
+    #[test]
+    fn try_blocks_catch_runtime_errors_and_thrown_values() {
+        let source = "
+fun check(x) {
+  try {
+    if (x) throw x;
+    return missing;
+  } finally {
+    print \"checked\";
+  }
+}
+try { check(nil); } catch (e) { print e; }
+try { check(42); } catch (e) { print e + 1; }
+try {
+  try { throw \"inner\"; } catch (e) { throw e + \"!\"; } finally { print \"cleanup\"; }
+} catch (e) {
+  print e;
+}
+throw \"done\";";
+        let result = crate::run(source);
+        assert_eq!(
+            result.output,
+            "checked\nUndefined variable missing\nchecked\n43\ncleanup\ninner!\n"
+        );
+        assert_eq!(result.error.as_deref(), Some("Uncaught exception: done\n[line 17] in script"));
+    }
+
+    #[test]
+    fn assert_reports_the_call_site_unless_turned_off() {
+        let source = "assert(1 < 2, \"fine\");\ntry { assert(nil, \"caught\"); } catch (e) { print e; }\nassert(false, \"stop\");";
+        let result = crate::run(source);
+        assert_eq!(result.output, "assert: Assertion failed at script:2: caught\n");
+        assert_eq!(
+            result.error.as_deref(),
+            Some("assert: Assertion failed at script:3: stop\n[line 3] in script")
+        );
+
+        let options = super::Options {
+            skip_asserts: true,
+            ..Default::default()
+        };
+        let mut vm = super::new_vm("checks.lox", &options);
+        let closure = compile(&mut Compiler::new(source.to_owned()));
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+    }
+
+    #[test]
+    fn check_reports_errors_and_deep_nesting_without_crashing() {
+        assert!(super::check("var a = 1; while (true) a = a + 1;").is_ok());
+        let errors = super::check("print 1 +;").unwrap_err();
+        assert_eq!(errors[0].line, 1);
+        let errors = super::check("print 1;\nprint nope;").unwrap_err();
+        assert!(errors[0].message.starts_with("Undefined variable"));
+        assert_eq!(errors[0].line, 2);
+
+        let deep = [
+            "(".repeat(100_000),
+            "{".repeat(100_000),
+            "-".repeat(100_000),
+            "fun f() {".repeat(10_000),
+        ];
+        for source in deep.iter() {
+            let errors = super::check(source).unwrap_err();
+            assert!(errors.iter().any(|e| e.message == crate::error::TOO_MUCH_NESTING));
+            assert!(crate::ast::parse(source).is_err());
+            assert!(crate::formatter::format(source).is_err());
+        }
+    }
+
+    #[test]
+    fn scanner_keeps_multi_byte_characters_whole() {
+        let result = super::run("var café = \"naïve ☃\"; /* 日本 */ var 名前 = café + \"!\"; // ✓\nprint 名前;");
+        assert_eq!(result.output, "naïve ☃!\n");
+        assert_eq!(result.error, None);
+
+        let diagnostics = Compiler::new("var ü = \"é\" ☃ 1;".to_owned()).compile().unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "Unexpected character");
+        assert_eq!((diagnostics[0].column, diagnostics[0].length), (13, 3));
+    }
+
+    #[test]
+    fn tokenize_locates_every_token_before_eof() {
+        let source = "var x = \"hi\"; // done\nx @";
+        let tokens = super::tokenize(source);
+        let summary: Vec<(TokenType, &str, i32)> = tokens
+            .iter()
+            .map(|token| (token.token_type, &source[token.start..token.start + token.length], token.line))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (TokenType::Var, "var", 0),
+                (TokenType::Identifier, "x", 0),
+                (TokenType::Equal, "=", 0),
+                (TokenType::String, "\"hi\"", 0),
+                (TokenType::SemiColon, ";", 0),
+                (TokenType::Identifier, "x", 1),
+                (TokenType::Error, "@", 1),
+            ]
+        );
+        assert_eq!(&*tokens[3].lexeme, "hi");
+    }
+
+    #[test]
+    fn scanner_stops_at_the_end_of_input() {
+        // Each of these ends where the scanner looks one character ahead
+        for source in ["x", "12", "1.", "!", "<", "print 1"] {
+            assert!(!super::tokenize(source).is_empty(), "{}", source);
+            assert!(Compiler::new(source.to_owned()).compile().is_err(), "{}", source);
+        }
+        assert!(super::tokenize("// note").is_empty());
+        assert!(Compiler::new("// note".to_owned()).compile().is_ok());
+    }
+
+    #[test]
+    fn highlight_classifies_tokens_and_comments() {
+        use crate::highlight::{self, Category};
+
+        let source = "var é = \"a\nb\"; // note\nprint é /* end";
+        let spans: Vec<(Category, &str, usize, usize)> = highlight::highlight(source)
+            .iter()
+            .map(|span| (span.category, &source[span.start..span.start + span.length], span.line, span.column))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (Category::Keyword, "var", 1, 1),
+                (Category::Identifier, "é", 1, 5),
+                (Category::Operator, "=", 1, 7),
+                (Category::String, "\"a\nb\"", 1, 9),
+                (Category::Punctuation, ";", 2, 3),
+                (Category::Comment, "// note", 2, 5),
+                (Category::Keyword, "print", 3, 1),
+                (Category::Identifier, "é", 3, 7),
+                (Category::Comment, "/* end", 3, 9),
+            ]
+        );
+        assert!(highlight::to_json(&highlight::highlight("nil"))
+            .contains("{\"start\": 0, \"length\": 3, \"line\": 1, \"column\": 1, \"category\": \"keyword\"}"));
+    }
+
+    #[test]
+    fn code_after_return_is_dropped_with_a_warning() {
+        let source = "fun f() {\n  return 1;\n  print \"never\";\n  print \"nor this\";\n}\nprint f();";
+        let mut compiler = Compiler::new(source.to_owned());
+        let closure = compile(&mut compiler);
+        let warnings: Vec<(&str, i32)> = compiler
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.message.as_str(), diagnostic.line))
+            .collect();
+        assert_eq!(warnings, vec![("Unreachable code", 3)]);
+
+        let f = closure.function.chunk.values.iter().find_map(|value| match value {
+            Value::Function(function) => Some(function.clone()),
+            _ => None,
+        });
+        let codes = &f.unwrap().chunk.codes;
+        assert!(!codes.iter().any(|code| matches!(code, OpCode::OpPrint)));
+
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(output.clone());
+        assert!(vm.interpret(Rc::new(closure)).is_ok());
+        assert_eq!(output.contents(), "1\n");
+    }
+
+    #[test]
+    fn locals_cannot_read_themselves_in_their_initializer() {
+        let mut compiler = Compiler::new("{\n  var x = 1;\n  { var x = x + 1; }\n}".to_owned());
+        let diagnostics = compiler.compile().unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "Can't read local variable in its own initializer");
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (3, 13));
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        let result = crate::run(
+            "fun count(n) { if (n < 1) return n; return count(n - 1); } print count(100000);",
+        );
+        assert_eq!(result.output, "0\n");
+
+        // Only the innermost call and the script are left to show
+        let result = crate::run("fun down(n) {\nif (n < 1) return -nil;\nreturn down(n - 1);\n}\ndown(1000);");
+        assert_eq!(
+            result.error.as_deref(),
+            Some("Operand must be a number\n[line 2] in down()\n[line 5] in script")
+        );
+    }
+
+    #[test]
+    fn closures_capture_enclosing_locals() {
+        let source = "
+fun makeCounter() {
+  var count = 1;
+  fun inc() { count = count + 1; return count; }
+  return inc;
+}
+var a = makeCounter();
+var b = makeCounter();
+print a();
+print a();
+print b();
+fun outer() {
+  var v = 10;
+  fun middle() { fun inner() { return v + 1; } return inner; }
+  return middle;
+}
+print outer()()();
+var first;
+var second;
+{
+  var i = 1;
+  while (i < 3) {
+    var j = i * 10;
+    fun get() { return j; }
+    if (i == 1) first = get; else second = get;
+    i = i + 1;
+  }
+}
+print first();
+print second();";
+        let result = crate::run(source);
+        assert_eq!(result.error, None);
+        assert_eq!(result.output, "2\n3\n2\n11\n10\n20\n");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn data_values_round_trip_through_serde() {
+        let value: Value = serde_json::from_str(r#"{"name": "lox", "tags": [1, true, null]}"#).unwrap();
+        let mut vm = VM::new();
+        vm.set_global("config", value);
+        let source = "var name = config[\"name\"]; config[\"tags\"][1] = 2.5;";
+        assert!(vm.interpret(Rc::new(compile(&mut Compiler::new(source.to_owned())))).is_ok());
+        assert_eq!(vm.get_global("name"), Some(Value::from("lox")));
+
+        let tags = serde_json::to_string(&vm.get_global("config").unwrap()).unwrap();
+        // JSON integers come back as integers
+        assert!(tags.contains(r#""tags":[1,2.5,null]"#), "{}", tags);
+        assert!(serde_json::to_string(&vm.get_global("clock").unwrap()).is_err());
+    }
+
+    #[test]
+    fn malformed_code_is_a_runtime_error_not_a_panic() {
+        // A global name that is not a string passes the verifier
+        let mut chunk = Chunk::new();
+        chunk.add_op_constant(Value::Double(1.0), 0);
+        chunk.add_op_pop(0);
+        chunk.add_op_get_global(0, 0);
+        chunk.add_op_pop(0);
+        let function = Function::new(0, chunk, "".to_owned(), vec![]);
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret(Rc::new(Closure::new(Rc::new(function)))),
+            Err(VmError::RuntimeError(_))
+        ));
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_offending_token() {
+        let source = "var a = 1;\n  var b = ;\nvar c = @;";
+        let mut compiler = Compiler::new(source.to_owned());
+        let diagnostics = compiler.compile().unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (2, 11));
+        assert_eq!(&source[diagnostics[0].start..][..diagnostics[0].length], ";");
+        assert_eq!(diagnostics[0].snippet(source), "  var b = ;");
+        assert_eq!((diagnostics[1].line, diagnostics[1].column), (3, 9));
+        assert_eq!(diagnostics[1].message, "Unexpected character");
+        assert_eq!(
+            diagnostics[0].render(source, false),
+            "error: Expect expression\n --> line 2:11\n  |\n2 |   var b = ;\n  |           ^\n"
+        );
+    }
 }
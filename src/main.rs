@@ -1,12 +1,53 @@
 use std::env;
 
 fn main() {
-    let args: Vec<String>= env::args().collect();
-    if args.len() ==1 {
-        rlox::repl();
-    } else if args.len() == 2 {
-        rlox::run_file(&args[1]);
-    } else {
-        println!("Usage: rlox [path]");
+    let args: Vec<String> = env::args().collect();
+    let mut options = rlox::Options::default();
+    let mut watch = false;
+    let mut check = false;
+    let mut json = false;
+    let mut rest = vec![];
+    for arg in args[1..].iter() {
+        match arg.as_str() {
+            "--watch" => watch = true,
+            "--check" => check = true,
+            "--json" => json = true,
+            "--strict" => options.strict = true,
+            "--lazy" => options.lazy = true,
+            "--time" => options.time = true,
+            "--disassemble" => options.disassemble = true,
+            "--trace" => options.trace = true,
+            "--profile" => options.profile = true,
+            "--dump-ast" => options.dump_ast = true,
+            "--sandbox" => options.sandbox = true,
+            "--assert=off" => options.skip_asserts = true,
+            "--assert=on" => options.skip_asserts = false,
+            "--emit=optimized-dis" => options.emit_optimized = true,
+            flag if rlox::optimizer::OptLevel::from_flag(flag).is_some() => {
+                options.opt_level = rlox::optimizer::OptLevel::from_flag(flag).unwrap()
+            }
+            _ => rest.push(arg),
+        }
+    }
+    match &rest[..] {
+        [] if !watch => rlox::repl(),
+        [path] if !watch => rlox::run_file(path, &options),
+        [command, path] if command.as_str() == "debug" && !watch => rlox::debug_file(path, &options),
+        [command, path] if command.as_str() == "fmt" && !watch => {
+            if !rlox::format_file(path, check) {
+                std::process::exit(1);
+            }
+        }
+        [command, path] if command.as_str() == "highlight" && !watch => rlox::highlight_file(path, json),
+        [command, path] if command.as_str() == "run" => {
+            if watch {
+                rlox::watch_file(path, &options)
+            } else {
+                rlox::run_file(path, &options)
+            }
+        }
+        _ => println!(
+            "Usage: rlox [run [--watch] [--strict] [--lazy] [-O0|-O1|-O2] [--emit=optimized-dis] [--time] [--disassemble] [--trace] [--profile] [--dump-ast] [--sandbox] [--assert=on|off]] [path]\n       rlox debug [--strict] [--lazy] [--sandbox] [--assert=on|off] [-O0|-O1|-O2] [--trace] path\n       rlox fmt [--check] path\n       rlox highlight [--json] path"
+        ),
     }
 }
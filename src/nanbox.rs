@@ -0,0 +1,131 @@
+use std::{fmt, rc::Rc};
+
+use crate::chunk::Value;
+
+// A Value packed into 64 bits. Numbers are stored as their own bits, everything else
+// hides in the unused payload of a quiet NaN: nil and booleans as small tags, ints and heap
+// values as a pointer to a reference counted Value with the sign bit set.
+pub struct NanBox(u64);
+
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN: u64 = 0x8000_0000_0000_0000;
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+// User space pointers fit in the low 48 bits on the platforms we run on
+const POINTER: u64 = 0x0000_ffff_ffff_ffff;
+
+impl NanBox {
+    pub fn nil() -> Self {
+        NanBox(QNAN | TAG_NIL)
+    }
+
+    pub fn boolean(value: bool) -> Self {
+        NanBox(QNAN | if value { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    pub fn number(value: f64) -> Self {
+        // A NaN computed at runtime could look like a tagged value, store the canonical one
+        if value.is_nan() {
+            NanBox(f64::NAN.to_bits())
+        } else {
+            NanBox(value.to_bits())
+        }
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == QNAN | TAG_NIL
+    }
+
+    pub fn is_object(&self) -> bool {
+        self.0 & (QNAN | SIGN) == QNAN | SIGN
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        if self.is_number() {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            bits if bits == QNAN | TAG_TRUE => Some(true),
+            bits if bits == QNAN | TAG_FALSE => Some(false),
+            _ => None,
+        }
+    }
+
+    fn object(&self) -> Option<*const Value> {
+        if self.is_object() {
+            Some((self.0 & POINTER) as *const Value)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        if let Some(number) = self.as_number() {
+            return Value::Double(number);
+        }
+        if let Some(boolean) = self.as_bool() {
+            return Value::Bool(boolean);
+        }
+        match self.object() {
+            // SAFETY: object bits are only ever made from Rc::into_raw in From<Value>,
+            // and this box holds one strong count, so the Value is still alive
+            Some(pointer) => unsafe { (*pointer).clone() },
+            None => Value::Nil,
+        }
+    }
+}
+
+impl From<Value> for NanBox {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Double(number) => NanBox::number(number),
+            Value::Bool(boolean) => NanBox::boolean(boolean),
+            Value::Nil => NanBox::nil(),
+            value => {
+                let pointer = Rc::into_raw(Rc::new(value)) as u64;
+                // Checked in release builds too, a pointer that lost bits would be read as another
+                assert_eq!(pointer & !POINTER, 0, "pointer does not fit in a NaN");
+                NanBox(SIGN | QNAN | pointer)
+            }
+        }
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> Self {
+        if let Some(pointer) = self.object() {
+            // SAFETY: the pointer came from Rc::into_raw and this box keeps it alive
+            unsafe { Rc::increment_strong_count(pointer) };
+        }
+        NanBox(self.0)
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        if let Some(pointer) = self.object() {
+            // SAFETY: every box owns exactly one strong count, given back here
+            unsafe { Rc::decrement_strong_count(pointer) };
+        }
+    }
+}
+
+impl fmt::Debug for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NanBox({:?})", self.to_value())
+    }
+}
@@ -0,0 +1,488 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    convert::TryFrom,
+    fs,
+    io::{self, Read},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    chunk::{
+        Class, Instance, LoxMap, LoxString, Native, NativeError, NativeFn, NativeResult, Value,
+    },
+    error,
+    vm::VM,
+};
+
+// Natives every VM starts with, sandboxed or not
+pub fn define_defaults(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("clone", 1, clone);
+    vm.define_native("inspect", 1, inspect);
+    vm.define_native("keys", 1, keys);
+    vm.define_native("values", 1, values);
+    vm.define_native("entries", 1, entries);
+    vm.define_native("map", 2, map);
+    vm.define_native("filter", 2, filter);
+    vm.define_native("reduce", 3, reduce);
+    vm.define_native("forEach", 2, for_each);
+    vm.define_native("sort", 2, sort);
+    vm.define_native("len", 1, len);
+    vm.define_native("substr", 3, substr);
+    vm.define_native("upper", 1, upper);
+    vm.define_native("lower", 1, lower);
+    vm.define_native("indexOf", 2, index_of);
+    vm.define_native("split", 2, split);
+    vm.define_native("sqrt", 1, sqrt);
+    vm.define_native("abs", 1, abs);
+    vm.define_native("floor", 1, floor);
+    vm.define_native("ceil", 1, ceil);
+    vm.define_native("min", 2, min);
+    vm.define_native("max", 2, max);
+    vm.define_native("pow", 2, pow);
+    vm.define_native("readLine", 0, read_line);
+    vm.define_native("readAll", 0, read_all);
+    vm.define_native("assert", 2, assert);
+    define_string_builder(vm);
+
+    vm.define_namespace(
+        "Math",
+        &[
+            ("sqrt", 1, sqrt),
+            ("abs", 1, abs),
+            ("floor", 1, floor),
+            ("ceil", 1, ceil),
+            ("min", 2, min),
+            ("max", 2, max),
+            ("pow", 2, pow),
+        ],
+    );
+    vm.define_namespace(
+        "String",
+        &[
+            ("len", 1, len),
+            ("substr", 3, substr),
+            ("upper", 1, upper),
+            ("lower", 1, lower),
+            ("indexOf", 2, index_of),
+            ("split", 2, split),
+        ],
+    );
+    vm.define_namespace(
+        "Map",
+        &[("len", 1, len), ("keys", 1, keys), ("values", 1, values), ("entries", 1, entries)],
+    );
+    vm.define_namespace(
+        "List",
+        &[
+            ("len", 1, len),
+            ("range", 2, range),
+            ("map", 2, map),
+            ("filter", 2, filter),
+            ("reduce", 3, reduce),
+            ("forEach", 2, for_each),
+            ("sort", 2, sort),
+        ],
+    );
+}
+
+// StringBuilder() makes an instance whose append, toString and clear members share one
+// buffer, so output assembled piece by piece is not copied again on every append
+fn define_string_builder(vm: &mut VM) {
+    let class = Rc::new(Class::new(Rc::new(LoxString::from("StringBuilder"))));
+    vm.define_native("StringBuilder", 0, move |_, _| {
+        let buffer = Rc::new(RefCell::new(String::new()));
+        let builder = Instance::new(class.clone());
+        let appended = buffer.clone();
+        let member = |name: &str, arity, function: Box<NativeFn>| {
+            let native = Native { name: format!("StringBuilder.{}", name), arity, function };
+            let name = Rc::new(LoxString::from(name));
+            builder.set_field(&name, Value::NativeFunction(Rc::new(native)));
+        };
+        // Takes any value and appends what print would show for it
+        member(
+            "append",
+            1,
+            Box::new(move |vm, args| {
+                let text = vm.stringify(args[0].clone())?;
+                appended.borrow_mut().push_str(&text);
+                Ok(Value::Nil)
+            }),
+        );
+        let read = buffer.clone();
+        member("toString", 0, Box::new(move |_, _| Ok(Value::from(read.borrow().as_str()))));
+        member(
+            "clear",
+            0,
+            Box::new(move |_, _| {
+                buffer.borrow_mut().clear();
+                Ok(Value::Nil)
+            }),
+        );
+        Ok(Value::Instance(Rc::new(builder)))
+    });
+}
+
+// File access, left out of sandboxed VMs
+pub fn define_file_io(vm: &mut VM) {
+    vm.define_native("readFile", 1, read_file);
+    vm.define_native("writeFile", 2, write_file);
+    vm.define_native("fileExists", 1, file_exists);
+}
+
+// Seconds since the Unix epoch, scripts subtract two readings to time a run
+#[cfg(not(target_arch = "wasm32"))]
+pub fn clock(_: &mut VM, _: &[Value]) -> NativeResult {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| Value::Double(elapsed.as_secs_f64()))
+        .map_err(|error| NativeError(error.to_string()))
+}
+
+// The browser has no system clock for std, ask JavaScript
+#[cfg(target_arch = "wasm32")]
+pub fn clock(_: &mut VM, _: &[Value]) -> NativeResult {
+    Ok(Value::Double(js_sys::Date::now() / 1000.0))
+}
+
+pub fn clone(_: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(args[0].deep_clone())
+}
+
+pub fn inspect(_: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::String(Rc::new(LoxString::new(args[0].pretty(0)))))
+}
+
+// Keys of a map as a new list, in insertion order like values and entries
+pub fn keys(_: &mut VM, args: &[Value]) -> NativeResult {
+    let keys = map_arg(&args[0])?.borrow().keys().cloned().collect();
+    Ok(Value::List(Rc::new(RefCell::new(keys))))
+}
+
+pub fn values(_: &mut VM, args: &[Value]) -> NativeResult {
+    let values = map_arg(&args[0])?.borrow().values().cloned().collect();
+    Ok(Value::List(Rc::new(RefCell::new(values))))
+}
+
+// Each entry as a two item list of key and value
+pub fn entries(_: &mut VM, args: &[Value]) -> NativeResult {
+    let entries = map_arg(&args[0])?
+        .borrow()
+        .iter()
+        .map(|(key, value)| Value::List(Rc::new(RefCell::new(vec![key.clone(), value.clone()]))))
+        .collect();
+    Ok(Value::List(Rc::new(RefCell::new(entries))))
+}
+
+// The callbacks below see the items the list held when the native was called, changes
+// they make to the list show up afterwards
+
+// A new list of what the function returns for each item
+pub fn map(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let mut mapped = vec![];
+    for item in list_items(&args[0])? {
+        mapped.push(vm.call(&args[1], &[item])?);
+    }
+    Ok(Value::List(Rc::new(RefCell::new(mapped))))
+}
+
+// A new list of the items the function returns something truthy for
+pub fn filter(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let mut kept = vec![];
+    for item in list_items(&args[0])? {
+        if vm.call(&args[1], std::slice::from_ref(&item))?.is_truthy() {
+            kept.push(item);
+        }
+    }
+    Ok(Value::List(Rc::new(RefCell::new(kept))))
+}
+
+// Folds the items left to right, calling the function with the total so far and an item
+pub fn reduce(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let mut total = args[2].clone();
+    for item in list_items(&args[0])? {
+        total = vm.call(&args[1], &[total, item])?;
+    }
+    Ok(total)
+}
+
+pub fn for_each(vm: &mut VM, args: &[Value]) -> NativeResult {
+    for item in list_items(&args[0])? {
+        vm.call(&args[1], &[item])?;
+    }
+    Ok(Value::Nil)
+}
+
+// Sorts the list in place and returns it. The comparator is called with two items and
+// returns a number below 0 when the first goes before the second; with nil instead,
+// numbers and strings sort ascending and instances by their cmp method. Equal items keep
+// their order.
+pub fn sort(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let list = match &args[0] {
+        Value::List(list) => list,
+        value => return Err(NativeError(format!("expected a list but got {}", value))),
+    };
+    let mut items = list.borrow().clone();
+    let mut failure = None;
+    items.sort_by(|left, right| {
+        if failure.is_some() {
+            return Ordering::Equal;
+        }
+        match order(vm, &args[1], left, right) {
+            Ok(ordering) => ordering,
+            Err(error) => {
+                failure = Some(error);
+                Ordering::Equal
+            }
+        }
+    });
+    if let Some(error) = failure {
+        return Err(error);
+    }
+    *list.borrow_mut() = items;
+    Ok(args[0].clone())
+}
+
+fn order(
+    vm: &mut VM,
+    comparator: &Value,
+    left: &Value,
+    right: &Value,
+) -> Result<Ordering, NativeError> {
+    let order = match (comparator, left, right) {
+        (Value::Nil, Value::String(left), Value::String(right)) => {
+            return Ok(left.chars.cmp(&right.chars))
+        }
+        (Value::Nil, Value::Instance(_), _) => vm.compare(left.clone(), right.clone())?,
+        (Value::Nil, _, _) => match (left.as_number(), right.as_number()) {
+            (Some(left), Some(right)) => left - right,
+            _ => return Err(NativeError(format!("cannot compare {} and {}", left, right))),
+        },
+        _ => {
+            let order = vm.call(comparator, &[left.clone(), right.clone()])?;
+            order.as_number().ok_or_else(|| {
+                NativeError(format!("comparator must return a number but returned {}", order))
+            })?
+        }
+    };
+    order
+        .partial_cmp(&0.0)
+        .ok_or_else(|| NativeError(format!("cannot compare {} and {}", left, right)))
+}
+
+pub fn len(_: &mut VM, args: &[Value]) -> NativeResult {
+    let len = match &args[0] {
+        Value::List(list) => list.borrow().len(),
+        Value::Map(map) => map.borrow().len(),
+        Value::String(string) => string.chars().count(),
+        _ => return Err(NativeError("expected a list, map or string".to_owned())),
+    };
+    Ok(Value::Int(len as i64))
+}
+
+// The ints from start up to but not including end
+pub fn range(_: &mut VM, args: &[Value]) -> NativeResult {
+    match (&args[0], &args[1]) {
+        (Value::Int(start), Value::Int(end)) => {
+            let items = (*start..*end).map(Value::Int).collect();
+            Ok(Value::List(Rc::new(RefCell::new(items))))
+        }
+        _ => Err(NativeError(format!("expected two integers but got {} and {}", args[0], args[1]))),
+    }
+}
+
+// String positions and lengths count characters, like len does
+pub fn substr(_: &mut VM, args: &[Value]) -> NativeResult {
+    let string = string_arg(&args[0])?;
+    let start = count_arg(&args[1])?;
+    let len = count_arg(&args[2])?;
+    let part: String = string.chars().skip(start).take(len).collect();
+    Ok(Value::from(part.as_str()))
+}
+
+pub fn upper(_: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::from(string_arg(&args[0])?.to_uppercase().as_str()))
+}
+
+pub fn lower(_: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::from(string_arg(&args[0])?.to_lowercase().as_str()))
+}
+
+// Position of the first match, -1 when there is none
+pub fn index_of(_: &mut VM, args: &[Value]) -> NativeResult {
+    let string = string_arg(&args[0])?;
+    let needle = string_arg(&args[1])?;
+    let index = match string.find(needle) {
+        Some(byte) => string[..byte].chars().count() as i64,
+        None => -1,
+    };
+    Ok(Value::Int(index))
+}
+
+// Pieces between separators as a list, an empty separator splits every character
+pub fn split(_: &mut VM, args: &[Value]) -> NativeResult {
+    let string = string_arg(&args[0])?;
+    let separator = string_arg(&args[1])?;
+    let pieces: Vec<Value> = if separator.is_empty() {
+        string.chars().map(|c| Value::from(c.to_string().as_str())).collect()
+    } else {
+        string.split(separator).map(Value::from).collect()
+    };
+    Ok(Value::List(Rc::new(RefCell::new(pieces))))
+}
+
+pub fn sqrt(_: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::Double(number_arg(&args[0])?.sqrt()))
+}
+
+// Ints stay ints, except for the one whose absolute value does not fit
+pub fn abs(_: &mut VM, args: &[Value]) -> NativeResult {
+    match args[0] {
+        Value::Int(v) => Ok(v.checked_abs().map_or(Value::Double((v as f64).abs()), Value::Int)),
+        _ => Ok(Value::Double(number_arg(&args[0])?.abs())),
+    }
+}
+
+pub fn floor(_: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(whole(number_arg(&args[0])?.floor()))
+}
+
+pub fn ceil(_: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(whole(number_arg(&args[0])?.ceil()))
+}
+
+// The smaller argument itself, so an Int stays an Int
+pub fn min(_: &mut VM, args: &[Value]) -> NativeResult {
+    let (left, right) = (number_arg(&args[0])?, number_arg(&args[1])?);
+    Ok(if right < left { args[1].clone() } else { args[0].clone() })
+}
+
+pub fn max(_: &mut VM, args: &[Value]) -> NativeResult {
+    let (left, right) = (number_arg(&args[0])?, number_arg(&args[1])?);
+    Ok(if right > left { args[1].clone() } else { args[0].clone() })
+}
+
+// Exact for an Int raised to a small non-negative Int, a double otherwise
+pub fn pow(_: &mut VM, args: &[Value]) -> NativeResult {
+    if let (Value::Int(base), Value::Int(exponent)) = (&args[0], &args[1]) {
+        let exact = u32::try_from(*exponent).ok().and_then(|exponent| base.checked_pow(exponent));
+        if let Some(value) = exact {
+            return Ok(Value::Int(value));
+        }
+    }
+    Ok(Value::Double(number_arg(&args[0])?.powf(number_arg(&args[1])?)))
+}
+
+// A rounded double as an Int when it fits, infinities and NaN stay doubles
+fn whole(value: f64) -> Value {
+    if value >= i64::MIN as f64 && value < i64::MAX as f64 {
+        Value::Int(value as i64)
+    } else {
+        Value::Double(value)
+    }
+}
+
+// The next line of input without its line break, nil once the input is exhausted
+pub fn read_line(vm: &mut VM, _: &[Value]) -> NativeResult {
+    let mut line = String::new();
+    let read = match vm.input.as_mut() {
+        Some(input) => input.read_line(&mut line),
+        None => io::stdin().read_line(&mut line),
+    };
+    match read.map_err(|error| NativeError(error.to_string()))? {
+        0 => Ok(Value::Nil),
+        _ => {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            Ok(Value::from(line.strip_suffix('\r').unwrap_or(line)))
+        }
+    }
+}
+
+// Everything left in the input, an empty string when nothing is
+pub fn read_all(vm: &mut VM, _: &[Value]) -> NativeResult {
+    let mut text = String::new();
+    let read = match vm.input.as_mut() {
+        Some(input) => input.read_to_string(&mut text),
+        None => io::stdin().read_to_string(&mut text),
+    };
+    read.map_err(|error| NativeError(error.to_string()))?;
+    Ok(Value::from(text.as_str()))
+}
+
+// Fails with the message and where the call is unless the condition holds
+pub fn assert(vm: &mut VM, args: &[Value]) -> NativeResult {
+    if args[0].is_truthy() {
+        return Ok(Value::Nil);
+    }
+    let line = vm.frames.last().and_then(|frame| frame.closure.function.chunk.line_at_ip(frame.ip));
+    Err(NativeError(format!(
+        "{} at {}:{}: {}",
+        error::ASSERTION_FAILED,
+        vm.source_name.as_deref().unwrap_or("script"),
+        line.map_or(0, |line| line + 1),
+        args[1]
+    )))
+}
+
+// Stands in for assert when assertions are turned off
+pub fn skip_assert(_: &mut VM, _: &[Value]) -> NativeResult {
+    Ok(Value::Nil)
+}
+
+pub fn read_file(_: &mut VM, args: &[Value]) -> NativeResult {
+    let path = string_arg(&args[0])?;
+    fs::read_to_string(path)
+        .map(|text| Value::from(text.as_str()))
+        .map_err(|error| NativeError(format!("could not read {}: {}", path, error)))
+}
+
+// Replaces the whole file, returns nil
+pub fn write_file(_: &mut VM, args: &[Value]) -> NativeResult {
+    let path = string_arg(&args[0])?;
+    let contents = string_arg(&args[1])?;
+    fs::write(path, contents)
+        .map(|_| Value::Nil)
+        .map_err(|error| NativeError(format!("could not write {}: {}", path, error)))
+}
+
+pub fn file_exists(_: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::Bool(Path::new(string_arg(&args[0])?).exists()))
+}
+
+fn number_arg(value: &Value) -> Result<f64, NativeError> {
+    value
+        .as_number()
+        .ok_or_else(|| NativeError(format!("expected a number but got {}", value)))
+}
+
+fn string_arg(value: &Value) -> Result<&str, NativeError> {
+    value
+        .as_str()
+        .ok_or_else(|| NativeError(format!("expected a string but got {}", value)))
+}
+
+fn map_arg(value: &Value) -> Result<&RefCell<LoxMap>, NativeError> {
+    match value {
+        Value::Map(map) => Ok(map),
+        _ => Err(NativeError(format!("expected a map but got {}", value))),
+    }
+}
+
+fn list_items(value: &Value) -> Result<Vec<Value>, NativeError> {
+    match value {
+        Value::List(list) => Ok(list.borrow().clone()),
+        _ => Err(NativeError(format!("expected a list but got {}", value))),
+    }
+}
+
+fn count_arg(value: &Value) -> Result<usize, NativeError> {
+    match value {
+        Value::Int(v) if *v >= 0 => Ok(*v as usize),
+        Value::Double(v) if *v >= 0.0 && v.fract() == 0.0 => Ok(*v as usize),
+        _ => Err(NativeError(format!("expected a non-negative integer but got {}", value))),
+    }
+}
@@ -1,9 +1,12 @@
 use std::fmt;
 
-#[derive(Debug,Clone, Copy)]
+#[derive(Debug,Clone, Copy,PartialEq)]
 pub enum OpCode {
     OpReturn,
+    // Indices up to 255, larger ones use OpConstantLong, so the byte encoding of the
+    // common case takes one operand byte and the rare one two
     OpConstant(usize),
+    OpConstantLong(usize),
     OpNegate,
     OpAdd,
     OpSubtract,
@@ -30,7 +33,30 @@ pub enum OpCode {
     OpGetUpValue(usize),
     OpSetUpValue(usize),
     OpClosure,
-    OpCloseUpvalue
+    OpCloseUpvalue,
+    OpClass(usize),
+    OpMethod(usize),
+    OpGetProperty(usize),
+    OpSetProperty(usize),
+    // Property name constant and argument count, calls a method without binding it first
+    OpInvoke(usize, usize),
+    OpBuildList(usize),
+    OpBuildMap(usize),
+    OpIndexGet,
+    OpIndexSet,
+    // Pops the value and unwinds to the innermost handler, or ends the script with it
+    OpThrow,
+    // Offset to the handler, which finds the thrown value on top of the stack
+    OpTry(usize),
+    OpPopHandler,
+    // Emitted by the peephole pass only: a jump taken on a truthy condition, and a run
+    // of pops in one instruction
+    OpJumpIfTrue(usize),
+    OpPopN(usize),
+    // Specialized forms installed by the VM at hot sites, never emitted by the compiler
+    OpAddNumber,
+    // Prints a bare expression the REPL was given, in the form a value is inspected
+    OpEcho,
 }
 
 impl fmt::Display for OpCode {
@@ -38,6 +64,7 @@ impl fmt::Display for OpCode {
         match self {
             OpCode::OpReturn => write!(f, "OpReturn"),
             OpCode::OpConstant(i) => write!(f, "OpConstant {}", i),
+            OpCode::OpConstantLong(i) => write!(f, "OpConstantLong {}", i),
             OpCode::OpNegate => write!(f,"OpNegate"),
             OpCode::OpAdd =>write!(f,"OpAdd"),
             OpCode::OpSubtract => write!(f,"OpSubtract"),
@@ -51,6 +78,7 @@ impl fmt::Display for OpCode {
             OpCode::OpGreater =>write!(f,"OpGreater"),
             OpCode::OpLess => write!(f, "OpLess"),
             OpCode::OpPrint => write!(f,"OpPrint"),
+            OpCode::OpEcho => write!(f, "OpEcho"),
             OpCode::OpPop => write!(f,"OpPop"),
             OpCode::OpDefineGlobal(_)=>write!(f,"OpDefineGlobal"),
             OpCode::OpGetGlobal(_) => write!(f,"OpGetGloabl"),
@@ -64,12 +92,105 @@ impl fmt::Display for OpCode {
             OpCode::OpGetUpValue(_)=>write!(f,"OpGetUpValue"),
             OpCode::OpSetUpValue(_)=>write!(f,"OpSetUpValue"),
             OpCode::OpClosure => write!(f,"OpClosure"),
-            OpCode::OpCloseUpvalue => write!(f,"OpCloseUpvalue")
+            OpCode::OpCloseUpvalue => write!(f,"OpCloseUpvalue"),
+            OpCode::OpClass(_) => write!(f,"OpClass"),
+            OpCode::OpMethod(_) => write!(f,"OpMethod"),
+            OpCode::OpGetProperty(_) => write!(f,"OpGetProperty"),
+            OpCode::OpSetProperty(_) => write!(f,"OpSetProperty"),
+            OpCode::OpInvoke(..) => write!(f,"OpInvoke"),
+            OpCode::OpBuildList(_) => write!(f,"OpBuildList"),
+            OpCode::OpBuildMap(_) => write!(f,"OpBuildMap"),
+            OpCode::OpIndexGet => write!(f,"OpIndexGet"),
+            OpCode::OpIndexSet => write!(f,"OpIndexSet"),
+            OpCode::OpThrow => write!(f,"OpThrow"),
+            OpCode::OpTry(_) => write!(f,"OpTry"),
+            OpCode::OpPopHandler => write!(f,"OpPopHandler"),
+            OpCode::OpJumpIfTrue(_) => write!(f,"OpJumpIfTrue"),
+            OpCode::OpPopN(_) => write!(f,"OpPopN"),
+            OpCode::OpAddNumber => write!(f,"OpAddNumber")
             // _ => write!(f, "Unknown OpCode...\n"),
         }
     }
 }
 
+impl OpCode {
+    // The form of OpConstant that fits index
+    pub fn constant(index: usize) -> OpCode {
+        if index <= u8::MAX as usize {
+            OpCode::OpConstant(index)
+        } else {
+            OpCode::OpConstantLong(index)
+        }
+    }
+
+    // Values popped and pushed by the instruction
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            OpCode::OpReturn => (1, 0),
+            OpCode::OpConstant(_) | OpCode::OpConstantLong(_) => (0, 1),
+            OpCode::OpNegate | OpCode::OpNot => (1, 1),
+            OpCode::OpAdd
+            | OpCode::OpAddNumber
+            | OpCode::OpSubtract
+            | OpCode::OpMultiply
+            | OpCode::OpDivide
+            | OpCode::OpEqual
+            | OpCode::OpGreater
+            | OpCode::OpLess => (2, 1),
+            OpCode::OpNil | OpCode::OpTrue | OpCode::OpFalse => (0, 1),
+            OpCode::OpPrint | OpCode::OpEcho | OpCode::OpPop | OpCode::OpDefineGlobal(_) => (1, 0),
+            OpCode::OpGetGlobal(_) | OpCode::OpGetLocal(_) | OpCode::OpGetUpValue(_) => (0, 1),
+            OpCode::OpSetGlobal(_) | OpCode::OpSetLocal(_) | OpCode::OpSetUpValue(_) => (1, 1),
+            OpCode::OpJumpIfFalse(_) | OpCode::OpJumpIfTrue(_) => (1, 1),
+            OpCode::OpPopN(count) => (*count, 0),
+            OpCode::OpJump(_) | OpCode::OpLoop(_) => (0, 0),
+            OpCode::OpCall(arg_count) => (arg_count + 1, 1),
+            OpCode::OpClosure => (1, 1),
+            OpCode::OpCloseUpvalue => (1, 0),
+            OpCode::OpClass(_) => (0, 1),
+            OpCode::OpMethod(_) => (1, 0),
+            OpCode::OpGetProperty(_) => (1, 1),
+            OpCode::OpSetProperty(_) => (2, 1),
+            OpCode::OpInvoke(_, arg_count) => (arg_count + 1, 1),
+            OpCode::OpBuildList(count) => (*count, 1),
+            OpCode::OpBuildMap(count) => (count * 2, 1),
+            OpCode::OpIndexGet => (2, 1),
+            OpCode::OpIndexSet => (3, 1),
+            OpCode::OpThrow => (1, 0),
+            OpCode::OpTry(_) | OpCode::OpPopHandler => (0, 0),
+        }
+    }
+
+    pub fn operand(&self) -> Option<usize> {
+        match self {
+            OpCode::OpConstant(i)
+            | OpCode::OpConstantLong(i)
+            | OpCode::OpDefineGlobal(i)
+            | OpCode::OpGetGlobal(i)
+            | OpCode::OpSetGlobal(i)
+            | OpCode::OpGetLocal(i)
+            | OpCode::OpSetLocal(i)
+            | OpCode::OpJumpIfFalse(i)
+            | OpCode::OpJump(i)
+            | OpCode::OpLoop(i)
+            | OpCode::OpCall(i)
+            | OpCode::OpGetUpValue(i)
+            | OpCode::OpSetUpValue(i)
+            | OpCode::OpClass(i)
+            | OpCode::OpMethod(i)
+            | OpCode::OpGetProperty(i)
+            | OpCode::OpSetProperty(i)
+            | OpCode::OpInvoke(i, _)
+            | OpCode::OpBuildList(i)
+            | OpCode::OpBuildMap(i)
+            | OpCode::OpTry(i)
+            | OpCode::OpJumpIfTrue(i)
+            | OpCode::OpPopN(i) => Some(*i),
+            _ => None,
+        }
+    }
+}
+
 pub fn test() {
     let mut chunk = vec![OpCode::OpReturn];
     chunk.push(OpCode::OpConstant(1));
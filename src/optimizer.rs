@@ -0,0 +1,239 @@
+use std::rc::Rc;
+
+use crate::{
+    chunk::{Chunk, Function, Value},
+    compiler::Compiler,
+    op_code::OpCode,
+    trace_event,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub enum OptLevel {
+    O0,
+    #[default]
+    O1,
+    O2,
+}
+
+impl OptLevel {
+    pub fn from_flag(flag: &str) -> Option<OptLevel> {
+        match flag {
+            "-O0" => Some(OptLevel::O0),
+            "-O1" => Some(OptLevel::O1),
+            "-O2" => Some(OptLevel::O2),
+            _ => None,
+        }
+    }
+
+    // Switch on the optimizations that happen while compiling
+    pub fn configure(&self, compiler: &mut Compiler) {
+        compiler.fold = *self >= OptLevel::O1;
+        compiler.inline = *self >= OptLevel::O2;
+    }
+}
+
+// A rewrite over a finished chunk, returning how many instructions it changed
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn run(&self, chunk: &mut Chunk) -> usize;
+}
+
+// Point jumps that land on another jump straight at the final target
+pub struct JumpThreading;
+
+impl JumpThreading {
+    fn target(index: usize, code: &OpCode) -> Option<usize> {
+        match code {
+            OpCode::OpJump(offset) | OpCode::OpJumpIfFalse(offset) => Some(index + offset),
+            OpCode::OpLoop(offset) => index.checked_sub(*offset),
+            _ => None,
+        }
+    }
+
+    fn retarget(index: usize, code: &OpCode, target: usize) -> OpCode {
+        match code {
+            OpCode::OpJumpIfFalse(_) => OpCode::OpJumpIfFalse(target - index),
+            _ if target > index => OpCode::OpJump(target - index),
+            _ => OpCode::OpLoop(index - target),
+        }
+    }
+}
+
+impl Pass for JumpThreading {
+    fn name(&self) -> &'static str {
+        "jump-threading"
+    }
+
+    fn run(&self, chunk: &mut Chunk) -> usize {
+        let mut changed = 0;
+        for index in 0..chunk.codes.len() {
+            let code = chunk.codes[index];
+            let mut target = match JumpThreading::target(index, &code) {
+                Some(target) => target,
+                None => continue,
+            };
+            // Bounded so a cycle of jumps cannot keep us here
+            for _ in 0..chunk.codes.len() {
+                let next = match (code, chunk.codes.get(target)) {
+                    (_, Some(OpCode::OpJump(offset))) => target + offset,
+                    // The condition is still on the stack and still false
+                    (OpCode::OpJumpIfFalse(_), Some(OpCode::OpJumpIfFalse(offset))) => {
+                        target + offset
+                    }
+                    _ => break,
+                };
+                if next == target {
+                    break;
+                }
+                target = next;
+            }
+            // A conditional jump can only go forward
+            if matches!(code, OpCode::OpJumpIfFalse(_)) && target <= index {
+                continue;
+            }
+            let threaded = JumpThreading::retarget(index, &code, target);
+            if threaded != code {
+                chunk.codes[index] = threaded;
+                changed += 1;
+            }
+        }
+        changed
+    }
+}
+
+// Rewrite short instruction sequences into fewer or cheaper ones:
+// - a negated condition jumps on the original value instead
+// - a constant condition decides its jump at compile time
+// - a run of pops becomes a single OpPopN
+// Conditions are only rewritten where both ways out pop them, as in if and while.
+pub struct Peephole;
+
+impl Peephole {
+    fn constant_truthiness(chunk: &Chunk, code: &OpCode) -> Option<bool> {
+        match code {
+            OpCode::OpTrue => Some(true),
+            OpCode::OpFalse | OpCode::OpNil => Some(false),
+            OpCode::OpConstant(index) | OpCode::OpConstantLong(index) => {
+                chunk.values.get(*index).map(Value::is_truthy)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Pass for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+
+    fn run(&self, chunk: &mut Chunk) -> usize {
+        let len = chunk.codes.len();
+        let mut targets = vec![false; len + 1];
+        for (index, code) in chunk.codes.iter().enumerate() {
+            if let Some(target) = Chunk::jump_target(index, code) {
+                targets[target.min(len)] = true;
+            }
+        }
+        let mut deleted = vec![false; len];
+        let mut changed = 0;
+
+        let mut index = 0;
+        while index + 2 < len {
+            let target = match chunk.codes[index + 1] {
+                OpCode::OpJumpIfFalse(offset) => index + 1 + offset,
+                _ => {
+                    index += 1;
+                    continue;
+                }
+            };
+            let is_condition = chunk.codes[index + 2] == OpCode::OpPop
+                && chunk.codes.get(target) == Some(&OpCode::OpPop)
+                && !targets[index + 1]
+                && !targets[index + 2];
+            if !is_condition {
+                index += 1;
+                continue;
+            }
+            if chunk.codes[index] == OpCode::OpNot {
+                deleted[index] = true;
+                chunk.codes[index + 1] = OpCode::OpJumpIfTrue(target - index - 1);
+                changed += 1;
+            } else if let Some(truthy) = Peephole::constant_truthiness(chunk, &chunk.codes[index]) {
+                // Falls through into the then branch, or goes straight past the pop
+                // that starts the else branch
+                if !truthy {
+                    chunk.codes[index] = OpCode::OpJump(target + 1 - index);
+                    targets[target + 1] = true;
+                } else {
+                    deleted[index] = true;
+                }
+                deleted[index + 1] = true;
+                deleted[index + 2] = true;
+                changed += 1;
+            }
+            index += 3;
+        }
+
+        let mut index = 0;
+        while index < len {
+            let mut end = index;
+            while end < len
+                && chunk.codes[end] == OpCode::OpPop
+                && !deleted[end]
+                && (end == index || !targets[end])
+            {
+                end += 1;
+            }
+            if end - index > 1 {
+                chunk.codes[index] = OpCode::OpPopN(end - index);
+                for flag in deleted[index + 1..end].iter_mut() {
+                    *flag = true;
+                }
+                changed += 1;
+            }
+            index = end.max(index + 1);
+        }
+
+        if changed > 0 {
+            chunk.delete_codes(&deleted);
+        }
+        changed
+    }
+}
+
+pub struct PassManager {
+    pub passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    pub fn for_level(level: OptLevel) -> Self {
+        let mut passes: Vec<Box<dyn Pass>> = vec![];
+        if level >= OptLevel::O1 {
+            passes.push(Box::new(Peephole));
+            passes.push(Box::new(JumpThreading));
+        }
+        PassManager { passes }
+    }
+
+    // Optimized copy of the function and every function nested in its constants
+    pub fn run(
+        &self,
+        function: &Function,
+        report: &mut Vec<(String, &'static str, usize)>,
+    ) -> Function {
+        let mut function = function.clone();
+        for pass in self.passes.iter() {
+            let changed = pass.run(&mut function.chunk);
+            trace_event!(debug, function = %function, pass = pass.name(), changed, "pass");
+            report.push((function.to_string(), pass.name(), changed));
+        }
+        for value in function.chunk.values.iter_mut() {
+            if let Value::Function(nested) = value {
+                if nested.lazy.is_none() {
+                    *value = Value::Function(Rc::new(self.run(nested, report)));
+                }
+            }
+        }
+        function
+    }
+}
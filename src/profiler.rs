@@ -0,0 +1,112 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io::{self, Write},
+    mem::{self, Discriminant},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{chunk::Function, op_code::OpCode};
+
+#[derive(Debug, Clone)]
+pub struct FunctionStats {
+    pub function: Rc<Function>,
+    pub calls: u64,
+    // Instructions run in the function itself, not in the functions it calls
+    pub instructions: u64,
+    // Wall time from entering the function until it returns, callees included,
+    // counted once for recursive calls
+    pub time: Duration,
+}
+
+// Counts gathered while VM::profile is set, read back once the script ends
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub functions: Vec<FunctionStats>,
+    // Keyed by address, the Rc in FunctionStats keeps it from being reused
+    index: HashMap<*const Function, usize>,
+    // One count per kind of instruction, with an example to name it by
+    opcodes: HashMap<Discriminant<OpCode>, (OpCode, u64)>,
+    // Stats index and start time of every active call, innermost last
+    active: Vec<(usize, Instant)>,
+}
+
+impl Profile {
+    fn stats(&mut self, function: &Rc<Function>) -> usize {
+        let functions = &mut self.functions;
+        *self.index.entry(Rc::as_ptr(function)).or_insert_with(|| {
+            functions.push(FunctionStats {
+                function: function.clone(),
+                calls: 0,
+                instructions: 0,
+                time: Duration::ZERO,
+            });
+            functions.len() - 1
+        })
+    }
+
+    pub fn enter(&mut self, function: &Rc<Function>) {
+        let index = self.stats(function);
+        self.functions[index].calls += 1;
+        self.active.push((index, Instant::now()));
+    }
+
+    pub fn exit(&mut self) {
+        if let Some((index, start)) = self.active.pop() {
+            // A recursive call is already timed by the outermost one
+            if self.active.iter().all(|&(active, _)| active != index) {
+                self.functions[index].time += start.elapsed();
+            }
+        }
+    }
+
+    pub fn instruction(&mut self, code: OpCode) {
+        if let Some(&(index, _)) = self.active.last() {
+            self.functions[index].instructions += 1;
+        }
+        self.opcodes.entry(mem::discriminant(&code)).or_insert((code, 0)).1 += 1;
+    }
+
+    // Instruction names with how often each ran, most frequent first
+    pub fn opcodes(&self) -> Vec<(String, u64)> {
+        let mut opcodes: Vec<(String, u64)> = self
+            .opcodes
+            .values()
+            .map(|(code, count)| {
+                // Display adds the operands after a space
+                let name = code.to_string();
+                (name.split(' ').next().unwrap_or_default().to_owned(), *count)
+            })
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        opcodes
+    }
+
+    pub fn report(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        // Calls cut short by an error still count up to now
+        while !self.active.is_empty() {
+            self.exit();
+        }
+        let mut functions: Vec<&FunctionStats> = self.functions.iter().collect();
+        functions.sort_by_key(|stats| Reverse(stats.time));
+        writeln!(out, "== Profile ==")?;
+        writeln!(out, "{:<24} {:>10} {:>14} {:>14}", "function", "calls", "instructions", "time")?;
+        for stats in functions {
+            writeln!(
+                out,
+                "{:<24} {:>10} {:>14} {:>14}",
+                stats.function.to_string(),
+                stats.calls,
+                stats.instructions,
+                format!("{:?}", stats.time)
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:<24} {:>10}", "instruction", "count")?;
+        for (name, count) in self.opcodes() {
+            writeln!(out, "{:<24} {:>10}", name, count)?;
+        }
+        Ok(())
+    }
+}
@@ -1,23 +1,44 @@
-use crate::{token::{Token, TokenType}, util};
+use crate::{error, token::{Token, TokenType}, util};
 
 pub struct Scanner {
     pub source: String,
     pub current: usize,
     pub start: usize,
     pub line: i32,
+    // Position of source[0] in the whole file, non-zero when scanning a lazy body
+    pub offset: usize,
+    // Position in the whole file where the current line begins
+    pub line_start: usize,
+    // Column of the token being scanned, taken before it can cross a newline
+    pub column: usize,
+    // Bytes of the current line that continue a multi-byte character, so columns
+    // count characters
+    pub continuation_bytes: usize,
 }
 
 impl Scanner {
     pub fn new(source: String) -> Scanner {
         Scanner {
-            source: source,
+            source,
             current: 0,
             start: 0,
             line: 0,
+            offset: 0,
+            line_start: 0,
+            column: 0,
+            continuation_bytes: 0,
         }
     }
 
-    pub fn skip_whitespace(&mut self) {
+    // Called on a newline that has not been consumed yet
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.offset + self.current + 1;
+        self.continuation_bytes = 0;
+    }
+
+    // False when a block comment is still open at the end of the source
+    pub fn skip_whitespace(&mut self) -> bool {
         loop {
             match self.peek() {
                 b'\r' | b' ' | b'\t' => {
@@ -25,24 +46,50 @@ impl Scanner {
                     continue;
                 }
                 b'\n' => {
-                    self.line += 1;
+                    self.newline();
                     self.advance();
                     continue;
                 }
-                b'/' => {
-                    if self.peek_next() == b'/' {
-                        while !self.is_at_end() && self.peek() != b'\n' {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                b'/' if self.peek_next() == b'/' => {
+                    while !self.is_at_end() && self.peek() != b'\n' {
+                        self.advance();
                     }
                 }
-                _ => return,
+                b'/' if self.peek_next() == b'*' => {
+                    if !self.skip_block_comment() {
+                        return false;
+                    }
+                }
+                _ => return true,
             }
         }
     }
 
+    // Block comments nest, so /* a /* b */ c */ is one comment
+    pub fn skip_block_comment(&mut self) -> bool {
+        let mut depth = 0;
+        while !self.is_at_end() {
+            match (self.peek(), self.peek_next()) {
+                (b'/', b'*') => {
+                    depth += 1;
+                    self.advance();
+                }
+                (b'*', b'/') => {
+                    depth -= 1;
+                    self.advance();
+                    if depth == 0 {
+                        self.advance();
+                        return true;
+                    }
+                }
+                (b'\n', _) => self.newline(),
+                _ => {}
+            }
+            self.advance();
+        }
+        false
+    }
+
     pub fn peek_next(&self) -> u8 {
         let index = self.current + 1;
         if index < self.source.len() {
@@ -53,13 +100,37 @@ impl Scanner {
     }
 
     pub fn scan(&mut self) -> Token {
-        self.skip_whitespace();
+        let mut token = self.scan_token();
+        token.start = self.offset + self.start;
+        token.length = self.current - self.start;
+        token.column = self.column;
+        token
+    }
+
+    fn scan_token(&mut self) -> Token {
+        let is_closed = self.skip_whitespace();
 
         self.start = self.current;
+        self.column = (self.offset + self.start)
+            .saturating_sub(self.line_start + self.continuation_bytes)
+            + 1;
+        if !is_closed {
+            return Token::new(TokenType::Error, error::UNTERMINATED_COMMENT, self.line);
+        }
         if self.is_at_end() {
             return self.token(TokenType::Eof);
         }
 
+        if !self.peek().is_ascii() {
+            // Letters from any script start an identifier, other characters are taken
+            // whole so no token splits one
+            let c = self.advance_char();
+            if c.is_alphabetic() {
+                return self.identifier_token();
+            }
+            return self.token(TokenType::Error);
+        }
+
         let c = self.advance();
 
         if util::is_alpha(c) {
@@ -71,7 +142,11 @@ impl Scanner {
             b')' => self.token(TokenType::RightParen),
             b'{' => self.token(TokenType::LeftBrace),
             b'}' => self.token(TokenType::RightBrace),
+            b'[' => self.token(TokenType::LeftBracket),
+            b']' => self.token(TokenType::RightBracket),
             b';' => self.token(TokenType::SemiColon),
+            b'?' => self.token(TokenType::Question),
+            b':' => self.token(TokenType::Colon),
             b',' => self.token(TokenType::Comma),
             b'.' => self.token(TokenType::Dot),
             b'-' => self.token(TokenType::Minus),
@@ -111,19 +186,28 @@ impl Scanner {
                 self.token(token_type)
             }
             b'"' => self.string_token(),
-            b'1'..=b'9' => self.number_token(),
+            b'0' if matches!(self.peek(), b'x' | b'X' | b'b' | b'B') => self.radix_token(),
+            b'0'..=b'9' => self.number_token(),
             _ => self.token(TokenType::Error),
         }
     }
 
     pub fn identifier_token(&mut self)->Token {
-        while (util::is_alpha(self.peek()) || util::is_digit(self.peek())) && !self.is_at_end() {
-            self.advance();
+        loop {
+            let c = self.peek();
+            if util::is_alpha(c) || util::is_digit(c) {
+                self.advance();
+            } else if !c.is_ascii() && self.peek_char().is_alphanumeric() {
+                self.advance_char();
+            } else {
+                break;
+            }
         }
         match &self.source[self.start..self.current] {
             "and" => self.token(TokenType::And),
             "class" => self.token(TokenType::Class),
             "else"=>self.token(TokenType::Else),
+            "extern"=>self.token(TokenType::Extern),
             "if"=>self.token(TokenType::If),
             "nil"=>self.token(TokenType::Nil),
             "or"=>self.token(TokenType::Or),
@@ -132,6 +216,10 @@ impl Scanner {
             "super"=>self.token(TokenType::Super),
             "var"=>self.token(TokenType::Var),
             "while"=>self.token(TokenType::While),
+            "throw"=>self.token(TokenType::Throw),
+            "try"=>self.token(TokenType::Try),
+            "catch"=>self.token(TokenType::Catch),
+            "finally"=>self.token(TokenType::Finally),
             "false"=>self.token(TokenType::False),
             "for"=>self.token(TokenType::For),
             "fun"=>self.token(TokenType::Fun),
@@ -151,23 +239,61 @@ impl Scanner {
                 self.advance();
             }
         }
+        if matches!(self.peek(), b'e' | b'E') {
+            // An exponent, which needs digits after its optional sign
+            self.advance();
+            if matches!(self.peek(), b'+' | b'-') {
+                self.advance();
+            }
+            if !util::is_digit(self.peek()) {
+                return Token::new(TokenType::Error, error::INVALID_NUMBER, self.line);
+            }
+            while util::is_digit(self.peek()) && !self.is_at_end() {
+                self.advance();
+            }
+        }
+        self.token(TokenType::Number)
+    }
+
+    // 0x followed by hex digits or 0b by binary ones
+    pub fn radix_token(&mut self) -> Token {
+        let radix = if self.advance().eq_ignore_ascii_case(&b'x') { 16 } else { 2 };
+        let digits_start = self.current;
+        // Take every letter and digit so 0b12 is one bad literal rather than 0b1 then 2
+        while self.peek().is_ascii_alphanumeric() || self.peek() == b'_' {
+            self.advance();
+        }
+        let digits = &self.source[digits_start..self.current];
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Token::new(TokenType::Error, error::INVALID_NUMBER, self.line);
+        }
         self.token(TokenType::Number)
     }
 
     pub fn string_token(&mut self) -> Token {
         while self.peek() != b'"' && !self.is_at_end() {
+            if self.peek() == b'\\' {
+                // Step over the escaped byte too, so \" does not end the string
+                self.advance();
+                if self.is_at_end() {
+                    break;
+                }
+            }
             if self.peek() == b'\n' {
-                self.line += 1;
+                self.newline();
             }
             self.advance();
         }
 
         if self.is_at_end() {
-            return self.token(TokenType::Error);
+            return Token::new(TokenType::Error, error::UNTERMINATED_STRING, self.line);
         }
 
         self.advance();
-        self.token(TokenType::String)
+        match util::unescape(&self.source[self.start + 1..self.current - 1]) {
+            Some(chars) => Token::new(TokenType::String, &chars, self.line),
+            None => Token::new(TokenType::Error, error::INVALID_ESCAPE, self.line),
+        }
     }
 
     pub fn is_at_end(&self) -> bool {
@@ -176,7 +302,24 @@ impl Scanner {
 
     pub fn advance(&mut self) -> u8 {
         self.current += 1;
-        self.source.as_bytes()[self.current - 1]
+        let c = self.source.as_bytes()[self.current - 1];
+        if c & 0xc0 == 0x80 {
+            self.continuation_bytes += 1;
+        }
+        c
+    }
+
+    // The character at current, which must start one
+    pub fn peek_char(&self) -> char {
+        self.source[self.current..].chars().next().unwrap_or('\0')
+    }
+
+    pub fn advance_char(&mut self) -> char {
+        let c = self.peek_char();
+        for _ in 0..c.len_utf8() {
+            self.advance();
+        }
+        c
     }
 
     pub fn token(&self, token_type: TokenType) -> Token {
@@ -211,6 +354,35 @@ impl Scanner {
     }
 
     pub fn peek(&self) -> u8 {
+        if self.is_at_end() {
+            return b'\0';
+        }
         self.source.as_bytes()[self.current]
     }
 }
+
+// Every token up to but not including Eof, error tokens included. A separate type so
+// Iterator::scan does not shadow Scanner::scan.
+pub struct Tokens(pub Scanner);
+
+impl Iterator for Tokens {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.0.scan();
+        if token.token_type == TokenType::Eof {
+            None
+        } else {
+            Some(token)
+        }
+    }
+}
+
+impl IntoIterator for Scanner {
+    type Item = Token;
+    type IntoIter = Tokens;
+
+    fn into_iter(self) -> Tokens {
+        Tokens(self)
+    }
+}
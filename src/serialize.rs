@@ -0,0 +1,113 @@
+use std::{cell::RefCell, convert::TryFrom, fmt, rc::Rc};
+
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::{self, SerializeMap, SerializeSeq},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::chunk::{LoxMap, LoxString, Value};
+
+// Only data crosses over: nil, booleans, numbers, strings, lists and maps.
+// Functions, classes and instances belong to the VM that made them.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Bool(value) => serializer.serialize_bool(*value),
+            Value::Double(value) => serializer.serialize_f64(*value),
+            Value::Int(value) => serializer.serialize_i64(*value),
+            Value::String(value) => serializer.serialize_str(value.as_str()),
+            Value::List(list) => {
+                let list = list
+                    .try_borrow()
+                    .map_err(|_| ser::Error::custom("cannot serialize a list that contains itself"))?;
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for value in list.iter() {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Value::Map(map) => {
+                let map = map
+                    .try_borrow()
+                    .map_err(|_| ser::Error::custom("cannot serialize a map that contains itself"))?;
+                let mut entries = serializer.serialize_map(Some(map.len()))?;
+                for (key, value) in map.iter() {
+                    entries.serialize_entry(key, value)?;
+                }
+                entries.end()
+            }
+            value => Err(ser::Error::custom(format!("cannot serialize {}", value))),
+        }
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nil, a boolean, number, string, list or map")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Value, E> {
+        Ok(Value::Bool(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Value, E> {
+        Ok(Value::Int(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Value, E> {
+        Ok(i64::try_from(value).map_or(Value::Double(value as f64), Value::Int))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Value, E> {
+        Ok(Value::Double(value))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Value, E> {
+        Ok(Value::String(Rc::new(LoxString::from(value))))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut list = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            list.push(value);
+        }
+        Ok(Value::List(Rc::new(RefCell::new(list))))
+    }
+
+    // Keys follow the rules of map literals, strings and numbers only
+    #[allow(clippy::mutable_key_type)]
+    fn visit_map<A: MapAccess<'de>>(self, mut entries: A) -> Result<Value, A::Error> {
+        let mut map = LoxMap::with_capacity(entries.size_hint().unwrap_or(0));
+        while let Some((key, value)) = entries.next_entry::<Value, Value>()? {
+            if !matches!(key, Value::String(_) | Value::Double(_) | Value::Int(_)) {
+                return Err(de::Error::custom(crate::error::INVALID_MAP_KEY));
+            }
+            map.insert(key, value);
+        }
+        Ok(Value::Map(Rc::new(RefCell::new(map))))
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
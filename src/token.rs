@@ -1,8 +1,15 @@
+use std::rc::Rc;
+
+// Lexemes are shared, so the compiler can hold on to tokens without copying text
 #[derive(Debug,Clone)]
 pub struct Token {
     pub token_type: TokenType,
-    pub lexeme: String,
+    pub lexeme: Rc<str>,
     pub line: i32,
+    // Byte offset and length in the whole source, and the 1-based column it starts at
+    pub start: usize,
+    pub length: usize,
+    pub column: usize,
 }
 
 #[derive(Debug,Clone, Copy,PartialEq)]
@@ -11,11 +18,15 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
     Plus,
     SemiColon,
+    Question,
+    Colon,
     Slash,
     Star,
     Bang,
@@ -30,6 +41,7 @@ pub enum TokenType {
     And,
     Class,
     Else,
+    Extern,
     False,
     For,
     Fun,
@@ -43,6 +55,10 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Throw,
+    Try,
+    Catch,
+    Finally,
     Equal,
     EqualEqual,
 
@@ -53,16 +69,25 @@ pub enum TokenType {
 impl<'a> Token {
     pub fn new(token_type: TokenType, lexeme: &'a str, line: i32) -> Token {
         Token {
-            token_type: token_type,
-            lexeme: lexeme.to_owned(),
-            line: line,
+            token_type,
+            lexeme: Rc::from(lexeme),
+            line,
+            start: 0,
+            length: 0,
+            column: 0,
         }
     }
-    pub fn default() -> Token {
+}
+
+impl Default for Token {
+    fn default() -> Token {
         Token {
             token_type: TokenType::Error,
-            lexeme: String::from(""),
+            lexeme: Rc::from(""),
             line: 0,
+            start: 0,
+            length: 0,
+            column: 0,
         }
     }
 }
@@ -1,9 +1,104 @@
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
 pub fn is_digit(c:u8) -> bool {
-    c>=b'0' && c<=b'9'
+    c.is_ascii_digit()
 }
 
 pub fn is_alpha(c:u8) -> bool {
-    (c>= b'a' && c<= b'z') || (c>=b'A' && c<=b'Z') || (c==b'_')
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+// Contents of a string literal with its escapes replaced, None if one is invalid
+pub fn unescape(raw: &str) -> Option<String> {
+    let mut chars = String::with_capacity(raw.len());
+    let mut iter = raw.chars();
+    while let Some(c) = iter.next() {
+        if c != '\\' {
+            chars.push(c);
+            continue;
+        }
+        let escaped = match iter.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '\\' => '\\',
+            '"' => '"',
+            'u' => {
+                if iter.next()? != '{' {
+                    return None;
+                }
+                let mut hex = String::new();
+                loop {
+                    match iter.next()? {
+                        '}' => break,
+                        digit => hex.push(digit),
+                    }
+                }
+                if hex.is_empty() || hex.len() > 6 {
+                    return None;
+                }
+                char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+            }
+            _ => return None,
+        };
+        chars.push(escaped);
+    }
+    Some(chars)
+}
+
+// Length of the block comment text starts with, all of it when the comment is unterminated
+pub fn block_comment_len(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    let mut depth = 0;
+    let mut index = 0;
+    while index + 1 < bytes.len() {
+        match (bytes[index], bytes[index + 1]) {
+            (b'/', b'*') => {
+                depth += 1;
+                index += 2;
+            }
+            (b'*', b'/') => {
+                depth -= 1;
+                index += 2;
+                if depth == 0 {
+                    return index;
+                }
+            }
+            _ => index += 1,
+        }
+    }
+    text.len()
+}
+
+// Digits and base of a 0x or 0b literal
+fn radix_digits(lexeme: &str) -> Option<(&str, u32)> {
+    match lexeme.get(..2) {
+        Some("0x") | Some("0X") => Some((&lexeme[2..], 16)),
+        Some("0b") | Some("0B") => Some((&lexeme[2..], 2)),
+        _ => None,
+    }
+}
+
+// Value of a whole number literal, None for fractions and numbers too large for an Int
+pub fn parse_int(lexeme: &str) -> Option<i64> {
+    match radix_digits(lexeme) {
+        Some((digits, radix)) => i64::from_str_radix(digits, radix).ok(),
+        None => lexeme.parse().ok(),
+    }
+}
+
+pub fn parse_double(lexeme: &str) -> f64 {
+    match radix_digits(lexeme) {
+        Some((digits, radix)) => digits
+            .chars()
+            .filter_map(|c| c.to_digit(radix))
+            .fold(0.0, |value, digit| value * radix as f64 + digit as f64),
+        None => lexeme.parse().unwrap_or(0.0),
+    }
 }
 
 #[macro_export]
@@ -16,19 +111,54 @@ macro_rules! matches {
     };
 }
 
+// Forwards to the tracing crate with the tracing feature, compiled out otherwise
+#[macro_export]
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+    };
+}
+
+#[macro_export]
+macro_rules! trace_span {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($($arg)+).entered();
+    };
+}
+
 #[macro_export]
 macro_rules! binary_op {
-    ($self:ident,$val_type:ident,$op:tt) => {
-        if let Value::Double(right_v) = $self.peek(0) {
-            if let Value::Double(left_v) = $self.peek(1) {
-                $self.slots.borrow_mut().push(Value::$val_type(left_v $op right_v));
-                // Pop values
-                $self.get_stack_value()?;
-                $self.get_stack_value()?;
-
-                continue;
-            }
+    ($self:ident,$code:expr) => {
+        if let Some(value) = Value::arithmetic($code, &$self.peek(1)?, &$self.peek(0)?) {
+            // Pop values
+            $self.get_stack_value()?;
+            $self.get_stack_value()?;
+            $self.slots.borrow_mut().push(value);
+        } else {
+            return Err(VmError::RuntimeError(error::OPERAND_MUST_BE_NUMBER.to_owned()));
         }
-        return Err(VmError::RuntimeError(error::OPERAND_MUST_BE_NUMBER.to_owned()));
     };
-}
\ No newline at end of file
+}
+
+// A writer whose bytes stay readable through every clone, to capture VM output
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
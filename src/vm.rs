@@ -1,104 +1,572 @@
 use std::{
-    cell::{Ref, RefCell},
-    result,
+    cell::RefCell,
+    io::{self, BufRead, Write},
+    mem, result,
 };
 use std::{collections::HashMap, rc::Rc};
 
-use crate::error;
-use crate::{binary_op, chunk::Value};
+use crate::{debugger::Debugger, error, natives, profiler::Profile};
+use crate::{binary_op, chunk::Value, trace_event};
 use crate::{
-    chunk::{Closure, UpValue},
-    compiler::UpValueMeta,
+    chunk::{
+        Accessor, BoundMethod, CachedHashMap, CachedProperty, Chunk, Class, Closure, Function, Instance,
+        LoxMap, LoxString, Native, NativeError, NativeFnPointer, NativeResult, UpValue,
+    },
     op_code::OpCode,
 };
 
+// The stack, frames and handlers stay inside the crate: with unsafe-fast the dispatch loop
+// trusts them to match what the verifier proved, so natives and debuggers must not change them
 pub struct VM {
-    pub stack: Rc<RefCell<Vec<Value>>>,
+    pub(crate) stack: Rc<RefCell<Vec<Value>>>,
     pub heap: Vec<Value>,
-    pub globals: HashMap<String, Value>,
-    pub frames: Vec<CallFrame>,
+    pub globals: CachedHashMap<Rc<LoxString>, Value>,
+    pub(crate) frames: Vec<CallFrame>,
     pub upvalues: Vec<Rc<RefCell<UpValue>>>,
+    // Instructions executed so far, only counted when set to Some
+    pub instruction_count: Option<u64>,
+    // Print the stack and each instruction as it runs, off unless asked for with --trace
+    pub trace_execution: bool,
+    // Where print and the stack trace go, stdout unless the host swaps it
+    pub output: Box<dyn Write>,
+    // File the script came from, named by failed assertions
+    pub source_name: Option<String>,
+    // Where readLine and readAll read from, the process's stdin when None so the REPL
+    // and scripts share its buffer
+    pub input: Option<Box<dyn BufRead>>,
+    // Deepest call nesting allowed before a stack overflow error
+    pub max_frames: usize,
+    // Instructions left before the run is cut short, unlimited when None
+    pub budget: Option<u64>,
+    // Bytes allocated by the running script, only ever going up since nothing
+    // tells the VM when an Rc frees its value
+    pub bytes_allocated: usize,
+    // Cap on bytes_allocated, unlimited when None
+    pub max_heap_bytes: Option<usize>,
+    // Consulted before every instruction, see the debugger module
+    pub debugger: Option<Box<dyn Debugger>>,
+    // Per function and per instruction counts, only gathered when set to Some
+    pub profile: Option<Profile>,
+    // Active try blocks, innermost last
+    pub(crate) handlers: Vec<Handler>,
+    // What the last throw threw, so a catch gets the value itself rather than its message
+    pub thrown: Option<Value>,
+    // Set while interpret runs, so a native can't start another script over this one's stack
+    running: bool,
+}
+
+// Where a runtime error inside a try block resumes
+#[derive(Debug, Clone)]
+pub struct Handler {
+    // Frame count when the try block began, the handler runs in the last of them
+    pub frames: usize,
+    pub stack_len: usize,
+    pub ip: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct CallFrame {
     pub closure: Rc<Closure>,
     pub ip: usize,
-    pub slots: Rc<RefCell<Vec<Value>>>,
+    pub(crate) slots: Rc<RefCell<Vec<Value>>>,
     pub base: usize,
 }
 
-impl<'a> CallFrame {
+impl CallFrame {
     fn new(closure: Rc<Closure>, stack: Rc<RefCell<Vec<Value>>>, base: usize) -> CallFrame {
         CallFrame {
-            closure: closure,
+            closure,
             ip: 0,
             slots: stack,
-            base: base,
+            base,
         }
     }
-    pub fn show_stack(&self) {
-        print!("        ");
+    pub fn show_stack(&self, output: &mut dyn Write) -> io::Result<()> {
+        write!(output, "        ")?;
         for value in self.slots.borrow().iter() {
-            print!("[ {} ]", value)
+            write!(output, "[ {} ]", value)?;
         }
-        println!()
+        writeln!(output)
     }
 
     pub fn get_stack_value(&mut self) -> Result<Value> {
+        #[cfg(feature = "unsafe-fast")]
+        // SAFETY: the verifier proved the stack holds every value an instruction pops
+        unsafe {
+            Ok(self.slots.borrow_mut().pop().unwrap_unchecked())
+        }
+        #[cfg(not(feature = "unsafe-fast"))]
         self.slots
             .borrow_mut()
             .pop()
-            .ok_or(VmError::RuntimeError(error::EMPTY_STACK.to_owned()))
+            .ok_or_else(|| VmError::RuntimeError(error::EMPTY_STACK.to_owned()))
+    }
+
+    pub fn pop_values(&mut self, count: usize) -> Result<()> {
+        let mut slots = self.slots.borrow_mut();
+        let len = slots
+            .len()
+            .checked_sub(count)
+            .ok_or_else(|| VmError::RuntimeError(error::EMPTY_STACK.to_owned()))?;
+        slots.truncate(len);
+        Ok(())
     }
 
-    pub fn peek(&self, distance: usize) -> Value {
-        let slots_len = self.slots.borrow().len();
-        if slots_len < distance + 1 {
-            panic!("Error peek slots")
+    pub fn peek(&self, distance: usize) -> Result<Value> {
+        let slots = self.slots.borrow();
+        #[cfg(feature = "unsafe-fast")]
+        // SAFETY: as for pops, the verified depth covers every peek
+        unsafe {
+            Ok(slots.get_unchecked(slots.len() - 1 - distance).clone())
+        }
+        #[cfg(not(feature = "unsafe-fast"))]
+        slots
+            .len()
+            .checked_sub(distance + 1)
+            .map(|index| slots[index].clone())
+            .ok_or_else(|| VmError::RuntimeError(error::EMPTY_STACK.to_owned()))
+    }
+
+    pub fn local(&self, index: usize) -> Result<Value> {
+        let slots = self.slots.borrow();
+        #[cfg(feature = "unsafe-fast")]
+        // SAFETY: the verifier rejects local slots at or above the stack depth
+        unsafe {
+            Ok(slots.get_unchecked(self.base + index).clone())
+        }
+        #[cfg(not(feature = "unsafe-fast"))]
+        slots
+            .get(self.base + index)
+            .cloned()
+            .ok_or_else(|| VmError::RuntimeError(error::INVALID_LOCAL.to_owned()))
+    }
+
+    pub fn set_local(&self, index: usize, value: Value) -> Result<()> {
+        let mut slots = self.slots.borrow_mut();
+        #[cfg(feature = "unsafe-fast")]
+        // SAFETY: see local
+        unsafe {
+            *slots.get_unchecked_mut(self.base + index) = value;
+            Ok(())
+        }
+        #[cfg(not(feature = "unsafe-fast"))]
+        match slots.get_mut(self.base + index) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(VmError::RuntimeError(error::INVALID_LOCAL.to_owned())),
         }
-        self.slots.borrow()[slots_len - 1 - distance].clone()
     }
 }
 
 pub enum VmError {
     CompileError(String),
     RuntimeError(String),
+    // The script ran out of instructions given to interpret_with_budget
+    BudgetExceeded,
+    // The debugger ended the script, which no try block may catch
+    Stopped,
 }
 
 pub type Result<T> = result::Result<T, VmError>;
 
+// Lets a native pass on the error of a call it made back into the VM
+impl From<VmError> for NativeError {
+    fn from(error: VmError) -> Self {
+        match error {
+            VmError::CompileError(message) | VmError::RuntimeError(message) => NativeError(message),
+            VmError::BudgetExceeded => NativeError(error::BUDGET_EXCEEDED.to_owned()),
+            VmError::Stopped => NativeError(error::DEBUGGER_STOPPED.to_owned()),
+        }
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> Self {
-        VM {
+        let mut vm = VM::sandboxed();
+        natives::define_file_io(&mut vm);
+        vm
+    }
+
+    // A VM whose scripts can't reach the file system, for embedders running code they
+    // don't trust. Hosts can still hand it whatever natives they choose.
+    pub fn sandboxed() -> Self {
+        let mut vm = VM {
             stack: Rc::new(RefCell::new(vec![])),
-            globals: HashMap::new(),
+            globals: CachedHashMap::default(),
             frames: vec![],
             heap: vec![],
             upvalues: vec![],
+            instruction_count: None,
+            trace_execution: false,
+            output: Box::new(io::stdout()),
+            source_name: None,
+            input: None,
+            max_frames: 256,
+            budget: None,
+            bytes_allocated: 0,
+            max_heap_bytes: None,
+            debugger: None,
+            profile: None,
+            handlers: vec![],
+            thrown: None,
+            running: false,
+        };
+        natives::define_defaults(&mut vm);
+        vm
+    }
+    // A VM whose print statements and execution trace go to output instead of stdout
+    pub fn with_output(output: impl Write + 'static) -> Self {
+        let mut vm = VM::new();
+        vm.output = Box::new(output);
+        vm
+    }
+
+    // Make a host value visible to scripts as a global, before or between runs
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(Rc::new(LoxString::from(name)), value);
+    }
+
+    // Expose a host function to scripts, calls with a different argument count fail
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut VM, &[Value]) -> NativeResult + 'static,
+    ) {
+        let native = Native {
+            name: name.to_owned(),
+            arity,
+            function: Box::new(function),
+        };
+        self.set_global(name, Value::NativeFunction(Rc::new(native)));
+    }
+
+    // Group natives under one global instance, scripts then call them as Name.member(...)
+    pub fn define_namespace(&mut self, name: &str, members: &[(&str, usize, NativeFnPointer)]) {
+        let class = Rc::new(Class::new(Rc::new(LoxString::from(name))));
+        let namespace = Instance::new(class);
+        for &(member, arity, function) in members {
+            let native = Native {
+                name: format!("{}.{}", name, member),
+                arity,
+                function: Box::new(function),
+            };
+            let member = Rc::new(LoxString::from(member));
+            namespace.set_field(&member, Value::NativeFunction(Rc::new(native)));
+        }
+        self.set_global(name, Value::Instance(Rc::new(namespace)));
+    }
+
+    pub fn define_globals(&mut self, globals: HashMap<String, Value>) {
+        self.globals.extend(
+            globals
+                .into_iter()
+                .map(|(name, value)| (Rc::new(LoxString::new(name)), value)),
+        );
+    }
+
+    // Read back a value the script defined, e.g. after running a config script
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(&LoxString::from(name)).cloned()
+    }
+
+    pub fn iter_globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    // Replace global functions with their updated definitions from a recompiled script,
+    // leaving every old definition in place if any new body fails verification
+    pub fn swap_functions(&mut self, chunk: &Chunk) -> std::result::Result<(), String> {
+        for value in chunk.values.iter() {
+            if let Value::Function(function) = value {
+                function
+                    .verify()
+                    .map_err(|message| format!("{} in {}", message, function))?;
+            }
+        }
+        for value in chunk.values.iter() {
+            if let Value::Function(function) = value {
+                let name = Rc::new(LoxString::from(function.name.as_str()));
+                let is_same_function = match self.globals.get(&name) {
+                    Some(Value::Closure(closure)) => closure.function.name == function.name,
+                    _ => false,
+                };
+                if is_same_function {
+                    self.globals.insert(
+                        name,
+                        Value::Closure(Rc::new(Closure::new(function.clone()))),
+                    );
+                }
+            }
         }
+        Ok(())
+    }
+
+    // Run untrusted code, giving up once max_instructions opcodes have been dispatched
+    pub fn interpret_with_budget(&mut self, closure: Rc<Closure>, max_instructions: u64) -> Result<()> {
+        self.budget = Some(max_instructions);
+        let result = self.interpret(closure);
+        self.budget = None;
+        result
     }
+
     pub fn interpret(&mut self, closure: Rc<Closure>) -> Result<()> {
+        if self.running {
+            return Err(VmError::RuntimeError(error::ALREADY_RUNNING.to_owned()));
+        }
+        // Globals survive between scripts, anything left from a previous run does not
+        self.frames.clear();
+        self.stack.borrow_mut().clear();
+        self.handlers.clear();
+        self.thrown = None;
+        self.bytes_allocated = 0;
+
+        verify(&closure.function)?;
+
+        let mut missing = vec![];
+        for (name, arity) in closure.function.chunk.externs.iter() {
+            match self.globals.get(&LoxString::from(name.as_str())) {
+                Some(Value::NativeFunction(native)) if native.arity != *arity => {
+                    return Err(VmError::RuntimeError(format!(
+                        "{} {} declares {} but the native takes {}",
+                        error::EXTERN_ARITY,
+                        name,
+                        arity,
+                        native.arity
+                    )));
+                }
+                Some(Value::NativeFunction(_)) => {}
+                _ => missing.push(name.as_str()),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(VmError::RuntimeError(format!(
+                "{} {}",
+                error::UNBOUND_EXTERN,
+                missing.join(", ")
+            )));
+        }
+
+        // The script occupies slot 0 like any callee, so its block locals start at 1
+        self.stack
+            .borrow_mut()
+            .push(Value::Closure(closure.clone()));
+        if let Some(profile) = self.profile.as_mut() {
+            profile.enter(&closure.function);
+        }
         let global_frame = CallFrame::new(closure, self.stack.clone(), 0);
         self.frames.push(global_frame);
-        let mut frame = &mut self.frames[0];
-        while frame.ip < frame.closure.function.chunk.codes.len() {
-            let code = frame.closure.function.chunk.codes[frame.ip];
-            frame.show_stack();
-            frame
-                .closure
-                .function
-                .chunk
-                .disassemble_op_code(&code, frame.ip);
+
+        self.running = true;
+        let result = self.run(0);
+        self.running = false;
+        #[cfg(feature = "tracing")]
+        if let Err(VmError::RuntimeError(message)) | Err(VmError::CompileError(message)) = &result {
+            tracing::error!(message = message.as_str(), "runtime error");
+        }
+        result
+    }
+
+    // Enter closure, whose callee slot and arguments are the top of the stack
+    fn push_frame(&mut self, closure: Rc<Closure>, arg_count: usize) -> Result<()> {
+        if self.frames.len() >= self.max_frames {
+            return Err(VmError::RuntimeError(error::STACK_OVERFLOW.to_owned()));
+        }
+        verify(&closure.function)?;
+        if let Some(profile) = self.profile.as_mut() {
+            profile.enter(&closure.function);
+        }
+        let base = self.stack.borrow().len() - arg_count - 1;
+        self.frames.push(CallFrame::new(closure, self.stack.clone(), base));
+        Ok(())
+    }
+
+    // Run a method to completion from inside the VM, with receiver as `this`
+    pub fn call_method(&mut self, receiver: Value, method: Rc<Closure>, args: &[Value]) -> Result<Value> {
+        let method = compiled(method)?;
+        if method.function.arity != args.len() {
+            return Err(VmError::RuntimeError(format!(
+                "Expected {} arguments but got {}",
+                method.function.arity,
+                args.len()
+            )));
+        }
+        {
+            let mut stack = self.stack.borrow_mut();
+            stack.push(receiver);
+            stack.extend_from_slice(args);
+        }
+        let depth = self.frames.len();
+        self.push_frame(method, args.len())?;
+        self.run(depth)?;
+        Ok(self.stack.borrow_mut().pop().unwrap_or(Value::Nil))
+    }
+
+    // Call a function, bound method or native the script handed over, e.g. from a native
+    // taking a callback
+    pub fn call(&mut self, callee: &Value, args: &[Value]) -> Result<Value> {
+        match callee {
+            Value::Closure(closure) => self.call_method(callee.clone(), closure.clone(), args),
+            Value::BoundMethod(bound) => {
+                self.call_method(bound.receiver.clone(), bound.method.clone(), args)
+            }
+            Value::NativeFunction(native) => {
+                if native.arity != args.len() {
+                    return Err(VmError::RuntimeError(format!(
+                        "Expected {} arguments but got {}",
+                        native.arity,
+                        args.len()
+                    )));
+                }
+                (native.function)(self, args).map_err(|NativeError(message)| {
+                    VmError::RuntimeError(format!("{}: {}", native.name, message))
+                })
+            }
+            _ => Err(VmError::RuntimeError("Not a callable".to_owned())),
+        }
+    }
+
+    // Text print shows for a value, from toString() for instances of classes that have one
+    pub fn stringify(&mut self, value: Value) -> Result<String> {
+        if let Value::Instance(instance) = &value {
+            let method = instance
+                .class
+                .methods
+                .borrow()
+                .get(&LoxString::from("toString"))
+                .cloned();
+            if let Some(method) = method {
+                return Ok(self.call_method(value, method, &[])?.to_string());
+            }
+        }
+        Ok(value.to_string())
+    }
+
+    // Where left sorts against right by left's cmp(other): below 0 when it comes first,
+    // 0 when they tie and above 0 when it comes after
+    pub fn compare(&mut self, left: Value, right: Value) -> Result<f64> {
+        let method = match &left {
+            Value::Instance(instance) => {
+                instance.class.methods.borrow().get(&LoxString::from("cmp")).cloned()
+            }
+            _ => None,
+        };
+        let method = method
+            .ok_or_else(|| VmError::RuntimeError(format!("{} {}", error::MISSING_CMP, left)))?;
+        let order = self.call_method(left, method, &[right])?;
+        order
+            .as_number()
+            .ok_or_else(|| VmError::RuntimeError(format!("{} {}", error::CMP_NOT_NUMBER, order)))
+    }
+
+    // Active calls, innermost first, as left behind by the last runtime error
+    pub fn backtrace(&self) -> Vec<String> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = &frame.closure.function;
+                // The line table counts from 0, editors count from 1
+                let line = function.chunk.line_at_ip(frame.ip).map_or(0, |line| line + 1);
+                if function.name.is_empty() {
+                    format!("[line {}] in script", line)
+                } else {
+                    format!("[line {}] in {}()", line, function.name)
+                }
+            })
+            .collect()
+    }
+
+    // Run until the frame count drops back to depth, which is above 0 when an
+    // instruction calls back into Lox code. Runtime errors unwind to the innermost try
+    // block this run entered, older ones are left to the run that entered them.
+    fn run(&mut self, depth: usize) -> Result<()> {
+        loop {
+            let message = match self.execute(depth) {
+                Err(VmError::RuntimeError(message)) => message,
+                result => return result,
+            };
+            let handler = match self.handlers.last() {
+                Some(handler) if handler.frames > depth => self.handlers.pop().unwrap(),
+                _ => return Err(VmError::RuntimeError(message)),
+            };
+            let exception = self.thrown.take().unwrap_or_else(|| Value::from(message.as_str()));
+            if let Some(profile) = self.profile.as_mut() {
+                for _ in handler.frames..self.frames.len() {
+                    profile.exit();
+                }
+            }
+            close_upvalues(&self.upvalues, &mut self.heap, &self.stack, handler.stack_len);
+            self.frames.truncate(handler.frames);
+            let mut stack = self.stack.borrow_mut();
+            stack.truncate(handler.stack_len);
+            stack.push(exception);
+            drop(stack);
+            if let Some(frame) = self.frames.last_mut() {
+                frame.ip = handler.ip;
+            }
+        }
+    }
+
+    fn execute(&mut self, depth: usize) -> Result<()> {
+        let frame_len = self.frames.len();
+        let mut frame = &mut self.frames[frame_len - 1];
+        while frame.ip < frame.closure.function.chunk.code_end() {
+            if let Some(mut debugger) = self.debugger.take() {
+                let proceed = debugger.before_instruction(self);
+                self.debugger = Some(debugger);
+                if !proceed {
+                    return Err(VmError::Stopped);
+                }
+                let frame_len = self.frames.len();
+                frame = &mut self.frames[frame_len - 1];
+            }
+            let (code, mut length) = frame.closure.function.chunk.fetch(frame.ip);
+            if let Some(profile) = self.profile.as_mut() {
+                profile.instruction(code);
+            }
+            if let Some(count) = self.instruction_count.as_mut() {
+                *count += 1;
+            }
+            if let Some(budget) = self.budget.as_mut() {
+                if *budget == 0 {
+                    return Err(VmError::BudgetExceeded);
+                }
+                *budget -= 1;
+            }
+            if self.trace_execution {
+                frame.show_stack(&mut self.output).map_err(output_error)?;
+                let chunk = &frame.closure.function.chunk;
+                let index = chunk.index_at(frame.ip);
+                chunk
+                    .disassemble_op_code(&mut self.output, &chunk.code_at(index), index)
+                    .map_err(output_error)?;
+            }
+            // An instance on the left of an operator may define what it does, the operands
+            // are then already laid out as receiver and argument for the method
+            if let Some((method, arg_count)) = operator_method(frame, code)? {
+                self.push_frame(method, arg_count)?;
+                let frame_len = self.frames.len();
+                frame = &mut self.frames[frame_len - 1];
+                continue;
+            }
             match code {
-                OpCode::OpConstant(index) => {
-                    let value = frame.closure.function.chunk.values[index].clone();
+                OpCode::OpConstant(index) | OpCode::OpConstantLong(index) => {
+                    let value = frame.closure.function.chunk.constant(index).clone();
                     frame.slots.borrow_mut().push(value);
                 }
                 OpCode::OpNegate => {
                     let value = frame.get_stack_value()?;
-                    if let Value::Double(v) = value {
-                        frame.slots.borrow_mut().push(Value::Double(-v))
+                    if let Some(value) = value.negate() {
+                        frame.slots.borrow_mut().push(value)
                     } else {
                         return Err(VmError::RuntimeError(
                             error::OPERAND_MUST_BE_NUMBER.to_owned(),
@@ -106,28 +574,76 @@ impl VM {
                     }
                 }
                 OpCode::OpAdd => {
-                    if let Value::String(left_v) = frame.peek(0) {
-                        if let Value::String(right_v) = frame.peek(1) {
-                            frame.get_stack_value()?;
-                            frame.get_stack_value()?;
+                    if let (Value::String(left_v), Value::String(right_v)) =
+                        (frame.peek(1)?, frame.peek(0)?)
+                    {
+                        frame.closure.function.chunk.record_miss(frame.ip);
+                        allocate(
+                            &mut self.bytes_allocated,
+                            self.max_heap_bytes,
+                            mem::size_of::<LoxString>() + left_v.chars.len() + right_v.chars.len(),
+                        )?;
+                        frame.get_stack_value()?;
+                        frame.get_stack_value()?;
 
-                            frame
-                                .slots
-                                .borrow_mut()
-                                .push(Value::String(Rc::new((*left_v).clone() + &right_v)));
+                        frame
+                            .slots
+                            .borrow_mut()
+                            .push(Value::String(Rc::new(left_v.concat(&right_v))));
+                    } else if let (Value::String(_), Value::Instance(_))
+                    | (Value::Instance(_), Value::String(_)) = (frame.peek(1)?, frame.peek(0)?)
+                    {
+                        frame.closure.function.chunk.record_miss(frame.ip);
+                        let right = frame.get_stack_value()?;
+                        let left = frame.get_stack_value()?;
+                        let text = self.stringify(left)? + &self.stringify(right)?;
+                        allocate(
+                            &mut self.bytes_allocated,
+                            self.max_heap_bytes,
+                            mem::size_of::<LoxString>() + text.len(),
+                        )?;
+                        let frame_len = self.frames.len();
+                        frame = &mut self.frames[frame_len - 1];
+                        frame
+                            .slots
+                            .borrow_mut()
+                            .push(Value::String(Rc::new(LoxString::new(text))));
+                    } else {
+                        let chunk = &frame.closure.function.chunk;
+                        if frame.peek(1)?.as_number().is_some() && frame.peek(0)?.as_number().is_some() {
+                            if chunk.record_hit(frame.ip) {
+                                chunk.quicken(frame.ip, OpCode::OpAddNumber);
+                            }
+                        } else {
+                            chunk.record_miss(frame.ip);
                         }
+                        binary_op!(frame, code);
+                    }
+                }
+                OpCode::OpAddNumber => {
+                    let sum = match (frame.peek(1)?, frame.peek(0)?) {
+                        (Value::Double(left_v), Value::Double(right_v)) => Some(Value::Double(left_v + right_v)),
+                        (Value::Int(left_v), Value::Int(right_v)) => left_v.checked_add(right_v).map(Value::Int),
+                        _ => None,
+                    };
+                    if let Some(sum) = sum {
+                        frame.get_stack_value()?;
+                        frame.get_stack_value()?;
+                        frame.slots.borrow_mut().push(sum);
                     } else {
-                        binary_op!(frame,Double,+);
+                        // Run the generic form again on the same operands
+                        frame.closure.function.chunk.deoptimize(frame.ip);
+                        continue;
                     }
                 }
                 OpCode::OpSubtract => {
-                    binary_op!(frame,Double,-);
+                    binary_op!(frame, code);
                 }
                 OpCode::OpMultiply => {
-                    binary_op!(frame,Double,*);
+                    binary_op!(frame, code);
                 }
                 OpCode::OpDivide => {
-                    binary_op!(frame,Double,/);
+                    binary_op!(frame, code);
                 }
                 OpCode::OpNil => {
                     frame.slots.borrow_mut().push(Value::Nil);
@@ -139,8 +655,8 @@ impl VM {
                     frame.slots.borrow_mut().push(Value::Bool(false));
                 }
                 OpCode::OpNot => {
-                    let boolean: bool = frame.get_stack_value()?.into();
-                    frame.slots.borrow_mut().push(Value::Bool(boolean));
+                    let boolean = frame.get_stack_value()?.is_truthy();
+                    frame.slots.borrow_mut().push(Value::Bool(!boolean));
                 }
                 OpCode::OpEqual => {
                     let left_value = frame.get_stack_value()?;
@@ -150,71 +666,90 @@ impl VM {
                         .borrow_mut()
                         .push(Value::Bool(left_value == right_value));
                 }
+                // An instance without lt or gt is ordered by its cmp method
+                OpCode::OpGreater | OpCode::OpLess if matches!(frame.peek(1)?, Value::Instance(_)) => {
+                    let right = frame.get_stack_value()?;
+                    let left = frame.get_stack_value()?;
+                    let order = self.compare(left, right)?;
+                    let frame_len = self.frames.len();
+                    frame = &mut self.frames[frame_len - 1];
+                    let result = if code == OpCode::OpLess { order < 0.0 } else { order > 0.0 };
+                    frame.slots.borrow_mut().push(Value::Bool(result));
+                }
                 OpCode::OpGreater => {
-                    binary_op!(frame,Bool,>);
+                    binary_op!(frame, code);
                 }
                 OpCode::OpLess => {
-                    binary_op!(frame,Bool,<);
+                    binary_op!(frame, code);
                 } // _ => println!("Executing {}", code),
                 OpCode::OpPrint => {
-                    println!("{}", frame.get_stack_value()?);
+                    let value = frame.get_stack_value()?;
+                    let text = self.stringify(value)?;
+                    writeln!(self.output, "{}", text).map_err(output_error)?;
+                    let frame_len = self.frames.len();
+                    frame = &mut self.frames[frame_len - 1];
+                }
+                OpCode::OpEcho => {
+                    let value = frame.get_stack_value()?;
+                    writeln!(self.output, "{}", value.pretty(0)).map_err(output_error)?;
                 }
                 OpCode::OpPop => {
                     frame.get_stack_value()?;
                 }
+                OpCode::OpPopN(count) => frame.pop_values(count)?,
                 OpCode::OpDefineGlobal(index) => {
-                    let name_value = frame.closure.function.chunk.values[index].clone();
+                    let name_value = frame.closure.function.chunk.constant(index).clone();
                     if let Value::String(name) = name_value {
                         let value = frame.get_stack_value()?;
-                        self.globals.insert((*name).clone(), value);
+                        self.globals.insert(name, value);
                     } else {
-                        panic!(error::WARN_GLOBAL_BE_STRING);
+                        return Err(VmError::RuntimeError(error::WARN_GLOBAL_BE_STRING.to_owned()));
                     }
                 }
                 OpCode::OpGetGlobal(index) => {
-                    let name_value = frame.closure.function.chunk.values[index].clone();
+                    let name_value = frame.closure.function.chunk.constant(index).clone();
                     if let Value::String(name) = name_value {
-                        let message = format!("{} {}", error::UNDEFINED_VARIABLE, name);
-                        let value = self
-                            .globals
-                            .get(&(*name))
-                            .ok_or(VmError::RuntimeError(message))?;
+                        let value = self.globals.get(&name).ok_or_else(|| {
+                            VmError::RuntimeError(format!("{} {}", error::UNDEFINED_VARIABLE, name))
+                        })?;
                         frame.slots.borrow_mut().push(value.clone());
                     } else {
-                        panic!(error::WARN_GLOBAL_BE_STRING);
+                        return Err(VmError::RuntimeError(error::WARN_GLOBAL_BE_STRING.to_owned()));
                     }
                 }
                 OpCode::OpSetGlobal(index) => {
-                    let name_value = frame.closure.function.chunk.values[index].clone();
+                    let name_value = frame.closure.function.chunk.constant(index).clone();
                     if let Value::String(name) = name_value {
-                        let message = format!("{} {}", error::UNDEFINED_VARIABLE, name);
                         let assign_value = frame.get_stack_value()?;
-                        let value = self
-                            .globals
-                            .get_mut(&(*name))
-                            .ok_or(VmError::RuntimeError(message))?;
+                        let value = self.globals.get_mut(&name).ok_or_else(|| {
+                            VmError::RuntimeError(format!("{} {}", error::UNDEFINED_VARIABLE, name))
+                        })?;
                         *value = assign_value;
                         frame.slots.borrow_mut().push(value.clone());
                     } else {
-                        panic!(error::WARN_GLOBAL_BE_STRING);
+                        return Err(VmError::RuntimeError(error::WARN_GLOBAL_BE_STRING.to_owned()));
                     }
                 }
                 OpCode::OpGetLocal(index) => {
-                    frame
-                        .slots
-                        .borrow_mut()
-                        .push(frame.slots.borrow()[frame.base + index].clone());
+                    let value = frame.local(index)?;
+                    frame.slots.borrow_mut().push(value);
                 }
                 OpCode::OpSetLocal(index) => {
-                    frame.slots.borrow_mut()[frame.base + index] = frame.peek(0);
+                    frame.set_local(index, frame.peek(0)?)?;
                 }
                 OpCode::OpJumpIfFalse(index) => {
-                    let boolean: bool = frame.peek(0).into();
+                    let boolean = frame.peek(0)?.is_truthy();
                     if !boolean {
                         frame.ip += index;
                         continue;
                     }
                 }
+                OpCode::OpJumpIfTrue(index) => {
+                    if frame.peek(0)?.is_truthy() {
+                        frame.ip += index;
+                        continue;
+                    }
+                }
                 OpCode::OpJump(index) => {
                     frame.ip += index;
                     continue;
@@ -223,31 +758,128 @@ impl VM {
                     frame.ip -= index;
                     continue;
                 }
-                OpCode::OpCall(arg_count) => {
-                    let value = frame.peek(arg_count);
+                OpCode::OpCall(arg_count) | OpCode::OpInvoke(_, arg_count) => {
+                    let callee = match code {
+                        OpCode::OpInvoke(index, _) => {
+                            let name = property_name(&frame.closure.function.chunk, index)?;
+                            let instance = match frame.peek(arg_count)? {
+                                Value::Instance(instance) => instance,
+                                _ => {
+                                    return Err(VmError::RuntimeError(
+                                        error::ONLY_INSTANCES_HAVE_PROPERTIES.to_owned(),
+                                    ))
+                                }
+                            };
+                            check_private(frame, &instance, &name)?;
+                            // A field shadows a method, otherwise the instance stays in the
+                            // callee slot as `this` and no bound method is needed. Getters
+                            // are left out, calling what one returns takes `(obj.name)()`
+                            let chunk = &frame.closure.function.chunk;
+                            match find_property(chunk, frame.ip, &instance, &name, false) {
+                                Some(CachedProperty::Field(slot)) => {
+                                    let field = instance.fields.borrow()[slot].clone();
+                                    let mut slots = frame.slots.borrow_mut();
+                                    let callee = slots.len() - arg_count - 1;
+                                    slots[callee] = field.clone();
+                                    field
+                                }
+                                Some(CachedProperty::Method(method)) => Value::Closure(method),
+                                _ => {
+                                    return Err(VmError::RuntimeError(format!(
+                                        "{} '{}'",
+                                        error::UNDEFINED_PROPERTY,
+                                        name
+                                    )))
+                                }
+                            }
+                        }
+                        _ => frame.peek(arg_count)?,
+                    };
+                    let value = match callee {
+                        // The receiver takes the callee slot, where the method finds `this`
+                        Value::BoundMethod(bound) => {
+                            let mut slots = frame.slots.borrow_mut();
+                            let callee = slots.len() - arg_count - 1;
+                            slots[callee] = bound.receiver.clone();
+                            Value::Closure(bound.method.clone())
+                        }
+                        value => value,
+                    };
                     match value {
                         Value::Closure(closure) => {
+                            let closure = compiled(closure)?;
                             let function = &closure.function;
+                            trace_event!(debug, function = %function, args = arg_count, "call");
                             if function.arity != arg_count {
                                 return Err(VmError::RuntimeError(format!(
                                     "Expected {} arguments but got {}",
                                     function.arity, arg_count
                                 )));
                             }
-                            let new_frame = CallFrame::new(
-                                closure.clone(),
-                                self.stack.clone(),
-                                self.stack.borrow().len() - arg_count - 1,
-                            );
-                            self.frames.push(new_frame);
+                            // A call whose result is returned straight away takes over the
+                            // caller's frame, so tail recursion runs in constant frames
+                            // A try block in the caller must keep its frame to resume in, and
+                            // only its handlers were entered above the frame's base
+                            let next = frame.ip + length;
+                            let chunk = &frame.closure.function.chunk;
+                            let in_try = self.handlers.last().is_some_and(|handler| handler.stack_len > frame.base);
+                            if next < chunk.code_end() && chunk.fetch(next).0 == OpCode::OpReturn && !in_try {
+                                let base = frame.base;
+                                close_upvalues(&self.upvalues, &mut self.heap, &frame.slots, base);
+                                let mut slots = frame.slots.borrow_mut();
+                                let callee = slots.len() - arg_count - 1;
+                                slots.drain(base..callee);
+                                drop(slots);
+                                verify(&closure.function)?;
+                                if let Some(profile) = self.profile.as_mut() {
+                                    profile.exit();
+                                    profile.enter(&closure.function);
+                                }
+                                frame.closure = closure.clone();
+                                frame.ip = 0;
+                                continue;
+                            }
+                            self.push_frame(closure, arg_count)?;
                             let frame_len = self.frames.len();
                             frame = &mut self.frames[frame_len - 1];
                             continue;
                         }
-                        Value::NativeFunction(function) => {
-                            let value = function();
-                            frame.get_stack_value()?;
-                            frame.slots.borrow_mut().push(value);
+                        Value::NativeFunction(native) => {
+                            trace_event!(debug, native = native.name.as_str(), args = arg_count, "call");
+                            if native.arity != arg_count {
+                                return Err(VmError::RuntimeError(format!(
+                                    "Expected {} arguments but got {}",
+                                    native.arity, arg_count
+                                )));
+                            }
+                            // Copied out so the native is free to use the VM and its stack
+                            let args = {
+                                let slots = frame.slots.borrow();
+                                slots[slots.len() - arg_count..].to_vec()
+                            };
+                            let result = (native.function)(self, &args);
+                            let frame_len = self.frames.len();
+                            frame = &mut self.frames[frame_len - 1];
+                            let value = result.map_err(|NativeError(message)| {
+                                VmError::RuntimeError(format!("{}: {}", native.name, message))
+                            })?;
+                            let mut slots = frame.slots.borrow_mut();
+                            let callee = slots.len() - arg_count - 1;
+                            slots.truncate(callee);
+                            slots.push(value);
+                        }
+                        Value::Class(class) => {
+                            if arg_count != 0 {
+                                return Err(VmError::RuntimeError(format!(
+                                    "Expected 0 arguments but got {}",
+                                    arg_count
+                                )));
+                            }
+                            allocate(&mut self.bytes_allocated, self.max_heap_bytes, mem::size_of::<Instance>())?;
+                            let instance = Value::Instance(Rc::new(Instance::new(class)));
+                            let mut slots = frame.slots.borrow_mut();
+                            let callee = slots.len() - 1;
+                            slots[callee] = instance;
                         }
                         _ => {
                             return Err(VmError::RuntimeError("Not a callable".to_owned()));
@@ -255,54 +887,57 @@ impl VM {
                     }
                 }
                 OpCode::OpReturn => {
+                    if let Some(profile) = self.profile.as_mut() {
+                        profile.exit();
+                    }
                     let value = frame.get_stack_value()?;
                     let base = frame.base;
 
-                    while base <= frame.slots.borrow().len() {
-                        let raw_index = frame.slots.borrow().len() - 1;
-                        let value = frame.get_stack_value()?;
-                        self.heap.push(value);
-                        let index = self.heap.len() - 1;
-                        let upvalue = self
-                            .upvalues
-                            .iter()
-                            .find(|&e| raw_index == e.borrow().location)
-                            .unwrap();
-                        upvalue.borrow_mut().is_hoist = true;
-                        upvalue.borrow_mut().location = index;
-                    }
+                    close_upvalues(&self.upvalues, &mut self.heap, &frame.slots, base);
 
-                    self.stack.borrow_mut().drain(base..);
+                    self.stack.borrow_mut().truncate(base);
 
                     self.stack.borrow_mut().push(value);
 
                     self.frames.pop();
                     let frame_len = self.frames.len();
-                    if frame_len == 0 {
+                    // Try blocks the function returned from inside of are over
+                    while matches!(self.handlers.last(), Some(handler) if handler.frames > frame_len) {
+                        self.handlers.pop();
+                    }
+                    if frame_len == depth {
                         return Ok(());
                     } else {
                         frame = &mut self.frames[frame_len - 1];
+                        // Step over the caller's call instead
+                        length = frame.closure.function.chunk.fetch(frame.ip).1;
                     }
                 }
                 OpCode::OpClosure => {
                     let value = frame.get_stack_value()?;
                     if let Value::Function(function) = value {
+                        allocate(
+                            &mut self.bytes_allocated,
+                            self.max_heap_bytes,
+                            mem::size_of::<Closure>()
+                                + function.upvalues.len() * mem::size_of::<Rc<RefCell<UpValue>>>(),
+                        )?;
                         let mut closure = Closure::new(function.clone());
                         for upvalue_meta in function.upvalues.iter() {
                             let is_local = upvalue_meta.is_local;
                             let index = upvalue_meta.index;
                             if is_local {
-                                let res = match self
-                                    .upvalues
-                                    .iter()
-                                    .find(|&v| v.borrow().location == index as usize)
-                                {
+                                // Slots count from the frame, upvalues point into the whole stack
+                                let location = frame.base + index as usize;
+                                let res = match self.upvalues.iter().find(|&v| {
+                                    let v = v.borrow();
+                                    !v.is_hoist && v.location == location
+                                }) {
                                     Some(v) => v.clone(),
                                     None => {
-                                        self.upvalues.push(Rc::new(RefCell::new(UpValue::new(
-                                            index as usize,
-                                        ))));
-                                        self.upvalues.last().unwrap().clone()
+                                        let upvalue = Rc::new(RefCell::new(UpValue::new(location)));
+                                        self.upvalues.push(upvalue.clone());
+                                        upvalue
                                     }
                                 };
                                 closure.upvalues.push(res);
@@ -333,30 +968,374 @@ impl VM {
                 }
                 OpCode::OpSetUpValue(index) => {
                     let upvalue = frame.closure.upvalues[index].clone();
-                    let value = frame.peek(0);
+                    let value = frame.peek(0)?;
                     if !upvalue.borrow().is_hoist {
                         frame.slots.borrow_mut()[upvalue.borrow().location] = value;
                     } else {
                         self.heap[upvalue.borrow().location] = value;
                     }
                 }
+                OpCode::OpClass(index) => {
+                    let name_value = frame.closure.function.chunk.constant(index).clone();
+                    if let Value::String(name) = name_value {
+                        allocate(&mut self.bytes_allocated, self.max_heap_bytes, mem::size_of::<Class>())?;
+                        frame
+                            .slots
+                            .borrow_mut()
+                            .push(Value::Class(Rc::new(Class::new(name))));
+                    } else {
+                        return Err(VmError::RuntimeError(
+                            error::WARN_CLASS_NAME_BE_STRING.to_owned(),
+                        ));
+                    }
+                }
+                OpCode::OpMethod(index) => {
+                    let name_value = frame.closure.function.chunk.constant(index).clone();
+                    if let (Value::String(name), Value::Closure(method), Value::Class(class)) =
+                        (name_value, frame.get_stack_value()?, frame.peek(0)?)
+                    {
+                        let methods = match method.function.accessor {
+                            Some(Accessor::Getter) => &class.getters,
+                            Some(Accessor::Setter) => &class.setters,
+                            None => &class.methods,
+                        };
+                        methods.borrow_mut().insert(name, method);
+                    } else {
+                        return Err(VmError::RuntimeError("Error not a method".to_owned()));
+                    }
+                }
+                OpCode::OpGetProperty(index) => {
+                    let name = property_name(&frame.closure.function.chunk, index)?;
+                    let instance = match frame.get_stack_value()? {
+                        Value::Instance(instance) => instance,
+                        _ => {
+                            return Err(VmError::RuntimeError(
+                                error::ONLY_INSTANCES_HAVE_PROPERTIES.to_owned(),
+                            ))
+                        }
+                    };
+                    check_private(frame, &instance, &name)?;
+                    let chunk = &frame.closure.function.chunk;
+                    match find_property(chunk, frame.ip, &instance, &name, true) {
+                        Some(CachedProperty::Field(slot)) => {
+                            let field = instance.fields.borrow()[slot].clone();
+                            frame.slots.borrow_mut().push(field);
+                        }
+                        // Runs with the instance as `this`, its result replaces it on the stack
+                        Some(CachedProperty::Getter(getter)) => {
+                            frame.slots.borrow_mut().push(Value::Instance(instance));
+                            self.push_frame(getter, 0)?;
+                            let frame_len = self.frames.len();
+                            frame = &mut self.frames[frame_len - 1];
+                            continue;
+                        }
+                        Some(CachedProperty::Method(method)) => {
+                            allocate(
+                                &mut self.bytes_allocated,
+                                self.max_heap_bytes,
+                                mem::size_of::<BoundMethod>(),
+                            )?;
+                            let bound = BoundMethod {
+                                receiver: Value::Instance(instance),
+                                method,
+                            };
+                            frame
+                                .slots
+                                .borrow_mut()
+                                .push(Value::BoundMethod(Rc::new(bound)));
+                        }
+                        None => {
+                            return Err(VmError::RuntimeError(format!(
+                                "{} '{}'",
+                                error::UNDEFINED_PROPERTY,
+                                name
+                            )))
+                        }
+                    }
+                }
+                OpCode::OpSetProperty(index) => {
+                    let name = property_name(&frame.closure.function.chunk, index)?;
+                    let instance = match frame.peek(1)? {
+                        Value::Instance(instance) => instance,
+                        _ => {
+                            return Err(VmError::RuntimeError(
+                                error::ONLY_INSTANCES_HAVE_FIELDS.to_owned(),
+                            ))
+                        }
+                    };
+                    check_private(frame, &instance, &name)?;
+                    // The instance and value already sit where the setter wants `this` and
+                    // its parameter
+                    let setter = instance.class.setters.borrow().get(&name).cloned();
+                    if let Some(setter) = setter {
+                        self.push_frame(setter, 1)?;
+                        let frame_len = self.frames.len();
+                        frame = &mut self.frames[frame_len - 1];
+                        continue;
+                    }
+                    let value = frame.get_stack_value()?;
+                    frame.get_stack_value()?;
+                    if !instance.has_field(&name) {
+                        allocate(&mut self.bytes_allocated, self.max_heap_bytes, mem::size_of::<Value>())?;
+                    }
+                    instance.set_field(&name, value.clone());
+                    frame.slots.borrow_mut().push(value);
+                }
+                OpCode::OpBuildList(count) => {
+                    allocate(
+                        &mut self.bytes_allocated,
+                        self.max_heap_bytes,
+                        mem::size_of::<RefCell<Vec<Value>>>() + count * mem::size_of::<Value>(),
+                    )?;
+                    let mut slots = frame.slots.borrow_mut();
+                    let start = slots.len() - count;
+                    let items = slots.split_off(start);
+                    slots.push(Value::List(Rc::new(RefCell::new(items))));
+                }
+                OpCode::OpBuildMap(count) => {
+                    allocate(
+                        &mut self.bytes_allocated,
+                        self.max_heap_bytes,
+                        mem::size_of::<RefCell<LoxMap>>() + count * 2 * mem::size_of::<Value>(),
+                    )?;
+                    let entries = {
+                        let mut slots = frame.slots.borrow_mut();
+                        let start = slots.len() - count * 2;
+                        slots.split_off(start)
+                    };
+                    // map_key only lets through strings and numbers
+                    #[allow(clippy::mutable_key_type)]
+                    let mut map = LoxMap::default();
+                    for entry in entries.chunks(2) {
+                        map.insert(map_key(entry[0].clone())?, entry[1].clone());
+                    }
+                    frame
+                        .slots
+                        .borrow_mut()
+                        .push(Value::Map(Rc::new(RefCell::new(map))));
+                }
+                OpCode::OpIndexGet => {
+                    let index = frame.get_stack_value()?;
+                    let target = frame.get_stack_value()?;
+                    // A missing key reads as nil, like an unset variable in other languages
+                    let value = match &target {
+                        Value::Map(map) => map.borrow().get(&map_key(index)?).cloned(),
+                        _ => Some(list_slot(&target, &index, |items, i| items[i].clone())?),
+                    };
+                    frame.slots.borrow_mut().push(value.unwrap_or(Value::Nil));
+                }
+                OpCode::OpIndexSet => {
+                    let value = frame.get_stack_value()?;
+                    let index = frame.get_stack_value()?;
+                    let target = frame.get_stack_value()?;
+                    match &target {
+                        Value::Map(map) => {
+                            map.borrow_mut().insert(map_key(index)?, value.clone());
+                        }
+                        _ => list_slot(&target, &index, |items, i| items[i] = value.clone())?,
+                    }
+                    frame.slots.borrow_mut().push(value);
+                }
+                OpCode::OpThrow => {
+                    let value = frame.get_stack_value()?;
+                    let text = self.stringify(value.clone())?;
+                    self.thrown = Some(value);
+                    return Err(VmError::RuntimeError(format!("{} {}", error::UNCAUGHT_EXCEPTION, text)));
+                }
+                OpCode::OpTry(offset) => {
+                    let stack_len = frame.slots.borrow().len();
+                    let ip = frame.ip + offset;
+                    let frame_len = self.frames.len();
+                    self.handlers.push(Handler { frames: frame_len, stack_len, ip });
+                    frame = &mut self.frames[frame_len - 1];
+                }
+                OpCode::OpPopHandler => {
+                    self.handlers.pop();
+                }
                 OpCode::OpCloseUpvalue => {
-                    let raw_index = frame.slots.borrow().len() - 1;
                     let value = frame.get_stack_value()?;
-                    self.heap.push(value);
-                    let index = self.heap.len() - 1;
-                    let upvalue = self
-                        .upvalues
-                        .iter()
-                        .find(|&e| raw_index == e.borrow().location)
-                        .unwrap();
-                    upvalue.borrow_mut().is_hoist = true;
-                    upvalue.borrow_mut().location = index;
+                    let raw_index = frame.slots.borrow().len();
+                    // No upvalue exists when the closure capturing the slot never ran
+                    let upvalue = self.upvalues.iter().find(|&e| {
+                        let e = e.borrow();
+                        !e.is_hoist && e.location == raw_index
+                    });
+                    if let Some(upvalue) = upvalue {
+                        self.heap.push(value);
+                        let mut upvalue = upvalue.borrow_mut();
+                        upvalue.is_hoist = true;
+                        upvalue.location = self.heap.len() - 1;
+                    }
                 }
             }
-            frame.ip += 1;
+            frame.ip += length;
         }
 
         Ok(())
     }
 }
+
+// closure itself, or a closure over its compiled body if that was left for the first call
+fn compiled(closure: Rc<Closure>) -> Result<Rc<Closure>> {
+    let body = match &closure.function.lazy {
+        Some(body) => body,
+        None => return Ok(closure),
+    };
+    let function = body.function().map_err(|diagnostics| {
+        VmError::CompileError(format!(
+            "Could not compile function {}\n{}",
+            body.name,
+            body.render(&diagnostics)
+        ))
+    })?;
+    Ok(Rc::new(Closure::new(function)))
+}
+
+// Every frame's code is verified before it runs, wherever the function came from. Unchecked
+// accesses rely on it, so it stays on in release builds with unsafe-fast.
+fn verify(function: &Function) -> Result<()> {
+    #[cfg(any(debug_assertions, feature = "unsafe-fast"))]
+    function
+        .verify()
+        .map_err(|message| VmError::CompileError(format!("{} in {}", message, function)))?;
+    #[cfg(not(any(debug_assertions, feature = "unsafe-fast")))]
+    let _ = function;
+    Ok(())
+}
+
+// Run `access` on the element `index` points at, checking both operands first
+fn list_slot<T>(
+    list: &Value,
+    index: &Value,
+    access: impl FnOnce(&mut Vec<Value>, usize) -> T,
+) -> Result<T> {
+    let list = match list {
+        Value::List(list) => list,
+        _ => {
+            return Err(VmError::RuntimeError(error::NOT_INDEXABLE.to_owned()))
+        }
+    };
+    let index = match index {
+        Value::Int(index) if *index >= 0 => *index as usize,
+        Value::Double(index) if *index >= 0.0 && index.fract() == 0.0 => *index as usize,
+        _ => return Err(VmError::RuntimeError(error::INDEX_MUST_BE_INTEGER.to_owned())),
+    };
+    let mut items = list.borrow_mut();
+    if index >= items.len() {
+        return Err(VmError::RuntimeError(error::INDEX_OUT_OF_RANGE.to_owned()));
+    }
+    Ok(access(&mut items, index))
+}
+
+// Maps hash their keys, so only values with stable equality may be used
+fn map_key(key: Value) -> Result<Value> {
+    match key {
+        Value::String(_) => Ok(key),
+        Value::Double(v) if !v.is_nan() => Ok(key),
+        Value::Int(_) => Ok(key),
+        _ => Err(VmError::RuntimeError(error::INVALID_MAP_KEY.to_owned())),
+    }
+}
+
+// Hoist every upvalue still pointing at or above base before those slots go away
+fn close_upvalues(
+    upvalues: &[Rc<RefCell<UpValue>>],
+    heap: &mut Vec<Value>,
+    slots: &RefCell<Vec<Value>>,
+    base: usize,
+) {
+    for upvalue in upvalues.iter() {
+        let mut upvalue = upvalue.borrow_mut();
+        if !upvalue.is_hoist && upvalue.location >= base {
+            heap.push(slots.borrow()[upvalue.location].clone());
+            upvalue.is_hoist = true;
+            upvalue.location = heap.len() - 1;
+        }
+    }
+}
+
+// Count bytes about to be allocated, failing instead once they would go past limit
+fn allocate(allocated: &mut usize, limit: Option<usize>, bytes: usize) -> Result<()> {
+    *allocated += bytes;
+    match limit {
+        Some(limit) if *allocated > limit => Err(VmError::RuntimeError(error::HEAP_LIMIT.to_owned())),
+        _ => Ok(()),
+    }
+}
+
+fn operator_method(frame: &CallFrame, code: OpCode) -> Result<Option<(Rc<Closure>, usize)>> {
+    let (name, arg_count) = match code {
+        OpCode::OpAdd | OpCode::OpAddNumber => ("plus", 1),
+        OpCode::OpSubtract => ("minus", 1),
+        OpCode::OpMultiply => ("times", 1),
+        OpCode::OpDivide => ("divide", 1),
+        OpCode::OpEqual => ("eq", 1),
+        OpCode::OpLess => ("lt", 1),
+        OpCode::OpGreater => ("gt", 1),
+        OpCode::OpNegate => ("negate", 0),
+        _ => return Ok(None),
+    };
+    let instance = match frame.peek(arg_count)? {
+        Value::Instance(instance) => instance,
+        _ => return Ok(None),
+    };
+    let method = instance.class.methods.borrow().get(&LoxString::from(name)).cloned();
+    match method {
+        Some(method) if method.function.arity != arg_count => Err(VmError::RuntimeError(format!(
+            "Expected {} arguments but got {}",
+            method.function.arity, arg_count
+        ))),
+        method => Ok(method.map(|method| (method, arg_count))),
+    }
+}
+
+// Where name is on instance: a field, else a getter when getters is set, else a method.
+// The site at ip remembers the answer for the instance's shape, which also fixes the
+// class, so only the first instance of each shape it sees needs the hash lookups.
+fn find_property(
+    chunk: &Chunk,
+    ip: usize,
+    instance: &Instance,
+    name: &LoxString,
+    getters: bool,
+) -> Option<CachedProperty> {
+    let shape = instance.shape.borrow().clone();
+    if let Some(found) = chunk.cached_property(ip, &shape) {
+        return Some(found);
+    }
+    let getter = if getters {
+        instance.class.getters.borrow().get(name).cloned()
+    } else {
+        None
+    };
+    let found = match (shape.slot(name), getter) {
+        (Some(slot), _) => CachedProperty::Field(slot),
+        (None, Some(getter)) => CachedProperty::Getter(getter),
+        (None, None) => CachedProperty::Method(instance.class.methods.borrow().get(name)?.clone()),
+    };
+    chunk.cache_property(ip, shape, found.clone());
+    Some(found)
+}
+
+// Members named with a leading underscore are private to their class, only a method
+// whose `this` is an instance of that same class may use them
+fn check_private(frame: &CallFrame, instance: &Instance, name: &LoxString) -> Result<()> {
+    if !name.starts_with('_') {
+        return Ok(());
+    }
+    match frame.slots.borrow().get(frame.base) {
+        Some(Value::Instance(this)) if Rc::ptr_eq(&this.class, &instance.class) => Ok(()),
+        _ => Err(VmError::RuntimeError(format!("{} '{}'", error::PRIVATE_MEMBER, name))),
+    }
+}
+
+fn property_name(chunk: &Chunk, index: usize) -> Result<Rc<LoxString>> {
+    match chunk.constant(index) {
+        Value::String(name) => Ok(name.clone()),
+        _ => Err(VmError::RuntimeError(error::WARN_PROPERTY_NAME_BE_STRING.to_owned())),
+    }
+}
+
+fn output_error(error: io::Error) -> VmError {
+    VmError::RuntimeError(format!("Could not write output: {}", error))
+}
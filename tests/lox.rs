@@ -0,0 +1,97 @@
+// Runs every script under tests/lox and checks it against the comments it carries:
+//   // expect: <text>                     the next line the script prints
+//   // expect runtime error: <message>    the error that stops the script, on this line
+//   // expect error: <message>            a compile error reported on this line
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rlox::{error::Severity, util::SharedBuffer, vm::VM, InterpretError, Interpreter};
+
+#[derive(Debug, Default, PartialEq)]
+struct Outcome {
+    output: Vec<String>,
+    // Line and message
+    runtime_error: Option<(usize, String)>,
+    compile_errors: Vec<(usize, String)>,
+}
+
+fn expected(source: &str) -> Outcome {
+    let mut outcome = Outcome::default();
+    for (index, line) in source.lines().enumerate() {
+        let comment = match line.find("// expect") {
+            Some(start) => &line[start + "// expect".len()..],
+            None => continue,
+        };
+        if let Some(text) = comment.strip_prefix(": ") {
+            outcome.output.push(text.to_owned());
+        } else if let Some(message) = comment.strip_prefix(" runtime error: ") {
+            outcome.runtime_error = Some((index + 1, message.to_owned()));
+        } else if let Some(message) = comment.strip_prefix(" error: ") {
+            outcome.compile_errors.push((index + 1, message.to_owned()));
+        }
+    }
+    outcome
+}
+
+fn actual(source: &str) -> Outcome {
+    let output = SharedBuffer::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.vm = VM::with_output(output.clone());
+    let mut outcome = Outcome::default();
+    match interpreter.interpret(source) {
+        Ok(()) => {}
+        Err(InterpretError::Compile(diagnostics)) => {
+            outcome.compile_errors = diagnostics
+                .into_iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .map(|diagnostic| (diagnostic.line as usize, diagnostic.message))
+                .collect();
+        }
+        Err(InterpretError::Runtime(message)) => {
+            // The innermost call comes first, as "[line N] in ..."
+            let line = interpreter.vm.backtrace().first().and_then(|call| {
+                let number = call.strip_prefix("[line ")?.split(']').next()?;
+                number.parse().ok()
+            });
+            outcome.runtime_error = Some((line.unwrap_or(0), message));
+        }
+    }
+    outcome.output = output.contents().lines().map(str::to_owned).collect();
+    outcome
+}
+
+fn scripts(dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("tests/lox is readable") {
+        let path = entry.expect("tests/lox is readable").path();
+        if path.is_dir() {
+            scripts(&path, found);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            found.push(path);
+        }
+    }
+}
+
+#[test]
+fn lox_scripts() {
+    let mut paths = vec![];
+    scripts(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox"), &mut paths);
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    let mut failures = vec![];
+    for path in paths.iter() {
+        let source = fs::read_to_string(path).expect("script is readable");
+        let (expected, actual) = (expected(&source), actual(&source));
+        if expected != actual {
+            failures.push(format!(
+                "{}\nexpected {:#?}\nbut got {:#?}",
+                path.display(),
+                expected,
+                actual
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}